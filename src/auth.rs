@@ -0,0 +1,413 @@
+//! Challenge-response authentication of devices after the hello.
+//!
+//! The server sends `[AUTH_CHALLENGE, nonce(16)]`; the device answers with
+//! the 32-byte HMAC-SHA256 of `nonce || mac` under its pre-shared key.
+//!
+//! In learn mode a device without a key is enrolled in two steps. On its
+//! first connection a random key is generated, written to the `[pending]`
+//! table of the key store and the device is turned away; the key is then
+//! provisioned on the device. Once the device answers a challenge with
+//! that key it moves to `[keys]` and the connection proceeds.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::config::ConfigError;
+use crate::device::DeviceId;
+use crate::error::Error;
+use crate::handshake::Hello;
+
+/// First byte of the challenge sent to the device.
+pub const AUTH_CHALLENGE: u8 = 0xA1;
+
+const NONCE_LEN: usize = 16;
+const RESPONSE_LEN: usize = 32;
+
+/// Shortest accepted pre-shared key, in bytes.
+const MIN_KEY_LEN: usize = 16;
+
+/// Length of the keys generated in learn mode, in bytes.
+const ENROLL_KEY_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC state over `nonce || mac`, ready to finalize or verify.
+fn keyed_mac(key: &[u8], nonce: &[u8], id: DeviceId) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(nonce);
+    mac.update(&id.mac());
+    mac
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// No key is enrolled for the device.
+    UnknownDevice,
+    /// A key was generated for the device and waits to be provisioned on
+    /// it.
+    Enrolling,
+    /// The device's answer did not match.
+    BadResponse,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownDevice => f.write_str("device is not enrolled"),
+            AuthError::Enrolling => f.write_str(
+                "device is being enrolled; provision the key from the pending table of the key store on it",
+            ),
+            AuthError::BadResponse => f.write_str("wrong challenge response"),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    /// MAC address to hex-encoded key.
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    /// Keys generated in learn mode that no device has used yet.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pending: HashMap<String, String>,
+}
+
+/// Parses the `MAC = hex key` entries of one table.
+fn parse_keys(
+    path: &Path,
+    entries: HashMap<String, String>,
+) -> Result<HashMap<DeviceId, Vec<u8>>, ConfigError> {
+    let mut keys = HashMap::new();
+    for (id, key) in entries {
+        let invalid = |msg: String| ConfigError::Invalid(format!("{}: {}", path.display(), msg));
+
+        let id: DeviceId = id.parse().map_err(invalid)?;
+        let key =
+            hex::decode(&key).map_err(|e| invalid(format!("key of {} is not hex: {}", id, e)))?;
+        if key.len() < MIN_KEY_LEN {
+            return Err(invalid(format!(
+                "key of {} is shorter than {} bytes",
+                id, MIN_KEY_LEN
+            )));
+        }
+        keys.insert(id, key);
+    }
+    Ok(keys)
+}
+
+/// Pre-shared keys of enrolled devices, and of the devices being enrolled.
+#[derive(Default)]
+pub struct KeyStore {
+    /// File the keys are saved to after an enrollment step.
+    path: Option<PathBuf>,
+    keys: HashMap<DeviceId, Vec<u8>>,
+    pending: HashMap<DeviceId, Vec<u8>>,
+}
+
+impl KeyStore {
+    /// Loads a TOML file of the form
+    ///
+    /// ```toml
+    /// [keys]
+    /// "aa:bb:cc:dd:ee:ff" = "<hex key>"
+    ///
+    /// [pending]
+    /// "aa:bb:cc:dd:ee:01" = "<hex key>"
+    /// ```
+    pub fn load(path: &Path) -> Result<KeyStore, ConfigError> {
+        let text =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let file: KeyFile =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        Ok(KeyStore {
+            path: Some(path.to_path_buf()),
+            keys: parse_keys(path, file.keys)?,
+            pending: parse_keys(path, file.pending)?,
+        })
+    }
+
+    /// An empty store that is saved to `path`, which does not exist yet.
+    pub fn create(path: &Path) -> KeyStore {
+        KeyStore {
+            path: Some(path.to_path_buf()),
+            ..KeyStore::default()
+        }
+    }
+
+    pub fn get(&self, id: DeviceId) -> Option<&[u8]> {
+        self.keys.get(&id).map(|key| key.as_slice())
+    }
+
+    /// Key generated for `id` that the device has not used yet.
+    pub fn pending(&self, id: DeviceId) -> Option<&[u8]> {
+        self.pending.get(&id).map(|key| key.as_slice())
+    }
+
+    /// Generates a key for `id`, stores it as pending and saves the store.
+    fn enroll(&mut self, id: DeviceId) -> std::io::Result<()> {
+        let mut key = vec![0; ENROLL_KEY_LEN];
+        getrandom::getrandom(&mut key).map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.pending.insert(id, key);
+        self.save()
+    }
+
+    /// Makes the pending key of `id` its key and saves the store.
+    fn confirm(&mut self, id: DeviceId) -> std::io::Result<()> {
+        if let Some(key) = self.pending.remove(&id) {
+            self.keys.insert(id, key);
+        }
+        self.save()
+    }
+
+    /// Writes the store back to its file, through a temporary file so a
+    /// crash cannot leave it half written. Comments in the file are lost.
+    fn save(&self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let encode = |keys: &HashMap<DeviceId, Vec<u8>>| {
+            keys.iter()
+                .map(|(id, key)| (id.to_string(), hex::encode(key)))
+                .collect()
+        };
+        let file = KeyFile {
+            keys: encode(&self.keys),
+            pending: encode(&self.pending),
+        };
+        let text = toml::to_string(&file).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// Keeps the keys out of debug output.
+impl fmt::Debug for KeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+/// Decides whether a device that sent its hello may proceed.
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    keys: Mutex<KeyStore>,
+    /// Enroll devices that have no key yet.
+    learn_mode: bool,
+}
+
+impl Authenticator {
+    pub fn new(keys: KeyStore, learn_mode: bool) -> Self {
+        Authenticator {
+            keys: Mutex::new(keys),
+            learn_mode,
+        }
+    }
+
+    /// Expected answer of `id` to `nonce`, if the device is enrolled.
+    /// Meant for device simulators.
+    pub fn expected_response(&self, id: DeviceId, nonce: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.lock().unwrap();
+        let key = keys.get(id)?;
+        Some(keyed_mac(key, nonce, id).finalize().into_bytes().to_vec())
+    }
+
+    /// Runs the challenge-response exchange with the device that sent
+    /// `hello`. In learn mode an unknown device is enrolled and turned
+    /// away, and a device answering with its pending key is confirmed.
+    pub async fn authenticate(
+        &self,
+        socket: &mut TcpStream,
+        hello: &Hello,
+        deadline: Duration,
+    ) -> Result<(), Error> {
+        let id = hello.id;
+        let (key, pending) = {
+            let mut keys = self.keys.lock().unwrap();
+            match (keys.get(id), keys.pending(id)) {
+                (Some(key), _) => (key.to_vec(), false),
+                (None, Some(key)) if self.learn_mode => (key.to_vec(), true),
+                (None, None) if self.learn_mode => {
+                    keys.enroll(id)?;
+                    println!(
+                        "{} learn mode: generated a key for {} in the pending table of the key store; it is enrolled once it authenticates with that key",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        id
+                    );
+                    return Err(Error::Auth(id, AuthError::Enrolling));
+                }
+                _ => return Err(Error::Auth(id, AuthError::UnknownDevice)),
+            }
+        };
+
+        challenge(socket, id, &key, deadline).await?;
+
+        if pending {
+            self.keys.lock().unwrap().confirm(id)?;
+            println!(
+                "{} learn mode: enrolled device {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                id
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Challenges the device and checks its answer against `key`.
+async fn challenge(
+    socket: &mut TcpStream,
+    id: DeviceId,
+    key: &[u8],
+    deadline: Duration,
+) -> Result<(), Error> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let mut challenge = vec![AUTH_CHALLENGE];
+    challenge.extend_from_slice(&nonce);
+    socket.write_all(&challenge).await?;
+
+    let mut response = [0; RESPONSE_LEN];
+    timeout(deadline, socket.read_exact(&mut response))
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+    keyed_mac(key, &nonce, id)
+        .verify_slice(&response)
+        .map_err(|_| Error::Auth(id, AuthError::BadResponse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Key store path unique to `name`, not created yet.
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "powermax-keys-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Connects to the authenticator as the device `id` would, answering
+    /// the challenge with `key` if there is one.
+    async fn connect(
+        auth: &Authenticator,
+        id: DeviceId,
+        key: Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut device = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let answer = async move {
+            let mut challenge = [0; 1 + NONCE_LEN];
+            if device.read_exact(&mut challenge).await.is_err() {
+                return;
+            }
+            assert_eq!(challenge[0], AUTH_CHALLENGE);
+            let response = keyed_mac(&key.unwrap(), &challenge[1..], id)
+                .finalize()
+                .into_bytes();
+            device.write_all(&response).await.unwrap();
+        };
+        let hello = Hello { id, version: None };
+        // The server side owns its socket so the device sees EOF when no
+        // challenge is coming.
+        let check = async move {
+            auth.authenticate(&mut server, &hello, Duration::from_secs(5))
+                .await
+        };
+        let (result, ()) = tokio::join!(check, answer);
+        result
+    }
+
+    #[test]
+    fn saves_and_loads_keys() {
+        let path = path("roundtrip");
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let mut keys = KeyStore::create(&path);
+        keys.enroll(id).unwrap();
+
+        let loaded = KeyStore::load(&path).unwrap();
+        assert_eq!(loaded.pending(id), keys.pending(id));
+        assert_eq!(loaded.get(id), None);
+
+        keys.confirm(id).unwrap();
+        let loaded = KeyStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get(id).map(|key| key.len()), Some(ENROLL_KEY_LEN));
+        assert_eq!(loaded.pending(id), None);
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        let path = path("short");
+        fs::write(&path, "[keys]\n\"00:1b:2c:3d:4e:5f\" = \"00112233\"\n").unwrap();
+        let result = KeyStore::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn enrolls_after_confirmation() {
+        let path = path("enroll");
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let auth = Authenticator::new(KeyStore::create(&path), true);
+
+        let first = connect(&auth, id, None).await;
+        assert!(matches!(first, Err(Error::Auth(_, AuthError::Enrolling))));
+        let key = KeyStore::load(&path).unwrap().pending(id).unwrap().to_vec();
+        assert_eq!(auth.expected_response(id, &[0; NONCE_LEN]), None);
+
+        let wrong = connect(&auth, id, Some(vec![0; ENROLL_KEY_LEN])).await;
+        assert!(matches!(wrong, Err(Error::Auth(_, AuthError::BadResponse))));
+
+        connect(&auth, id, Some(key.clone())).await.unwrap();
+        let stored = KeyStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(stored.get(id), Some(key.as_slice()));
+        assert_eq!(stored.pending(id), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_devices_outside_learn_mode() {
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let auth = Authenticator::new(KeyStore::default(), false);
+        let result = connect(&auth, id, None).await;
+        assert!(matches!(
+            result,
+            Err(Error::Auth(_, AuthError::UnknownDevice))
+        ));
+    }
+}
@@ -0,0 +1,1041 @@
+//! Gateway configuration: a TOML file, overridden by environment variables.
+//!
+//! Secrets are best left out of the file and passed as
+//! `B5120_INFLUXDB_TOKEN` instead.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use reqwest::Url;
+use rumqttc::MqttOptions;
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::auth::{Authenticator, KeyStore};
+use crate::history::Retention;
+use crate::influxdb::BatchConfig;
+use crate::inverter::InverterConfig;
+use crate::mqtt;
+use crate::registry::Registry;
+use crate::schedule::{RegisterGroup, Schedule};
+use crate::session::SessionConfig;
+use crate::site::FlowConfig;
+use crate::spool::Spool;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A value is missing or out of range; the string names the setting.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the device listener binds to.
+    pub listen: String,
+    pub influxdb: InfluxDbConfig,
+    pub mqtt: MqttConfig,
+    pub metrics: MetricsConfig,
+    pub history: HistoryConfig,
+    pub control: ControlConfig,
+    pub auth: AuthConfig,
+    pub registry: RegistryConfig,
+    pub session: SessionSettings,
+    /// Polling interval in seconds per register group, e.g. `current = 2`.
+    /// Groups not listed keep the default interval of the driver.
+    pub schedule: BTreeMap<String, u64>,
+    /// Inverters the gateway connects out to, as `[[inverters]]` tables.
+    pub inverters: Vec<InverterSettings>,
+    pub sites: SitesConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxDbConfig {
+    pub enabled: bool,
+    /// Base URL of the InfluxDB v2 server.
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    /// Flush once this many lines are buffered.
+    pub batch_size: usize,
+    pub flush_interval_ms: u64,
+    pub gzip: bool,
+    /// Retries of a batch after connection errors, 5xx and 429.
+    pub max_retries: u32,
+    /// Directory of the on-disk buffer used while InfluxDB is unavailable.
+    pub buffer_dir: Option<PathBuf>,
+    pub buffer_max_mb: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Leave empty to connect anonymously.
+    pub username: String,
+    pub password: String,
+    /// Root of the state and availability topics.
+    pub topic_prefix: String,
+    /// Root of Home Assistant discovery topics.
+    pub discovery_prefix: String,
+    pub keep_alive_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Address the Prometheus `/metrics` endpoint binds to.
+    pub listen: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// SQLite database file, created if missing.
+    pub path: PathBuf,
+    /// Address the `/history` endpoint binds to.
+    pub listen: String,
+    /// Days raw samples are kept.
+    pub raw_days: u32,
+    /// Days 1-minute rollups are kept.
+    pub minute_days: u32,
+    /// Days 1-hour rollups are kept.
+    pub hour_days: u32,
+}
+
+/// Endpoint for changing inverter settings.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    /// Address the `/settings` endpoint binds to.
+    pub listen: String,
+    /// Bearer token every request must carry.
+    pub token: String,
+}
+
+/// Energy flow of the sites with an inverter.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SitesConfig {
+    pub enabled: bool,
+    /// Battery current difference between inverter and packs always
+    /// tolerated, in A.
+    pub current_tolerance_a: f32,
+    /// Difference tolerated relative to the larger current.
+    pub current_tolerance_percent: f32,
+    /// Pack currents older than this are left out of the flow.
+    pub pack_max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// TOML file with the pre-shared key of every enrolled device.
+    pub key_store: Option<PathBuf>,
+    /// Generate keys for devices that have none and enroll them once they
+    /// authenticate with it; the keys are written to `key_store`.
+    pub learn_mode: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// TOML file describing every known device.
+    pub path: Option<PathBuf>,
+    /// Let devices that are not in the registry connect.
+    pub allow_unknown: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InverterSettings {
+    pub name: String,
+    #[serde(default)]
+    pub site: Option<String>,
+    /// `host:port` of the inverter's serial-to-TCP bridge.
+    pub address: String,
+    #[serde(default = "InverterSettings::default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default = "InverterSettings::default_response_timeout_ms")]
+    pub response_timeout_ms: u64,
+    /// How often the ratings and settings (`QPIRI`) are read.
+    #[serde(default = "InverterSettings::default_rating_interval_ms")]
+    pub rating_interval_ms: u64,
+    /// How often the warning bits (`QPIWS`) are read.
+    #[serde(default = "InverterSettings::default_warnings_interval_ms")]
+    pub warnings_interval_ms: u64,
+    /// How often the operating mode (`QMOD`) is read.
+    #[serde(default = "InverterSettings::default_mode_interval_ms")]
+    pub mode_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    pub response_timeout_ms: u64,
+    pub retries: u32,
+    pub max_consecutive_timeouts: u32,
+    pub min_command_gap_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "0.0.0.0:30278".to_string(),
+            influxdb: InfluxDbConfig::default(),
+            mqtt: MqttConfig::default(),
+            metrics: MetricsConfig::default(),
+            history: HistoryConfig::default(),
+            control: ControlConfig::default(),
+            auth: AuthConfig::default(),
+            registry: RegistryConfig::default(),
+            session: SessionSettings::default(),
+            schedule: BTreeMap::new(),
+            inverters: Vec::new(),
+            sites: SitesConfig::default(),
+        }
+    }
+}
+
+// Keeps the token out of debug output.
+impl fmt::Debug for InfluxDbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InfluxDbConfig")
+            .field("enabled", &self.enabled)
+            .field("url", &self.url)
+            .field("org", &self.org)
+            .field("bucket", &self.bucket)
+            .field("token", &"<redacted>")
+            .field("batch_size", &self.batch_size)
+            .field("flush_interval_ms", &self.flush_interval_ms)
+            .field("gzip", &self.gzip)
+            .field("max_retries", &self.max_retries)
+            .field("buffer_dir", &self.buffer_dir)
+            .field("buffer_max_mb", &self.buffer_max_mb)
+            .finish()
+    }
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> Self {
+        InfluxDbConfig {
+            enabled: true,
+            url: "http://localhost:9999".to_string(),
+            org: String::new(),
+            bucket: String::new(),
+            token: String::new(),
+            batch_size: 5000,
+            flush_interval_ms: 1000,
+            gzip: true,
+            max_retries: 5,
+            buffer_dir: None,
+            buffer_max_mb: 512,
+        }
+    }
+}
+
+// Keeps the password out of debug output.
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("enabled", &self.enabled)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("topic_prefix", &self.topic_prefix)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .field("keep_alive_secs", &self.keep_alive_secs)
+            .finish()
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "powermax-b5120".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "powermax".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            keep_alive_secs: 30,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: "0.0.0.0:9120".to_string(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        let retention = Retention::default();
+        HistoryConfig {
+            enabled: false,
+            path: PathBuf::from("/var/lib/powermax-b5120/history.db"),
+            listen: "0.0.0.0:9121".to_string(),
+            raw_days: retention.raw_days,
+            minute_days: retention.minute_days,
+            hour_days: retention.hour_days,
+        }
+    }
+}
+
+// Keeps the token out of debug output.
+impl fmt::Debug for ControlConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlConfig")
+            .field("enabled", &self.enabled)
+            .field("listen", &self.listen)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            enabled: false,
+            listen: "127.0.0.1:9122".to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        let defaults = SessionConfig::default();
+        SessionSettings {
+            response_timeout_ms: defaults.response_timeout.as_millis() as u64,
+            retries: defaults.retries,
+            max_consecutive_timeouts: defaults.max_consecutive_timeouts,
+            min_command_gap_ms: defaults.min_command_gap.as_millis() as u64,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path` if given, applies environment overrides and validates
+    /// the result.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env(|name| env::var(name).ok());
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Environment variables, looked up with `var`, take precedence over
+    /// the file.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        let overrides: [(&str, &mut String); 8] = [
+            ("B5120_LISTEN", &mut self.listen),
+            ("B5120_INFLUXDB_URL", &mut self.influxdb.url),
+            ("B5120_INFLUXDB_ORG", &mut self.influxdb.org),
+            ("B5120_INFLUXDB_BUCKET", &mut self.influxdb.bucket),
+            ("B5120_INFLUXDB_TOKEN", &mut self.influxdb.token),
+            ("B5120_MQTT_HOST", &mut self.mqtt.host),
+            ("B5120_MQTT_PASSWORD", &mut self.mqtt.password),
+            ("B5120_CONTROL_TOKEN", &mut self.control.token),
+        ];
+
+        for (name, value) in overrides {
+            if let Some(v) = var(name) {
+                *value = v;
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        if self.listen.parse::<SocketAddr>().is_err() {
+            return invalid(format!("listen: `{}` is not a socket address", self.listen));
+        }
+
+        if !self.influxdb.enabled
+            && !self.mqtt.enabled
+            && !self.metrics.enabled
+            && !self.history.enabled
+        {
+            return invalid(
+                "no output is enabled; enable influxdb, mqtt, metrics or history".to_string(),
+            );
+        }
+        if self.influxdb.enabled {
+            self.influxdb.validate()?;
+        }
+        if self.mqtt.enabled {
+            self.mqtt.validate()?;
+        }
+        if self.metrics.enabled && self.metrics.listen.parse::<SocketAddr>().is_err() {
+            return invalid(format!(
+                "metrics.listen: `{}` is not a socket address",
+                self.metrics.listen
+            ));
+        }
+        if self.history.enabled {
+            self.history.validate()?;
+        }
+        if self.control.enabled {
+            if self.control.listen.parse::<SocketAddr>().is_err() {
+                return invalid(format!(
+                    "control.listen: `{}` is not a socket address",
+                    self.control.listen
+                ));
+            }
+            if self.control.token.is_empty() {
+                return invalid(
+                    "control token is not set; set B5120_CONTROL_TOKEN or control.token"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.auth.key_store.is_none() {
+            return invalid(if self.auth.learn_mode {
+                "auth.learn_mode needs auth.key_store to write the generated keys to".to_string()
+            } else {
+                "auth.key_store is not set; every device would be rejected".to_string()
+            });
+        }
+
+        if self.registry.path.is_none() && !self.registry.allow_unknown {
+            return invalid(
+                "registry.path is not set; every device would be rejected (set registry.allow_unknown to accept them)"
+                    .to_string(),
+            );
+        }
+
+        if self.session.response_timeout_ms == 0 {
+            return invalid("session.response_timeout_ms must be greater than 0".to_string());
+        }
+        if self.session.max_consecutive_timeouts == 0 {
+            return invalid("session.max_consecutive_timeouts must be greater than 0".to_string());
+        }
+
+        let mut names = HashSet::new();
+        let mut slugs = HashMap::new();
+        for inverter in &self.inverters {
+            if inverter.name.is_empty() {
+                return invalid("inverters: name must not be empty".to_string());
+            }
+            if inverter.site.as_deref() == Some("") {
+                return invalid(format!(
+                    "inverters.{}.site: must not be empty; leave it out instead",
+                    inverter.name
+                ));
+            }
+            if !names.insert(&inverter.name) {
+                return invalid(format!(
+                    "inverters: `{}` is configured twice",
+                    inverter.name
+                ));
+            }
+            if let Some(other) = slugs.insert(mqtt::slug(&inverter.name), &inverter.name) {
+                return invalid(format!(
+                    "inverters: `{}` and `{}` would share the MQTT topic `{}`",
+                    other,
+                    inverter.name,
+                    mqtt::slug(&inverter.name)
+                ));
+            }
+            match inverter.address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+                _ => {
+                    return invalid(format!(
+                        "inverters.{}.address: `{}` is not host:port",
+                        inverter.name, inverter.address
+                    ))
+                }
+            }
+            for (key, ms) in [
+                ("poll_interval_ms", inverter.poll_interval_ms),
+                ("response_timeout_ms", inverter.response_timeout_ms),
+                ("rating_interval_ms", inverter.rating_interval_ms),
+                ("warnings_interval_ms", inverter.warnings_interval_ms),
+                ("mode_interval_ms", inverter.mode_interval_ms),
+            ] {
+                if ms == 0 {
+                    return invalid(format!(
+                        "inverters.{}.{}: must be greater than 0",
+                        inverter.name, key
+                    ));
+                }
+            }
+        }
+
+        if self.sites.current_tolerance_a < 0.0 || self.sites.current_tolerance_percent < 0.0 {
+            return invalid("sites: current tolerances must not be negative".to_string());
+        }
+        if self.sites.pack_max_age_secs == 0 {
+            return invalid("sites.pack_max_age_secs: must be greater than 0".to_string());
+        }
+
+        for (name, secs) in &self.schedule {
+            if let Err(e) = name.parse::<RegisterGroup>() {
+                return invalid(format!("schedule: {}", e));
+            }
+            if *secs == 0 {
+                return invalid(format!("schedule.{} must be greater than 0", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the key store named in the `[auth]` section.
+    pub fn authenticator(&self) -> Result<Authenticator, ConfigError> {
+        let keys = match &self.auth.key_store {
+            Some(path) if self.auth.learn_mode && !path.exists() => KeyStore::create(path),
+            Some(path) => KeyStore::load(path)?,
+            None => KeyStore::default(),
+        };
+        Ok(Authenticator::new(keys, self.auth.learn_mode))
+    }
+
+    /// Loads the device registry named in the `[registry]` section.
+    pub fn registry(&self) -> Result<Registry, ConfigError> {
+        match &self.registry.path {
+            Some(path) => Registry::load(
+                path,
+                self.registry.allow_unknown,
+                self.inverters.iter().map(|i| i.name.clone()).collect(),
+            ),
+            None => Ok(Registry::empty(self.registry.allow_unknown)),
+        }
+    }
+
+    pub fn session_config(&self) -> SessionConfig {
+        let mut schedule = Schedule::new();
+        for (name, secs) in &self.schedule {
+            if let Ok(group) = name.parse() {
+                schedule.set(group, Duration::from_secs(*secs));
+            }
+        }
+
+        SessionConfig {
+            response_timeout: Duration::from_millis(self.session.response_timeout_ms),
+            retries: self.session.retries,
+            max_consecutive_timeouts: self.session.max_consecutive_timeouts,
+            min_command_gap: Duration::from_millis(self.session.min_command_gap_ms),
+            schedule,
+        }
+    }
+}
+
+impl InfluxDbConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        match Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+            _ => {
+                return invalid(format!(
+                    "influxdb.url: `{}` is not an http(s) URL",
+                    self.url
+                ))
+            }
+        }
+        if self.org.is_empty() {
+            return invalid("influxdb.org is not set".to_string());
+        }
+        if self.bucket.is_empty() {
+            return invalid("influxdb.bucket is not set".to_string());
+        }
+        if self.token.is_empty() {
+            return invalid(
+                "influxdb token is not set; set B5120_INFLUXDB_TOKEN or influxdb.token".to_string(),
+            );
+        }
+
+        if self.batch_size == 0 {
+            return invalid("influxdb.batch_size must be greater than 0".to_string());
+        }
+        if self.flush_interval_ms == 0 {
+            return invalid("influxdb.flush_interval_ms must be greater than 0".to_string());
+        }
+
+        if self.buffer_dir.is_some() && self.buffer_max_mb == 0 {
+            return invalid("influxdb.buffer_max_mb must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The v2 write endpoint for the configured org and bucket.
+    pub fn write_url(&self) -> String {
+        let mut url = Url::parse(&self.url).expect("validated in Config::load");
+        url.path_segments_mut()
+            .expect("http(s) URL")
+            .pop_if_empty()
+            .extend(["api", "v2", "write"]);
+        url.query_pairs_mut()
+            .append_pair("org", &self.org)
+            .append_pair("bucket", &self.bucket)
+            .append_pair("precision", "ms");
+        url.to_string()
+    }
+
+    pub fn batch_config(&self) -> BatchConfig {
+        BatchConfig {
+            max_lines: self.batch_size,
+            flush_interval: Duration::from_millis(self.flush_interval_ms),
+            gzip: self.gzip,
+            max_retries: self.max_retries,
+            ..BatchConfig::default()
+        }
+    }
+
+    /// Opens the on-disk buffer, if one is configured.
+    pub fn spool(&self) -> Result<Option<Spool>, ConfigError> {
+        match &self.buffer_dir {
+            Some(dir) => Spool::open(dir, self.buffer_max_mb * 1024 * 1024)
+                .map(Some)
+                .map_err(|e| ConfigError::Read(dir.clone(), e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Value of the `Authorization` header.
+    pub fn authorization(&self) -> String {
+        format!("Token {}", self.token)
+    }
+}
+
+impl Default for SitesConfig {
+    fn default() -> Self {
+        SitesConfig {
+            enabled: true,
+            current_tolerance_a: 5.0,
+            current_tolerance_percent: 10.0,
+            pack_max_age_secs: 30,
+        }
+    }
+}
+
+impl SitesConfig {
+    pub fn flow_config(&self) -> FlowConfig {
+        FlowConfig {
+            current_tolerance_a: self.current_tolerance_a,
+            current_tolerance_percent: self.current_tolerance_percent,
+            pack_max_age: chrono::Duration::seconds(self.pack_max_age_secs as i64),
+        }
+    }
+}
+
+impl InverterSettings {
+    fn default_poll_interval_ms() -> u64 {
+        5000
+    }
+
+    fn default_response_timeout_ms() -> u64 {
+        2000
+    }
+
+    fn default_rating_interval_ms() -> u64 {
+        3_600_000
+    }
+
+    fn default_warnings_interval_ms() -> u64 {
+        10_000
+    }
+
+    fn default_mode_interval_ms() -> u64 {
+        5000
+    }
+
+    pub fn inverter_config(&self) -> InverterConfig {
+        InverterConfig {
+            name: self.name.clone(),
+            site: self.site.clone(),
+            address: self.address.clone(),
+            poll_interval: Duration::from_millis(self.poll_interval_ms),
+            response_timeout: Duration::from_millis(self.response_timeout_ms),
+            rating_interval: Duration::from_millis(self.rating_interval_ms),
+            warnings_interval: Duration::from_millis(self.warnings_interval_ms),
+            mode_interval: Duration::from_millis(self.mode_interval_ms),
+        }
+    }
+}
+
+impl HistoryConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        if self.path.as_os_str().is_empty() {
+            return invalid("history.path is not set".to_string());
+        }
+        if self.listen.parse::<SocketAddr>().is_err() {
+            return invalid(format!(
+                "history.listen: `{}` is not a socket address",
+                self.listen
+            ));
+        }
+        for (name, days) in [
+            ("raw_days", self.raw_days),
+            ("minute_days", self.minute_days),
+            ("hour_days", self.hour_days),
+        ] {
+            if days == 0 {
+                return invalid(format!("history.{} must be greater than 0", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            raw_days: self.raw_days,
+            minute_days: self.minute_days,
+            hour_days: self.hour_days,
+        }
+    }
+}
+
+impl MqttConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        if self.host.is_empty() {
+            return invalid("mqtt.host is not set".to_string());
+        }
+        if self.port == 0 {
+            return invalid("mqtt.port must be greater than 0".to_string());
+        }
+        if self.client_id.is_empty() {
+            return invalid("mqtt.client_id is not set".to_string());
+        }
+        for (name, prefix) in [
+            ("topic_prefix", &self.topic_prefix),
+            ("discovery_prefix", &self.discovery_prefix),
+        ] {
+            if prefix.is_empty() || prefix.contains(['+', '#']) {
+                return invalid(format!(
+                    "mqtt.{}: `{}` is not a topic without wildcards",
+                    name, prefix
+                ));
+            }
+        }
+        if self.keep_alive_secs == 0 {
+            return invalid("mqtt.keep_alive_secs must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(self.keep_alive_secs));
+        if !self.username.is_empty() {
+            options.set_credentials(&self.username, &self.password);
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration that passes validation.
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.influxdb.org = "site".to_string();
+        config.influxdb.bucket = "battery".to_string();
+        config.influxdb.token = "secret".to_string();
+        config.auth.key_store = Some(PathBuf::from("/etc/powermax/keys.toml"));
+        config.registry.allow_unknown = true;
+        config
+    }
+
+    fn inverter(name: &str) -> InverterSettings {
+        toml::from_str(&format!("name = \"{}\"\naddress = \"10.0.0.5:8899\"", name)).unwrap()
+    }
+
+    #[test]
+    fn parses_the_example() {
+        let text = fs::read_to_string("config.example.toml").unwrap();
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.influxdb.org, "my-org");
+        assert!(config.influxdb.token.is_empty());
+    }
+
+    #[test]
+    fn accepts_a_valid_configuration() {
+        let mut config = valid();
+        config.inverters.push(inverter("garage"));
+        config.schedule.insert("current".to_string(), 2);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn requires_org_and_bucket() {
+        let mut config = valid();
+        config.influxdb.org = InfluxDbConfig::default().org;
+        config.influxdb.bucket = InfluxDbConfig::default().bucket;
+        assert!(config.validate().is_err());
+
+        config.influxdb.enabled = false;
+        config.metrics.enabled = true;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("[influxdb]\nbukket = \"battery\"").is_err());
+        assert!(toml::from_str::<Config>("lisen = \"0.0.0.0:1\"").is_err());
+    }
+
+    /// Breaks one setting of a valid configuration.
+    type Change = fn(&mut Config);
+
+    #[test]
+    fn names_each_invalid_setting() {
+        let cases: Vec<(Change, &str)> = vec![
+            (|c| c.listen = "nowhere".to_string(), "listen:"),
+            (|c| c.influxdb.enabled = false, "no output is enabled"),
+            (|c| c.influxdb.url = "ftp://db".to_string(), "influxdb.url"),
+            (|c| c.influxdb.org.clear(), "influxdb.org"),
+            (|c| c.influxdb.bucket.clear(), "influxdb.bucket"),
+            (|c| c.influxdb.token.clear(), "B5120_INFLUXDB_TOKEN"),
+            (|c| c.influxdb.batch_size = 0, "influxdb.batch_size"),
+            (
+                |c| c.influxdb.flush_interval_ms = 0,
+                "influxdb.flush_interval_ms",
+            ),
+            (
+                |c| {
+                    c.influxdb.buffer_dir = Some(PathBuf::from("/tmp/buffer"));
+                    c.influxdb.buffer_max_mb = 0;
+                },
+                "influxdb.buffer_max_mb",
+            ),
+            (
+                |c| {
+                    c.mqtt.enabled = true;
+                    c.mqtt.host.clear();
+                },
+                "mqtt.host",
+            ),
+            (
+                |c| {
+                    c.mqtt.enabled = true;
+                    c.mqtt.port = 0;
+                },
+                "mqtt.port",
+            ),
+            (
+                |c| {
+                    c.mqtt.enabled = true;
+                    c.mqtt.client_id.clear();
+                },
+                "mqtt.client_id",
+            ),
+            (
+                |c| {
+                    c.mqtt.enabled = true;
+                    c.mqtt.topic_prefix = "powermax/#".to_string();
+                },
+                "mqtt.topic_prefix",
+            ),
+            (
+                |c| {
+                    c.mqtt.enabled = true;
+                    c.mqtt.keep_alive_secs = 0;
+                },
+                "mqtt.keep_alive_secs",
+            ),
+            (
+                |c| {
+                    c.metrics.enabled = true;
+                    c.metrics.listen = "9120".to_string();
+                },
+                "metrics.listen",
+            ),
+            (
+                |c| {
+                    c.history.enabled = true;
+                    c.history.path = PathBuf::new();
+                },
+                "history.path",
+            ),
+            (
+                |c| {
+                    c.history.enabled = true;
+                    c.history.listen = "9121".to_string();
+                },
+                "history.listen",
+            ),
+            (
+                |c| {
+                    c.history.enabled = true;
+                    c.history.minute_days = 0;
+                },
+                "history.minute_days",
+            ),
+            (
+                |c| {
+                    c.control.enabled = true;
+                    c.control.listen = "9122".to_string();
+                },
+                "control.listen",
+            ),
+            (|c| c.control.enabled = true, "B5120_CONTROL_TOKEN"),
+            (|c| c.auth.key_store = None, "auth.key_store is not set"),
+            (
+                |c| {
+                    c.auth.key_store = None;
+                    c.auth.learn_mode = true;
+                },
+                "auth.learn_mode",
+            ),
+            (|c| c.registry.allow_unknown = false, "registry.path"),
+            (
+                |c| c.session.response_timeout_ms = 0,
+                "session.response_timeout_ms",
+            ),
+            (
+                |c| c.session.max_consecutive_timeouts = 0,
+                "session.max_consecutive_timeouts",
+            ),
+            (|c| c.inverters.push(inverter("")), "name must not be empty"),
+            (
+                |c| {
+                    c.inverters.push(inverter("garage"));
+                    c.inverters.push(inverter("garage"));
+                },
+                "`garage` is configured twice",
+            ),
+            (
+                |c| {
+                    c.inverters.push(inverter("Inverter 1"));
+                    c.inverters.push(inverter("inverter-1"));
+                },
+                "share the MQTT topic `inverter_1`",
+            ),
+            (
+                |c| {
+                    let mut garage = inverter("garage");
+                    garage.site = Some(String::new());
+                    c.inverters.push(garage);
+                },
+                "inverters.garage.site",
+            ),
+            (
+                |c| {
+                    let mut garage = inverter("garage");
+                    garage.address = "10.0.0.5".to_string();
+                    c.inverters.push(garage);
+                },
+                "inverters.garage.address",
+            ),
+            (
+                |c| {
+                    let mut garage = inverter("garage");
+                    garage.mode_interval_ms = 0;
+                    c.inverters.push(garage);
+                },
+                "inverters.garage.mode_interval_ms",
+            ),
+            (|c| c.sites.current_tolerance_a = -1.0, "sites: current"),
+            (|c| c.sites.pack_max_age_secs = 0, "sites.pack_max_age_secs"),
+            (
+                |c| {
+                    c.schedule.insert("voltage".to_string(), 2);
+                },
+                "schedule:",
+            ),
+            (
+                |c| {
+                    c.schedule.insert("cells".to_string(), 0);
+                },
+                "schedule.cells",
+            ),
+        ];
+
+        for (change, expected) in cases {
+            let mut config = valid();
+            change(&mut config);
+            match config.validate() {
+                Err(ConfigError::Invalid(msg)) => {
+                    assert!(msg.contains(expected), "`{}` should name {}", msg, expected)
+                }
+                other => panic!("expected an error naming {}, got {:?}", expected, other),
+            }
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config: Config = toml::from_str(
+            "listen = \"0.0.0.0:1\"\n[influxdb]\norg = \"file-org\"\nbucket = \"file-bucket\"\ntoken = \"file-token\"",
+        )
+        .unwrap();
+        config.apply_env(|name| match name {
+            "B5120_INFLUXDB_ORG" => Some("env-org".to_string()),
+            "B5120_INFLUXDB_TOKEN" => Some("env-token".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(config.listen, "0.0.0.0:1");
+        assert_eq!(config.influxdb.org, "env-org");
+        assert_eq!(config.influxdb.bucket, "file-bucket");
+        assert_eq!(config.influxdb.token, "env-token");
+    }
+
+    #[test]
+    fn builds_the_write_url() {
+        let mut influxdb = valid().influxdb;
+        influxdb.url = "https://db.example/influx/".to_string();
+        influxdb.bucket = "pack data".to_string();
+        assert_eq!(
+            influxdb.write_url(),
+            "https://db.example/influx/api/v2/write?org=site&bucket=pack+data&precision=ms"
+        );
+    }
+}
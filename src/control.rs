@@ -0,0 +1,251 @@
+//! HTTP endpoint for changing inverter settings:
+//!
+//! ```text
+//! POST /settings?inverter=<name>&setting=<name>&value=<value>[&dry_run=true]
+//! ```
+//!
+//! Every setting is validated by the inverter's own task before anything is
+//! sent; a dry run reports the frame that would have been sent.
+//!
+//! Requests must carry the configured token as `Authorization: Bearer
+//! <token>`. Requests with an `Origin` header come from a browser and are
+//! refused, so that a web page cannot change settings through a browser on
+//! the operator's network.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use crate::http::{self, Request, Response};
+use crate::inverter::InverterControl;
+use crate::setting::{InverterSetting, Outcome, SettingError};
+
+/// Settings handles of the running inverters, by name.
+#[derive(Clone)]
+pub struct Control {
+    inverters: Arc<BTreeMap<String, InverterControl>>,
+    /// Value of the `Authorization` header every request must carry.
+    authorization: Arc<str>,
+}
+
+impl Control {
+    pub fn new(inverters: BTreeMap<String, InverterControl>, token: &str) -> Self {
+        Control {
+            inverters: Arc::new(inverters),
+            authorization: format!("Bearer {}", token).into(),
+        }
+    }
+
+    /// Serves `POST /settings` on `listener` until the task is dropped.
+    pub async fn serve(self, listener: TcpListener) {
+        http::serve(listener, "control", &["POST"], move |request: Request| {
+            let control = self.clone();
+            async move { control.handle(&request).await }
+        })
+        .await
+    }
+
+    async fn handle(&self, request: &Request) -> Response {
+        if request.header("origin").is_some() {
+            return Response::error("403 Forbidden", "cross-origin requests are refused");
+        }
+        let authorized = request
+            .header("authorization")
+            .is_some_and(|value| equal(value.as_bytes(), self.authorization.as_bytes()));
+        if !authorized {
+            return Response::error("401 Unauthorized", "a valid bearer token is required");
+        }
+
+        match request.url.path() {
+            "/settings" => self.respond(request).await,
+            _ => Response::error("404 Not Found", "not found"),
+        }
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        let bad = |msg: String| Response::error("400 Bad Request", &msg);
+
+        let name = match request.query("inverter") {
+            Some(name) => name,
+            None => return bad("inverter is missing".to_string()),
+        };
+        let inverter = match self.inverters.get(&name) {
+            Some(inverter) => inverter,
+            None => return Response::error("404 Not Found", &format!("no inverter `{}`", name)),
+        };
+        let setting = match (request.query("setting"), request.query("value")) {
+            (Some(setting), Some(value)) => match InverterSetting::parse(&setting, &value) {
+                Ok(setting) => setting,
+                Err(e) => return bad(e),
+            },
+            _ => return bad("setting and value are required".to_string()),
+        };
+        let dry_run = match request.query("dry_run").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return bad(format!("dry_run `{}` is not true or false", other)),
+        };
+
+        match inverter.apply(setting, dry_run).await {
+            Ok(Outcome::Applied) => Response::new(
+                "200 OK",
+                "text/plain; charset=utf-8",
+                format!("{} set {}\n", name, setting),
+            ),
+            Ok(Outcome::DryRun(frame)) => Response::new(
+                "200 OK",
+                "text/plain; charset=utf-8",
+                format!("dry run: would send {}\n", frame.escape_ascii()),
+            ),
+            Err(e) => {
+                let status = match e {
+                    SettingError::Invalid(_) => "422 Unprocessable Entity",
+                    SettingError::NoRating | SettingError::NotConnected | SettingError::Stopped => {
+                        "503 Service Unavailable"
+                    }
+                    SettingError::LinkLost(_) | SettingError::Inverter(_) => "502 Bad Gateway",
+                };
+                Response::error(status, &e.to_string())
+            }
+        }
+    }
+}
+
+/// Compares in time that depends only on the lengths, so that the token
+/// cannot be guessed byte by byte from response times.
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::inverter::{Inverter, InverterConfig};
+    use crate::sink::Router;
+
+    /// Control of one inverter that never connects, so it has no ratings.
+    fn control() -> Control {
+        let inverter = Inverter::new(
+            InverterConfig {
+                name: "garage".to_string(),
+                site: None,
+                // nothing listens on port 1
+                address: "127.0.0.1:1".to_string(),
+                poll_interval: Duration::from_secs(5),
+                response_timeout: Duration::from_millis(100),
+                rating_interval: Duration::from_secs(3600),
+                warnings_interval: Duration::from_secs(10),
+                mode_interval: Duration::from_secs(5),
+            },
+            Arc::new(Router::new()),
+        );
+        let inverters = BTreeMap::from([("garage".to_string(), inverter.control())]);
+        tokio::spawn(inverter.run());
+        Control::new(inverters, "secret")
+    }
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "POST".to_string(),
+            url: Url::parse(&format!("http://localhost{}", target)).unwrap(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    async fn status(control: &Control, target: &str) -> &'static str {
+        control
+            .handle(&request(target, &[("authorization", "Bearer secret")]))
+            .await
+            .status
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let control = control();
+        let target = "/settings?inverter=garage&setting=max_charging_current&value=60";
+        for headers in [
+            &[][..],
+            &[("authorization", "Bearer secreT")][..],
+            &[("authorization", "Bearer secret2")][..],
+            &[("authorization", "secret")][..],
+        ] {
+            let response = control.handle(&request(target, headers)).await;
+            assert_eq!(response.status, "401 Unauthorized", "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_cross_origin_requests() {
+        let control = control();
+        let response = control
+            .handle(&request(
+                "/settings?inverter=garage&setting=max_charging_current&value=60",
+                &[
+                    ("authorization", "Bearer secret"),
+                    ("origin", "http://example.com"),
+                ],
+            ))
+            .await;
+        assert_eq!(response.status, "403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn checks_parameters() {
+        let control = control();
+        for (target, expected) in [
+            ("/other", "404 Not Found"),
+            (
+                "/settings?setting=max_charging_current&value=60",
+                "400 Bad Request",
+            ),
+            (
+                "/settings?inverter=attic&setting=max_charging_current&value=60",
+                "404 Not Found",
+            ),
+            (
+                "/settings?inverter=garage&setting=max_charging_current",
+                "400 Bad Request",
+            ),
+            (
+                "/settings?inverter=garage&setting=volume&value=11",
+                "400 Bad Request",
+            ),
+            (
+                "/settings?inverter=garage&setting=max_charging_current&value=lots",
+                "400 Bad Request",
+            ),
+            (
+                "/settings?inverter=garage&setting=max_charging_current&value=60&dry_run=yes",
+                "400 Bad Request",
+            ),
+        ] {
+            assert_eq!(status(&control, target).await, expected, "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn needs_ratings_before_sending() {
+        let control = control();
+        for target in [
+            "/settings?inverter=garage&setting=max_charging_current&value=60",
+            "/settings?inverter=garage&setting=max_charging_current&value=60&dry_run=true",
+        ] {
+            assert_eq!(status(&control, target).await, "503 Service Unavailable");
+        }
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(equal(b"Bearer secret", b"Bearer secret"));
+        assert!(!equal(b"Bearer secret", b"Bearer secreT"));
+        assert!(!equal(b"Bearer secret", b"Bearer secret "));
+        assert!(!equal(b"", b"Bearer secret"));
+    }
+}
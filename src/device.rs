@@ -0,0 +1,116 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Identity of a connected device: the 6-byte MAC address it sends in its
+/// hello.
+///
+/// Formats as `aa:bb:cc:dd:ee:ff` and parses from the same form, in either
+/// case and with `-` instead of every `:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId([u8; 6]);
+
+impl DeviceId {
+    pub fn new(mac: [u8; 6]) -> Self {
+        DeviceId(mac)
+    }
+
+    pub fn from_slice(mac: &[u8]) -> Option<Self> {
+        mac.try_into().ok().map(DeviceId)
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("`{}` is not a MAC address like aa:bb:cc:dd:ee:ff", s);
+
+        // Either separator is accepted, but only one of them throughout.
+        let separator = match s.as_bytes().get(2) {
+            Some(b':') => ':',
+            Some(b'-') => '-',
+            _ => return Err(err()),
+        };
+
+        let mut mac = [0; 6];
+        let mut parts = s.split(separator);
+        for byte in mac.iter_mut() {
+            let part = parts.next().ok_or_else(err)?;
+            if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(err());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| err())?;
+        }
+        if parts.next().is_some() {
+            return Err(err());
+        }
+
+        Ok(DeviceId(mac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_with_leading_zeros() {
+        let id = DeviceId::new([0x00, 0x1B, 0x02, 0x3D, 0x0E, 0x5F]);
+        assert_eq!(id.to_string(), "00:1b:02:3d:0e:5f");
+        assert_eq!(id.to_string().parse::<DeviceId>(), Ok(id));
+    }
+
+    #[test]
+    fn parses_either_separator_and_case() {
+        let id = DeviceId::new([0xAA, 0xBB, 0xCC, 0x0D, 0xEE, 0xFF]);
+        for s in [
+            "aa:bb:cc:0d:ee:ff",
+            "AA:BB:CC:0D:EE:FF",
+            "aa-bb-cc-0d-ee-ff",
+        ] {
+            assert_eq!(s.parse::<DeviceId>(), Ok(id), "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for s in [
+            "",
+            "aa:bb:cc:dd:ee",
+            "aa:bb:cc:dd:ee:ff:00",
+            "aa:bb-cc:dd:ee:ff",
+            "aa:bb:cc:dd:ee:+f",
+            "aa:bb:cc:dd:ee:f",
+            "aa:bb:cc:dd:ee:fff",
+            "aabbccddeeff",
+            "aa bb cc dd ee ff",
+            "aa:bb:cc:dd:ee:gg",
+        ] {
+            assert!(s.parse::<DeviceId>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn reads_slices_of_six_bytes() {
+        assert_eq!(
+            DeviceId::from_slice(&[1, 2, 3, 4, 5, 6]),
+            Some(DeviceId::new([1, 2, 3, 4, 5, 6]))
+        );
+        assert_eq!(DeviceId::from_slice(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,392 @@
+//! Battery pack protocols.
+//!
+//! A driver decides what is polled on a pack and how: which register
+//! groups it has and how often they are read by default, which registers
+//! make up each group, how a register is asked for, how a response is
+//! delimited and checked, and how it ends up in the `PackSnapshot`. The
+//! session does the timing and retries. Which driver a device gets is
+//! decided once per connection by `select`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::time::Duration;
+
+use crate::error::Error;
+use crate::handshake::Hello;
+use crate::protocol::{crc8_check, CRC_8};
+use crate::register::{Reading, Register, CELL_COUNT, TEMPERATURE_COUNT};
+use crate::registry::DeviceInfo;
+use crate::schedule::{RegisterGroup, Schedule};
+use crate::snapshot::PackSnapshot;
+use crate::status::BatteryStatus;
+
+/// One battery pack protocol.
+///
+/// Responses carry no header, so a response is only meaningful relative to
+/// the request it answers; `check` rejects a frame that fails the
+/// protocol's integrity check, and the codec then looks for the frame
+/// elsewhere in the received bytes.
+pub trait BatteryDriver: fmt::Debug + Send + Sync {
+    /// Name used for the driver in the registry, e.g. `b5120`.
+    fn name(&self) -> &'static str;
+
+    /// Whether a device announcing protocol `version` in its hello speaks
+    /// this protocol; `None` is a bare-MAC hello.
+    fn identify(&self, version: Option<u8>) -> bool;
+
+    /// Register groups the pack has, with their default intervals. The
+    /// `[schedule]` config overrides the intervals but adds no groups.
+    fn schedule(&self) -> Schedule;
+
+    /// Registers read, in order, each time `group` is due.
+    fn registers(&self, group: RegisterGroup) -> Vec<Register>;
+
+    /// Bytes that ask the pack for `register`.
+    fn request(&self, register: Register) -> Vec<u8>;
+
+    /// Length of a complete response to `register`, including any
+    /// checksum.
+    fn response_len(&self, register: Register) -> usize;
+
+    /// Whether a complete response of `response_len` bytes passes the
+    /// protocol's integrity check.
+    fn check(&self, register: Register, frame: &[u8]) -> bool;
+
+    /// Stores a checked response in `snapshot` and returns what was read,
+    /// or `None` if its content makes no sense.
+    fn decode(
+        &self,
+        register: Register,
+        frame: &[u8],
+        snapshot: &mut PackSnapshot,
+    ) -> Option<Reading>;
+}
+
+/// The B5120 protocol: `[0x0A, register, length]` requests answered by the
+/// big-endian value and a CRC-8 over request and value.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct B5120;
+
+impl BatteryDriver for B5120 {
+    fn name(&self) -> &'static str {
+        "b5120"
+    }
+
+    /// Existing firmware sends a bare MAC; framed hellos announce version 1.
+    fn identify(&self, version: Option<u8>) -> bool {
+        matches!(version, None | Some(1))
+    }
+
+    fn schedule(&self) -> Schedule {
+        Schedule::default()
+    }
+
+    fn registers(&self, group: RegisterGroup) -> Vec<Register> {
+        match group {
+            RegisterGroup::Cells => (1..=CELL_COUNT).map(Register::CellVoltage).collect(),
+            RegisterGroup::Temperatures => {
+                (1..=TEMPERATURE_COUNT).map(Register::Temperature).collect()
+            }
+            RegisterGroup::TotalVoltage => vec![Register::TotalVoltage],
+            RegisterGroup::Current => vec![Register::Current],
+            RegisterGroup::FullCapacity => vec![Register::FullCapacity],
+            RegisterGroup::RemainingCapacity => vec![Register::RemainingCapacity],
+            RegisterGroup::Rsoc => vec![Register::Rsoc],
+            RegisterGroup::CycleCount => vec![Register::CycleCount],
+            RegisterGroup::Status => vec![Register::PackStatus, Register::BatteryStatus],
+            RegisterGroup::PackConfig => vec![Register::PackConfig],
+        }
+    }
+
+    fn request(&self, register: Register) -> Vec<u8> {
+        register.request().to_vec()
+    }
+
+    fn response_len(&self, register: Register) -> usize {
+        register.data_len() as usize + 1
+    }
+
+    fn check(&self, register: Register, frame: &[u8]) -> bool {
+        crc8_check(&register.request(), frame)
+    }
+
+    fn decode(
+        &self,
+        register: Register,
+        frame: &[u8],
+        snapshot: &mut PackSnapshot,
+    ) -> Option<Reading> {
+        let reading = register.decode(&frame[..frame.len() - 1])?;
+        snapshot.apply(&reading);
+        Some(reading)
+    }
+}
+
+/// Packs with a Smart Battery Data (SBS 1.1) gauge behind the same serial
+/// bridge, announcing protocol version 2.
+///
+/// Requests are `[0x16, command]`; responses are the little-endian word
+/// followed by its SMBus PEC. Only the standard SBS commands are read, so
+/// cell voltages and the B5120 pack status and config are not available.
+///
+/// Not yet tested against hardware.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sbs;
+
+impl Sbs {
+    /// SMBus write address of a smart battery, 0x0B shifted left.
+    const ADDRESS: u8 = 0x16;
+
+    /// SBS command reading `register`.
+    fn command(register: Register) -> Option<u8> {
+        match register {
+            // Temperature, in 0.1 K
+            Register::Temperature(1) => Some(0x08),
+            // Voltage, in mV
+            Register::TotalVoltage => Some(0x09),
+            // Current, in mA
+            Register::Current => Some(0x0A),
+            // RelativeStateOfCharge, in %
+            Register::Rsoc => Some(0x0D),
+            // RemainingCapacity, in mAh
+            Register::RemainingCapacity => Some(0x0F),
+            // FullChargeCapacity, in mAh
+            Register::FullCapacity => Some(0x10),
+            // BatteryStatus
+            Register::BatteryStatus => Some(0x16),
+            // CycleCount
+            Register::CycleCount => Some(0x17),
+            _ => None,
+        }
+    }
+
+    /// Maps the SBS BatteryStatus bits onto the B5120 battery status.
+    fn battery_status(word: u16) -> BatteryStatus {
+        const FULLY_DISCHARGED: u16 = 1 << 4;
+        const FULLY_CHARGED: u16 = 1 << 5;
+        const DISCHARGING: u16 = 1 << 6;
+
+        let mut bits = 0;
+        if word & DISCHARGING != 0 {
+            bits |= BatteryStatus::DISCHARGING;
+        }
+        if word & FULLY_CHARGED != 0 {
+            bits |= BatteryStatus::FULLY_CHARGED;
+        }
+        if word & FULLY_DISCHARGED != 0 {
+            bits |= BatteryStatus::FULLY_DISCHARGED;
+        }
+        BatteryStatus(bits)
+    }
+}
+
+impl BatteryDriver for Sbs {
+    fn name(&self) -> &'static str {
+        "sbs"
+    }
+
+    fn identify(&self, version: Option<u8>) -> bool {
+        version == Some(2)
+    }
+
+    fn schedule(&self) -> Schedule {
+        let secs = Duration::from_secs;
+        let mut schedule = Schedule::new();
+        schedule.set(RegisterGroup::Current, secs(2));
+        schedule.set(RegisterGroup::TotalVoltage, secs(2));
+        schedule.set(RegisterGroup::Status, secs(5));
+        schedule.set(RegisterGroup::Temperatures, secs(10));
+        schedule.set(RegisterGroup::RemainingCapacity, secs(10));
+        schedule.set(RegisterGroup::Rsoc, secs(10));
+        schedule.set(RegisterGroup::FullCapacity, secs(3600));
+        schedule.set(RegisterGroup::CycleCount, secs(3600));
+        schedule
+    }
+
+    fn registers(&self, group: RegisterGroup) -> Vec<Register> {
+        match group {
+            RegisterGroup::Temperatures => vec![Register::Temperature(1)],
+            RegisterGroup::TotalVoltage => vec![Register::TotalVoltage],
+            RegisterGroup::Current => vec![Register::Current],
+            RegisterGroup::FullCapacity => vec![Register::FullCapacity],
+            RegisterGroup::RemainingCapacity => vec![Register::RemainingCapacity],
+            RegisterGroup::Rsoc => vec![Register::Rsoc],
+            RegisterGroup::CycleCount => vec![Register::CycleCount],
+            RegisterGroup::Status => vec![Register::BatteryStatus],
+            RegisterGroup::Cells | RegisterGroup::PackConfig => Vec::new(),
+        }
+    }
+
+    fn request(&self, register: Register) -> Vec<u8> {
+        // Only registers listed by `registers` are requested.
+        vec![Self::ADDRESS, Self::command(register).unwrap_or_default()]
+    }
+
+    fn response_len(&self, _register: Register) -> usize {
+        3
+    }
+
+    fn check(&self, register: Register, frame: &[u8]) -> bool {
+        // The PEC covers the write address, the command, the read address
+        // and the word.
+        let mut data = self.request(register);
+        data.push(Self::ADDRESS | 1);
+        data.extend_from_slice(&frame[..2]);
+        CRC_8.checksum(&data) == frame[2]
+    }
+
+    fn decode(
+        &self,
+        register: Register,
+        frame: &[u8],
+        snapshot: &mut PackSnapshot,
+    ) -> Option<Reading> {
+        let word = u16::from_le_bytes([frame[0], frame[1]]);
+        let raw = match register {
+            Register::Current => i64::from(word as i16),
+            // 0.1 K to the 0.01 °C of the B5120 temperature registers
+            Register::Temperature(_) => (i64::from(word) - 2731) * 10,
+            Register::BatteryStatus => i64::from(Self::battery_status(word).bits()),
+            _ => i64::from(word),
+        };
+        let reading = Reading { register, raw };
+        snapshot.apply(&reading);
+        Some(reading)
+    }
+}
+
+/// Every driver, in the order `select` tries them.
+pub fn drivers() -> Vec<Arc<dyn BatteryDriver>> {
+    vec![Arc::new(B5120), Arc::new(Sbs)]
+}
+
+/// The driver called `name`.
+pub fn by_name(name: &str) -> Option<Arc<dyn BatteryDriver>> {
+    drivers().into_iter().find(|driver| driver.name() == name)
+}
+
+/// Picks the driver for a device: the one named in its registry entry if
+/// there is one, otherwise the first that recognises the hello.
+pub fn select(hello: &Hello, device: Option<&DeviceInfo>) -> Result<Arc<dyn BatteryDriver>, Error> {
+    if let Some(name) = device.and_then(|device| device.driver.as_deref()) {
+        // Names are checked when the registry is loaded.
+        return by_name(name).ok_or(Error::NoDriver(hello.id, hello.version));
+    }
+    drivers()
+        .into_iter()
+        .find(|driver| driver.identify(hello.version))
+        .ok_or(Error::NoDriver(hello.id, hello.version))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::prelude::*;
+
+    use super::*;
+    use crate::device::DeviceId;
+
+    fn snapshot() -> PackSnapshot {
+        PackSnapshot::new(DeviceId::new([0, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]), Utc::now())
+    }
+
+    fn hello(version: Option<u8>) -> Hello {
+        Hello {
+            id: DeviceId::new([0, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]),
+            version,
+        }
+    }
+
+    /// A B5120 response carrying `data`.
+    fn b5120_frame(register: Register, data: &[u8]) -> Vec<u8> {
+        let mut frame = data.to_vec();
+        let mut covered = register.request().to_vec();
+        covered.extend_from_slice(data);
+        frame.push(CRC_8.checksum(&covered));
+        frame
+    }
+
+    #[test]
+    fn b5120_decodes_into_snapshot() {
+        let frame = b5120_frame(Register::Current, &(-1500i32).to_be_bytes());
+        assert_eq!(B5120.response_len(Register::Current), frame.len());
+        assert!(B5120.check(Register::Current, &frame));
+        assert!(!B5120.check(Register::TotalVoltage, &frame));
+
+        let mut snapshot = snapshot();
+        let reading = B5120.decode(Register::Current, &frame, &mut snapshot);
+        assert_eq!(reading.map(|r| r.raw), Some(-1500));
+        assert_eq!(snapshot.current_ma, Some(-1500));
+    }
+
+    #[test]
+    fn b5120_groups() {
+        assert_eq!(
+            B5120.registers(RegisterGroup::Cells).len(),
+            CELL_COUNT as usize
+        );
+        assert_eq!(
+            B5120.registers(RegisterGroup::Status),
+            [Register::PackStatus, Register::BatteryStatus]
+        );
+        assert_eq!(B5120.schedule().intervals().len(), RegisterGroup::ALL.len());
+    }
+
+    #[test]
+    fn sbs_decodes_into_snapshot() {
+        // Temperature 298.2 K, PEC over 16 08 17 A6 0B
+        let word = 2982u16.to_le_bytes();
+        let pec = CRC_8.checksum(&[0x16, 0x08, 0x17, word[0], word[1]]);
+        let frame = [word[0], word[1], pec];
+        assert!(Sbs.check(Register::Temperature(1), &frame));
+        assert!(!Sbs.check(Register::TotalVoltage, &frame));
+
+        let mut snapshot = snapshot();
+        Sbs.decode(Register::Temperature(1), &frame, &mut snapshot);
+        assert_eq!(snapshot.temperatures[0], Some(25.1));
+
+        let discharging = (1u16 << 6 | 1 << 7).to_le_bytes();
+        Sbs.decode(
+            Register::BatteryStatus,
+            &[discharging[0], discharging[1], 0],
+            &mut snapshot,
+        );
+        assert_eq!(
+            snapshot.battery_status,
+            Some(BatteryStatus(BatteryStatus::DISCHARGING))
+        );
+    }
+
+    #[test]
+    fn sbs_has_no_cells() {
+        assert!(Sbs.registers(RegisterGroup::Cells).is_empty());
+        assert!(Sbs
+            .schedule()
+            .intervals()
+            .iter()
+            .all(|(group, _)| !Sbs.registers(*group).is_empty()));
+    }
+
+    #[test]
+    fn selects_by_version_or_registry() {
+        assert_eq!(select(&hello(None), None).unwrap().name(), "b5120");
+        assert_eq!(select(&hello(Some(1)), None).unwrap().name(), "b5120");
+        assert_eq!(select(&hello(Some(2)), None).unwrap().name(), "sbs");
+        assert!(matches!(
+            select(&hello(Some(9)), None),
+            Err(Error::NoDriver(_, Some(9)))
+        ));
+
+        let device = DeviceInfo {
+            name: "Pack 1".to_string(),
+            site: "north-depot".to_string(),
+            position: None,
+            cell_count: None,
+            design_capacity_mah: None,
+            inverter: None,
+            driver: Some("sbs".to_string()),
+            min_command_gap_ms: None,
+        };
+        assert_eq!(select(&hello(None), Some(&device)).unwrap().name(), "sbs");
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt;
+use std::io;
+
+use crate::auth::AuthError;
+use crate::device::DeviceId;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer closed the connection.
+    Closed,
+    /// The first message from the device was not a valid hello.
+    Handshake(Vec<u8>),
+    /// The device did not answer in time, too often in a row.
+    Timeout,
+    /// The device is not in the registry and unknown devices are rejected.
+    NotRegistered(DeviceId),
+    /// The device failed authentication.
+    Auth(DeviceId, AuthError),
+    /// No driver speaks the protocol version the device announced.
+    NoDriver(DeviceId, Option<u8>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "socket error: {}", e),
+            Error::Closed => f.write_str("connection closed by peer"),
+            Error::Handshake(rev) => write!(f, "malformed hello, REV: {:02X?}", rev),
+            Error::Timeout => f.write_str("device stopped answering"),
+            Error::NotRegistered(id) => write!(f, "device {} is not registered", id),
+            Error::Auth(id, e) => write!(f, "authentication of {} failed: {}", id, e),
+            Error::NoDriver(id, Some(version)) => {
+                write!(f, "no driver for {} protocol version {}", id, version)
+            }
+            Error::NoDriver(id, None) => write!(f, "no driver for {}", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,176 @@
+//! The hello a device sends right after connecting.
+//!
+//! Two forms are accepted:
+//!
+//! * the bare 6-byte MAC address sent by existing firmware, and
+//! * a framed hello `[HELLO_MAGIC, version, mac(6), crc8]`, where the CRC-8
+//!   covers the first eight bytes.
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::device::DeviceId;
+use crate::error::Error;
+use crate::protocol::CRC_8;
+
+/// First byte of a framed hello.
+pub const HELLO_MAGIC: u8 = 0xA5;
+
+const BARE_LEN: usize = 6;
+const FRAMED_LEN: usize = 9;
+
+/// How long to wait for further bytes once a bare hello's worth arrived,
+/// to tell it apart from the start of a framed one.
+const SETTLE: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub id: DeviceId,
+    /// Protocol version from a framed hello; `None` for a bare MAC.
+    pub version: Option<u8>,
+}
+
+impl Hello {
+    /// Parses a complete hello.
+    pub fn parse(buf: &[u8]) -> Option<Hello> {
+        match buf.len() {
+            BARE_LEN => Some(Hello {
+                id: DeviceId::from_slice(buf)?,
+                version: None,
+            }),
+            FRAMED_LEN if buf[0] == HELLO_MAGIC && CRC_8.checksum(&buf[..8]) == buf[8] => {
+                Some(Hello {
+                    id: DeviceId::from_slice(&buf[2..8])?,
+                    version: Some(buf[1]),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads the hello, tolerating it arriving in several segments.
+///
+/// Fails with `Error::Timeout` if nothing usable arrives within `deadline`
+/// and with `Error::Handshake` if the bytes are not a valid hello.
+pub async fn read_hello(socket: &mut TcpStream, deadline: Duration) -> Result<Hello, Error> {
+    let mut buf = Vec::with_capacity(FRAMED_LEN);
+    let mut chunk = [0; 64];
+    let mut until = Instant::now() + deadline;
+
+    loop {
+        let n = match timeout_at(until, socket.read(&mut chunk)).await {
+            Ok(n) => n?,
+            // Nothing more came after a bare hello's worth of bytes.
+            Err(_) if buf.len() >= BARE_LEN => break,
+            Err(_) if buf.is_empty() => return Err(Error::Timeout),
+            Err(_) => return Err(Error::Handshake(buf)),
+        };
+        if n == 0 {
+            // socket closed
+            return Err(Error::Closed);
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() >= FRAMED_LEN {
+            break;
+        }
+        if buf.len() >= BARE_LEN {
+            until = until.min(Instant::now() + SETTLE);
+        }
+    }
+
+    Hello::parse(&buf).ok_or(Error::Handshake(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    const MAC: [u8; 6] = [0x00, 0x1B, 0x2C, 0x3D, 0x4E, 0x5F];
+
+    fn framed(version: u8) -> Vec<u8> {
+        let mut hello = vec![HELLO_MAGIC, version];
+        hello.extend_from_slice(&MAC);
+        hello.push(CRC_8.checksum(&hello));
+        hello
+    }
+
+    /// Sends `segments` from a device and reads the hello on the other end.
+    async fn read(segments: Vec<Vec<u8>>) -> Result<Hello, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut device = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let send = async move {
+            for segment in segments {
+                device.write_all(&segment).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            device
+        };
+        let (hello, _device) = tokio::join!(read_hello(&mut server, Duration::from_secs(2)), send);
+        hello
+    }
+
+    #[test]
+    fn parses_hellos() {
+        let id = DeviceId::new(MAC);
+        assert_eq!(Hello::parse(&MAC), Some(Hello { id, version: None }));
+        assert_eq!(
+            Hello::parse(&framed(1)),
+            Some(Hello {
+                id,
+                version: Some(1)
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_hellos() {
+        let mut bad_crc = framed(1);
+        bad_crc[8] ^= 0xFF;
+        assert_eq!(Hello::parse(&bad_crc), None);
+
+        let mut bad_magic = framed(1);
+        bad_magic[0] = 0x5A;
+        bad_magic[8] = CRC_8.checksum(&bad_magic[..8]);
+        assert_eq!(Hello::parse(&bad_magic), None);
+
+        assert_eq!(Hello::parse(&MAC[..5]), None);
+    }
+
+    #[tokio::test]
+    async fn reads_bare_mac() {
+        let hello = read(vec![MAC[..2].to_vec(), MAC[2..].to_vec()])
+            .await
+            .unwrap();
+        assert_eq!(hello.version, None);
+        assert_eq!(hello.id, DeviceId::new(MAC));
+    }
+
+    #[tokio::test]
+    async fn reads_framed_hello_in_segments() {
+        let hello = framed(2);
+        let hello = read(vec![hello[..7].to_vec(), hello[7..].to_vec()])
+            .await
+            .unwrap();
+        assert_eq!(hello.version, Some(2));
+        assert_eq!(hello.id, DeviceId::new(MAC));
+    }
+
+    #[tokio::test]
+    async fn rejects_framed_hello_with_bad_crc() {
+        let mut hello = framed(1);
+        hello[8] ^= 0xFF;
+        match read(vec![hello.clone()]).await {
+            Err(Error::Handshake(received)) => assert_eq!(received, hello),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,678 @@
+//! Embedded SQLite history of every snapshot field, for sites without an
+//! external database.
+//!
+//! Raw samples are kept for `raw_days`, then only survive as 1-minute and
+//! 1-hour min/max/avg rollups, which are kept for `minute_days` and
+//! `hour_days`. The `/history` endpoint reads any of the three:
+//!
+//! ```text
+//! GET /history?device=aa:bb:cc:dd:ee:ff&field=cell_3&from=2024-05-01T00:00:00Z&resolution=1m
+//! ```
+//!
+//! Inverters are selected with `inverter=<name>` instead of `device`.
+//! `from` and `to` take RFC 3339 times or Unix milliseconds and default to
+//! the last hour; `resolution` is `raw` (default), `1m` or `1h`.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::task::{spawn_blocking, JoinError};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::device::DeviceId;
+use crate::http::{self, Request, Response};
+use crate::sink::{Event, Sink};
+use crate::snapshot::FieldValue;
+
+const MINUTE_MS: i64 = 60_000;
+const HOUR_MS: i64 = 3_600_000;
+const DAY_MS: i64 = 86_400_000;
+
+/// How often rollups and retention run.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most points returned by one query.
+const MAX_POINTS: usize = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        device TEXT NOT NULL,
+        field TEXT NOT NULL,
+        ts INTEGER NOT NULL,
+        value REAL NOT NULL,
+        PRIMARY KEY (device, field, ts)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS samples_ts ON samples (ts);
+
+    CREATE TABLE IF NOT EXISTS rollup_1m (
+        device TEXT NOT NULL,
+        field TEXT NOT NULL,
+        bucket INTEGER NOT NULL,
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        avg REAL NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (device, field, bucket)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS rollup_1m_bucket ON rollup_1m (bucket);
+
+    CREATE TABLE IF NOT EXISTS rollup_1h (
+        device TEXT NOT NULL,
+        field TEXT NOT NULL,
+        bucket INTEGER NOT NULL,
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        avg REAL NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (device, field, bucket)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS rollup_1h_bucket ON rollup_1h (bucket);
+
+    -- End of the range each rollup table is complete up to, in ms.
+    CREATE TABLE IF NOT EXISTS rollup_state (
+        name TEXT PRIMARY KEY,
+        done_until INTEGER NOT NULL
+    );
+";
+
+/// Failure of a database operation.
+#[derive(Debug)]
+pub enum HistoryError {
+    Sqlite(rusqlite::Error),
+    /// The blocking task running the operation panicked.
+    Task(JoinError),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            HistoryError::Task(e) => write!(f, "database task failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HistoryError::Sqlite(e) => Some(e),
+            HistoryError::Task(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError::Sqlite(e)
+    }
+}
+
+/// Stored resolution of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    fn table(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "samples",
+            Resolution::Minute => "rollup_1m",
+            Resolution::Hour => "rollup_1h",
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        })
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Resolution::Raw),
+            "1m" => Ok(Resolution::Minute),
+            "1h" => Ok(Resolution::Hour),
+            _ => Err(format!("unknown resolution `{}`; use raw, 1m or 1h", s)),
+        }
+    }
+}
+
+/// What a series belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Pack(DeviceId),
+    /// An inverter by its configured name.
+    Inverter(String),
+}
+
+impl Source {
+    /// Value of the `device` column.
+    fn key(&self) -> String {
+        match self {
+            Source::Pack(id) => id.to_string(),
+            Source::Inverter(name) => format!("inverter:{}", name),
+        }
+    }
+}
+
+/// Days each table keeps its rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub raw_days: u32,
+    pub minute_days: u32,
+    pub hour_days: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            raw_days: 7,
+            minute_days: 90,
+            hour_days: 3650,
+        }
+    }
+}
+
+/// One stored point. Raw samples have `min == max == avg` and `count == 1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub timestamp: DateTime<Utc>,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: u64,
+}
+
+/// Sink writing snapshots to the database; also answers history queries.
+///
+/// Cloning is cheap; every clone shares the same connection.
+#[derive(Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    retention: Retention,
+}
+
+impl History {
+    /// Opens or creates the database at `path`.
+    pub fn open(path: &Path, retention: Retention) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(History {
+            conn: Arc::new(Mutex::new(conn)),
+            retention,
+        })
+    }
+
+    /// Runs `f` on the connection off the async runtime.
+    ///
+    /// A panic in `f` is returned as `HistoryError::Task`. The connection
+    /// stays usable after one: an open transaction is rolled back when it
+    /// is dropped.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, HistoryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(result) => Ok(result?),
+            Err(e) => Err(HistoryError::Task(e)),
+        }
+    }
+
+    /// Rolls up and prunes the tables every minute until the task is
+    /// dropped.
+    pub async fn maintain(self) {
+        let mut ticker = interval(MAINTENANCE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let retention = self.retention;
+            let now = Utc::now().timestamp_millis();
+            if let Err(e) = self
+                .with_conn(move |conn| maintain(conn, retention, now))
+                .await
+            {
+                eprintln!(
+                    "{}: history maintenance failed. err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Points of one field between `from` and `to`, oldest first.
+    pub async fn query(
+        &self,
+        source: &Source,
+        field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        resolution: Resolution,
+    ) -> Result<Vec<Point>, HistoryError> {
+        let key = source.key();
+        let field = field.to_string();
+        self.with_conn(move |conn| {
+            let sql = match resolution {
+                Resolution::Raw => "SELECT ts, value, value, value, 1 FROM samples
+                     WHERE device = ?1 AND field = ?2 AND ts >= ?3 AND ts < ?4
+                     ORDER BY ts LIMIT ?5"
+                    .to_string(),
+                _ => format!(
+                    "SELECT bucket, min, max, avg, count FROM {}
+                     WHERE device = ?1 AND field = ?2 AND bucket >= ?3 AND bucket < ?4
+                     ORDER BY bucket LIMIT ?5",
+                    resolution.table()
+                ),
+            };
+            let mut statement = conn.prepare_cached(&sql)?;
+            let rows = statement.query_map(
+                params![
+                    key,
+                    field,
+                    from.timestamp_millis(),
+                    to.timestamp_millis(),
+                    MAX_POINTS as i64
+                ],
+                |row| {
+                    let ms: i64 = row.get(0)?;
+                    let timestamp = Utc
+                        .timestamp_millis_opt(ms)
+                        .single()
+                        .ok_or(rusqlite::Error::IntegralValueOutOfRange(0, ms))?;
+                    Ok(Point {
+                        timestamp,
+                        min: row.get(1)?,
+                        max: row.get(2)?,
+                        avg: row.get(3)?,
+                        count: row.get::<_, i64>(4)? as u64,
+                    })
+                },
+            )?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Serves `GET /history` on `listener` until the task is dropped.
+    pub async fn serve(self, listener: TcpListener) {
+        http::serve(listener, "history", &["GET"], move |request: Request| {
+            let history = self.clone();
+            async move {
+                match request.url.path() {
+                    "/history" => history.respond(&request).await,
+                    _ => Response::error("404 Not Found", "not found"),
+                }
+            }
+        })
+        .await
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        let bad = |msg: String| Response::error("400 Bad Request", &msg);
+
+        let source = match (request.query("device"), request.query("inverter")) {
+            (Some(device), None) => match device.parse() {
+                Ok(id) => Source::Pack(id),
+                Err(e) => return bad(e),
+            },
+            (None, Some(name)) => Source::Inverter(name),
+            _ => return bad("give either device or inverter".to_string()),
+        };
+        let field = match request.query("field") {
+            Some(field) => field,
+            None => return bad("field is missing".to_string()),
+        };
+        let now = Utc::now();
+        let to = match request.query("to").map(|t| parse_time(&t)) {
+            Some(Ok(to)) => to,
+            Some(Err(e)) => return bad(e),
+            None => now,
+        };
+        let from = match request.query("from").map(|t| parse_time(&t)) {
+            Some(Ok(from)) => from,
+            Some(Err(e)) => return bad(e),
+            None => to - chrono::Duration::hours(1),
+        };
+        let resolution = match request.query("resolution").map(|r| r.parse()) {
+            Some(Ok(resolution)) => resolution,
+            Some(Err(e)) => return bad(e),
+            None => Resolution::Raw,
+        };
+
+        let points = match self.query(&source, &field, from, to, resolution).await {
+            Ok(points) => points,
+            Err(e) => {
+                eprintln!(
+                    "{}: history query failed. err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    e
+                );
+                return Response::error("500 Internal Server Error", "query failed");
+            }
+        };
+
+        let points: Vec<Value> = points
+            .iter()
+            .map(|p| {
+                let t = p.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+                match resolution {
+                    Resolution::Raw => json!({ "t": t, "value": p.avg }),
+                    _ => json!({
+                        "t": t,
+                        "min": p.min,
+                        "max": p.max,
+                        "avg": p.avg,
+                        "count": p.count,
+                    }),
+                }
+            })
+            .collect();
+        let (kind, name) = match &source {
+            Source::Pack(id) => ("device", id.to_string()),
+            Source::Inverter(name) => ("inverter", name.clone()),
+        };
+        let body = json!({
+            kind: name,
+            "field": field,
+            "resolution": resolution.to_string(),
+            "from": from.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "to": to.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "truncated": points.len() == MAX_POINTS,
+            "points": points,
+        });
+        Response::new("200 OK", "application/json", body.to_string())
+    }
+}
+
+/// RFC 3339 time or Unix milliseconds.
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(ms) = s.parse::<i64>() {
+        return Utc
+            .timestamp_millis_opt(ms)
+            .single()
+            .ok_or_else(|| format!("`{}` is out of range", s));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| format!("`{}` is not an RFC 3339 time or Unix milliseconds", s))
+}
+
+/// Numeric value of every field of a snapshot; flags are stored as 0 or 1.
+fn values<'a>(fields: impl IntoIterator<Item = (&'a str, FieldValue)>) -> Vec<(String, f64)> {
+    fields
+        .into_iter()
+        .map(|(field, value)| {
+            let value = match value {
+                FieldValue::Float(v) => f64::from(v),
+                FieldValue::Bool(v) => f64::from(u8::from(v)),
+            };
+            (field.to_string(), value)
+        })
+        .collect()
+}
+
+fn insert(
+    conn: &mut Connection,
+    key: &str,
+    timestamp: DateTime<Utc>,
+    values: &[(String, f64)],
+) -> rusqlite::Result<()> {
+    let ts = timestamp.timestamp_millis();
+
+    let tx = conn.transaction()?;
+    {
+        let mut statement = tx.prepare_cached(
+            "INSERT OR REPLACE INTO samples (device, field, ts, value) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (field, value) in values {
+            statement.execute(params![key, field, ts, value])?;
+        }
+    }
+    tx.commit()
+}
+
+fn done_until(conn: &Connection, name: &str) -> rusqlite::Result<i64> {
+    conn.query_row(
+        "SELECT coalesce(max(done_until), 0) FROM rollup_state WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    )
+}
+
+/// Rolls complete buckets up and deletes rows past their retention.
+///
+/// The last bucket before the previous run is recomputed, so samples that
+/// arrived slightly late are still counted.
+fn maintain(conn: &mut Connection, retention: Retention, now: i64) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+
+    let minute_to = now - now.rem_euclid(MINUTE_MS);
+    let minute_from = (done_until(&tx, "rollup_1m")? - MINUTE_MS).max(0);
+    tx.execute(
+        "INSERT OR REPLACE INTO rollup_1m (device, field, bucket, min, max, avg, count)
+         SELECT device, field, ts - ts % ?3, min(value), max(value), avg(value), count(*)
+         FROM samples WHERE ts >= ?1 AND ts < ?2
+         GROUP BY device, field, ts - ts % ?3",
+        params![minute_from, minute_to, MINUTE_MS],
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO rollup_state (name, done_until) VALUES ('rollup_1m', ?1)",
+        [minute_to],
+    )?;
+
+    let hour_to = now - now.rem_euclid(HOUR_MS);
+    let hour_from = (done_until(&tx, "rollup_1h")? - HOUR_MS).max(0);
+    tx.execute(
+        "INSERT OR REPLACE INTO rollup_1h (device, field, bucket, min, max, avg, count)
+         SELECT device, field, bucket - bucket % ?3, min(min), max(max),
+                sum(avg * count) / sum(count), sum(count)
+         FROM rollup_1m WHERE bucket >= ?1 AND bucket < ?2
+         GROUP BY device, field, bucket - bucket % ?3",
+        params![hour_from, hour_to, HOUR_MS],
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO rollup_state (name, done_until) VALUES ('rollup_1h', ?1)",
+        [hour_to],
+    )?;
+
+    for (table, column, days) in [
+        ("samples", "ts", retention.raw_days),
+        ("rollup_1m", "bucket", retention.minute_days),
+        ("rollup_1h", "bucket", retention.hour_days),
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE {} < ?1", table, column),
+            [now - i64::from(days) * DAY_MS],
+        )?;
+    }
+
+    tx.commit()
+}
+
+impl Sink for History {
+    async fn handle(&mut self, event: &Event) {
+        let (source, timestamp, values) = match event {
+            Event::Snapshot(snapshot) => {
+                let fields = snapshot.fields();
+                let values = values(fields.iter().map(|(f, v)| (f.as_str(), *v)));
+                (Source::Pack(snapshot.device_id), snapshot.timestamp, values)
+            }
+            Event::Inverter(snapshot) => (
+                Source::Inverter(snapshot.name.clone()),
+                snapshot.timestamp,
+                values(snapshot.reading.fields()),
+            ),
+            Event::SiteFlow(flow) => (
+                Source::Inverter(flow.inverter.clone()),
+                flow.timestamp,
+                values(flow.fields()),
+            ),
+            _ => return,
+        };
+        if values.is_empty() {
+            return;
+        }
+
+        let key = source.key();
+        let result = self
+            .with_conn(move |conn| insert(conn, &key, timestamp, &values))
+            .await;
+        if let Err(e) = result {
+            eprintln!(
+                "{}: history insert for {} failed. err = {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                source.key(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of an hour.
+    const BASE: i64 = 1_700_000_000_000 - 1_700_000_000_000 % HOUR_MS;
+
+    fn memory(retention: Retention) -> History {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        History {
+            conn: Arc::new(Mutex::new(conn)),
+            retention,
+        }
+    }
+
+    fn pack() -> Source {
+        Source::Pack("00:1b:2c:3d:4e:5f".parse().unwrap())
+    }
+
+    fn time(ms: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(ms).unwrap()
+    }
+
+    /// Stores `value` of the pack's `cell_1` at `BASE + secs`.
+    fn sample(history: &History, secs: i64, value: f64) {
+        let values = [("cell_1".to_string(), value)];
+        let mut conn = history.conn.lock().unwrap();
+        insert(&mut conn, &pack().key(), time(BASE + secs * 1000), &values).unwrap();
+    }
+
+    fn run_maintenance(history: &History, now: i64) {
+        let mut conn = history.conn.lock().unwrap();
+        maintain(&mut conn, history.retention, now).unwrap();
+    }
+
+    fn count(history: &History, table: &str) -> i64 {
+        let conn = history.conn.lock().unwrap();
+        conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    async fn points(history: &History, resolution: Resolution) -> Vec<(i64, f64, f64, f64, u64)> {
+        history
+            .query(
+                &pack(),
+                "cell_1",
+                time(0),
+                time(BASE + 2 * HOUR_MS),
+                resolution,
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|p| (p.timestamp.timestamp_millis(), p.min, p.max, p.avg, p.count))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rolls_up_complete_buckets() {
+        let history = memory(Retention::default());
+        sample(&history, 1, 1.0);
+        sample(&history, 30, 3.0);
+        sample(&history, 61, 5.0);
+        // Still in its minute, so not rolled up yet.
+        sample(&history, 125, 7.0);
+
+        run_maintenance(&history, BASE + 121_000);
+        assert_eq!(
+            points(&history, Resolution::Raw).await[0],
+            (BASE + 1000, 1.0, 1.0, 1.0, 1)
+        );
+        assert_eq!(
+            points(&history, Resolution::Minute).await,
+            [
+                (BASE, 1.0, 3.0, 2.0, 2),
+                (BASE + MINUTE_MS, 5.0, 5.0, 5.0, 1)
+            ]
+        );
+        assert!(points(&history, Resolution::Hour).await.is_empty());
+
+        run_maintenance(&history, BASE + HOUR_MS + 1);
+        assert_eq!(points(&history, Resolution::Minute).await.len(), 3);
+        assert_eq!(
+            points(&history, Resolution::Hour).await,
+            [(BASE, 1.0, 7.0, 4.0, 4)]
+        );
+    }
+
+    #[test]
+    fn prunes_rows_past_retention() {
+        let history = memory(Retention {
+            raw_days: 1,
+            minute_days: 2,
+            hour_days: 3,
+        });
+        sample(&history, 1, 1.0);
+        run_maintenance(&history, BASE + HOUR_MS);
+
+        let counts = || ["samples", "rollup_1m", "rollup_1h"].map(|table| count(&history, table));
+        assert_eq!(counts(), [1, 1, 1]);
+        run_maintenance(&history, BASE + DAY_MS + HOUR_MS);
+        assert_eq!(counts(), [0, 1, 1]);
+        run_maintenance(&history, BASE + 2 * DAY_MS + HOUR_MS);
+        assert_eq!(counts(), [0, 0, 1]);
+        run_maintenance(&history, BASE + 3 * DAY_MS + HOUR_MS);
+        assert_eq!(counts(), [0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn reports_a_panicking_task() {
+        let history = memory(Retention::default());
+        let result: Result<(), _> = history.with_conn(|_| panic!("boom")).await;
+        assert!(matches!(result, Err(HistoryError::Task(_))));
+
+        // The connection is still usable.
+        assert!(points(&history, Resolution::Raw).await.is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! Minimal HTTP/1.1 server for the gateway's own endpoints: one request per
+//! connection, no bodies. Parameters are always passed in the query.
+
+use std::future::Future;
+use std::io;
+
+use chrono::prelude::*;
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+/// Longest request head that is read.
+const MAX_REQUEST: usize = 8192;
+
+/// Time a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A parsed request line and its headers.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path and query of the request target.
+    pub url: Url,
+    /// `(name, value)` of every header, names in lower case.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the first header called `name`, in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Decoded value of query parameter `name`.
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.url
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    /// Plain text error response.
+    pub fn error(status: &'static str, message: &str) -> Self {
+        Response::new(
+            status,
+            "text/plain; charset=utf-8",
+            format!("{}\n", message),
+        )
+    }
+}
+
+/// Accepts connections on `listener` and answers each request using one of
+/// `methods` with `handler` until the task is dropped. `name` labels log
+/// messages.
+pub async fn serve<F, Fut>(
+    listener: TcpListener,
+    name: &'static str,
+    methods: &'static [&'static str],
+    handler: F,
+) where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!(
+                    "{}: {} listener accept failed. err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    name,
+                    e
+                );
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, methods, handler).await {
+                eprintln!(
+                    "{}: {} request from {} failed. err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    name,
+                    peer,
+                    e
+                );
+            }
+        });
+    }
+}
+
+async fn respond<F, Fut>(mut socket: TcpStream, methods: &[&str], handler: F) -> io::Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            let response =
+                Response::error("431 Request Header Fields Too Large", "request too large");
+            return write_response(&mut socket, &response).await;
+        }
+        let n = match timeout(REQUEST_TIMEOUT, socket.read(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Ok(()),
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let request = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) if target.starts_with('/') => {
+            match Url::parse(&format!("http://localhost{}", target)) {
+                Ok(url) => Some(Request {
+                    method: method.to_string(),
+                    url,
+                    headers,
+                }),
+                Err(_) => None,
+            }
+        }
+        _ => None,
+    };
+
+    let response = match request {
+        Some(request) if methods.contains(&request.method.as_str()) => handler(request).await,
+        Some(_) => Response::error(
+            "405 Method Not Allowed",
+            &format!("only {} is supported", methods.join(" and ")),
+        ),
+        None => Response::error("400 Bad Request", "malformed request line"),
+    };
+    write_response(&mut socket, &response).await
+}
+
+async fn write_response(socket: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown().await
+}
@@ -0,0 +1,1035 @@
+//! InfluxDB v2 output: line protocol formatting and a batching writer that
+//! all sessions share.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use chrono::prelude::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, Duration, Instant, MissedTickBehavior};
+
+use crate::power::{InverterMode, InverterReading, InverterSnapshot};
+use crate::sink::{Event, Sink};
+use crate::site::EnergyFlow;
+use crate::snapshot::{FieldValue, PackSnapshot};
+use crate::spool::Spool;
+
+/// When the writer flushes and how it encodes the request body.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Flush once this many lines are buffered.
+    pub max_lines: usize,
+    /// Flush at least this often while lines are buffered.
+    pub flush_interval: Duration,
+    /// Lines that may wait for the writer before the sink blocks.
+    pub queue_capacity: usize,
+    pub gzip: bool,
+    /// Attempts after the first one for retryable failures.
+    pub max_retries: u32,
+    /// First retry delay; doubled on every further attempt.
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_lines: 5000,
+            flush_interval: Duration::from_secs(1),
+            queue_capacity: 100_000,
+            gzip: true,
+            max_retries: 5,
+            retry_base: Duration::from_millis(500),
+            retry_max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Wait before retry `attempt`, counted from 0: the delay the server
+    /// asked for, or the exponential backoff scaled by `jitter`, but never
+    /// more than `retry_max`.
+    fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>, jitter: f64) -> Duration {
+        retry_after
+            .unwrap_or_else(|| {
+                self.retry_base
+                    .saturating_mul(1 << attempt.min(16))
+                    .mul_f64(jitter)
+            })
+            .min(self.retry_max)
+    }
+}
+
+/// Escapes commas, equals signs and spaces in a line protocol tag value.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Line protocol measurement and tag set of a snapshot: the device id plus
+/// the registry metadata, if any.
+fn series_key(snapshot: &PackSnapshot) -> String {
+    let mut key = format!("powermax_b5120,device={}", snapshot.device_id);
+    if let Some(info) = &snapshot.device {
+        key.push_str(&format!(
+            ",name={},site={}",
+            escape_tag(&info.name),
+            escape_tag(&info.site)
+        ));
+        if let Some(position) = &info.position {
+            key.push_str(&format!(",position={}", escape_tag(position)));
+        }
+    }
+    key
+}
+
+/// One line carrying every field of the snapshot, stamped in milliseconds
+/// with the time the round's responses were received.
+fn line(snapshot: &PackSnapshot, fields: &[(String, FieldValue)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(field, value)| format!("{}={}", field, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{} {} {}",
+        series_key(snapshot),
+        fields,
+        snapshot.timestamp.timestamp_millis()
+    )
+}
+
+/// Series key of an inverter in `measurement`.
+fn inverter_key(measurement: &str, name: &str, site: Option<&str>) -> String {
+    let mut key = format!("{},inverter={}", measurement, escape_tag(name));
+    if let Some(site) = site {
+        key.push_str(&format!(",site={}", escape_tag(site)));
+    }
+    key
+}
+
+/// One line with every field of an inverter reading. Status readings go
+/// to `powermax_inverter`, the others to `powermax_inverter_<kind>`.
+fn inverter_line(snapshot: &InverterSnapshot) -> String {
+    let measurement = match &snapshot.reading {
+        InverterReading::Status(_) => "powermax_inverter".to_string(),
+        reading => format!("powermax_inverter_{}", reading.kind()),
+    };
+    let fields = snapshot
+        .reading
+        .fields()
+        .iter()
+        .map(|(field, value)| format!("{}={}", field, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{} {} {}",
+        inverter_key(&measurement, &snapshot.name, snapshot.site.as_deref()),
+        fields,
+        snapshot.timestamp.timestamp_millis()
+    )
+}
+
+/// One `powermax_site_flow` line with every figure of the flow.
+fn flow_line(flow: &EnergyFlow) -> String {
+    let fields = flow
+        .fields()
+        .iter()
+        .map(|(field, value)| format!("{}={}", field, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{} {} {}",
+        inverter_key("powermax_site_flow", &flow.inverter, flow.site.as_deref()),
+        fields,
+        flow.timestamp.timestamp_millis()
+    )
+}
+
+/// One `powermax_inverter_mode` line for a mode transition.
+fn mode_line(
+    name: &str,
+    site: Option<&str>,
+    from: Option<InverterMode>,
+    to: InverterMode,
+    timestamp: DateTime<Utc>,
+) -> String {
+    let mut fields = format!("mode=\"{}\"", to.as_str());
+    if let Some(from) = from {
+        fields.push_str(&format!(",previous=\"{}\"", from.as_str()));
+    }
+    format!(
+        "{} {} {}",
+        inverter_key("powermax_inverter_mode", name, site),
+        fields,
+        timestamp.timestamp_millis()
+    )
+}
+
+/// Sink that formats snapshots as line protocol and queues them for the
+/// batching writer.
+#[derive(Clone)]
+pub struct InfluxDb {
+    lines: mpsc::Sender<String>,
+}
+
+impl InfluxDb {
+    /// Spawns the writer task. `url` is the full write endpoint including
+    /// org, bucket and precision, `token` the value of the `Authorization`
+    /// header. Batches that cannot be written go to `spool`, if given.
+    pub fn spawn(url: &str, token: &str, batch: BatchConfig, spool: Option<Spool>) -> Self {
+        let (tx, rx) = mpsc::channel(batch.queue_capacity);
+        let writer = Writer {
+            client: Client::new(),
+            url: url.to_string(),
+            token: token.to_string(),
+            batch,
+            last_permanent: None,
+            spool,
+            draining: None,
+        };
+        tokio::spawn(writer.run(rx));
+
+        InfluxDb { lines: tx }
+    }
+}
+
+impl Sink for InfluxDb {
+    /// Waits for room in the writer's channel, so a backlog builds up in
+    /// this sink's router queue rather than being lost here.
+    async fn handle(&mut self, event: &Event) {
+        let line = match event {
+            Event::Snapshot(snapshot) => {
+                let fields = snapshot.fields();
+                if fields.is_empty() {
+                    return;
+                }
+                line(snapshot, &fields)
+            }
+            Event::Inverter(snapshot) => inverter_line(snapshot),
+            Event::InverterMode {
+                name,
+                site,
+                from,
+                to,
+                timestamp,
+            } => mode_line(name, site.as_deref(), *from, *to, *timestamp),
+            Event::SiteFlow(flow) => flow_line(flow),
+            _ => return,
+        };
+
+        if self.lines.send(line).await.is_err() {
+            eprintln!(
+                "{}: influxDB writer stopped, dropping line",
+                Local::now().format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+    }
+}
+
+/// Why a write failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum WriteError {
+    /// Worth trying again: connection errors, 5xx, 429.
+    Retryable {
+        reason: String,
+        /// Delay requested by the server with `Retry-After`.
+        retry_after: Option<Duration>,
+    },
+    /// Retrying cannot help: bad token, unknown bucket, malformed lines.
+    Permanent { status: u16, message: String },
+}
+
+impl WriteError {
+    /// Maps a failed response to an error: 5xx and 429 are worth retrying,
+    /// every other status, including a bad token or bucket, is not.
+    fn from_response(status: StatusCode, retry_after: Option<Duration>, body: &str) -> Self {
+        let message = error_message(body);
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            let reason = if message.is_empty() {
+                status.to_string()
+            } else {
+                format!("{} ({})", status, message)
+            };
+            WriteError::Retryable {
+                reason,
+                retry_after,
+            }
+        } else {
+            WriteError::Permanent {
+                status: status.as_u16(),
+                message,
+            }
+        }
+    }
+}
+
+/// Delay requested by a `Retry-After` header, in seconds or as an HTTP
+/// date.
+fn retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+/// Error body returned by InfluxDB v2.
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: Option<String>,
+    message: Option<String>,
+}
+
+/// `code: message` from an InfluxDB error body, or the raw body.
+fn error_message(body: &str) -> String {
+    match serde_json::from_str::<ErrorBody>(body) {
+        Ok(ErrorBody {
+            code: Some(code),
+            message: Some(message),
+        }) => format!("{}: {}", code, message),
+        Ok(ErrorBody {
+            message: Some(message),
+            ..
+        }) => message,
+        _ => body.trim().to_string(),
+    }
+}
+
+/// Uniform random factor in `[0.5, 1.5)`.
+fn jitter() -> f64 {
+    let mut bytes = [0; 4];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return 1.0;
+    }
+    0.5 + u32::from_le_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+/// How long draining the disk buffer pauses after a failed attempt.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Buffered batches written per flush interval, so that the writer keeps
+/// taking new lines while it drains.
+const DRAIN_BATCHES: usize = 8;
+
+/// How often one flush may halve a rejected batch, bounding the requests
+/// spent on malformed lines.
+const MAX_SPLITS: usize = 32;
+
+/// Collects lines from every session and posts them in batches over one
+/// pooled HTTP client.
+struct Writer {
+    client: Client,
+    url: String,
+    token: String,
+    batch: BatchConfig,
+    /// The last permanent error, reported once until a write succeeds or
+    /// the error changes.
+    last_permanent: Option<WriteError>,
+    /// On-disk buffer for batches that could not be written.
+    spool: Option<Spool>,
+    /// Batches of the spool segment being drained that are still to be
+    /// written, with the segment's sequence number.
+    draining: Option<(u64, VecDeque<Vec<u8>>)>,
+}
+
+impl Writer {
+    async fn run(mut self, mut rx: mpsc::Receiver<String>) {
+        let mut lines: Vec<String> = Vec::with_capacity(self.batch.max_lines);
+        let mut ticker = interval(self.batch.flush_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut next_drain = Instant::now();
+
+        loop {
+            tokio::select! {
+                line = rx.recv() => match line {
+                    Some(line) => {
+                        lines.push(line);
+                        if lines.len() >= self.batch.max_lines {
+                            self.flush(&mut lines).await;
+                        }
+                    }
+                    // every sender is gone
+                    None => {
+                        self.flush(&mut lines).await;
+                        return;
+                    }
+                },
+                _ = ticker.tick() => {
+                    self.flush(&mut lines).await;
+                    if self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+                        && Instant::now() >= next_drain
+                        && !self.drain().await
+                    {
+                        next_drain = Instant::now() + DRAIN_INTERVAL;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn flush(&mut self, lines: &mut Vec<String>) {
+        if lines.is_empty() {
+            return;
+        }
+
+        let n = lines.len();
+        let batch = std::mem::replace(lines, Vec::with_capacity(self.batch.max_lines));
+
+        // Keep the order: while older data waits on disk, new data queues
+        // behind it.
+        if self.spool.as_ref().is_some_and(|spool| !spool.is_empty()) {
+            self.buffer(batch.join("\n").as_bytes(), n);
+            return;
+        }
+
+        // InfluxDB writes nothing of a batch with a malformed line, so a
+        // rejected batch is halved until the bad lines stand alone, up to
+        // MAX_SPLITS times; chunks still rejected after that are dropped.
+        let mut chunks = vec![&batch[..]];
+        let mut splits = 0;
+        let mut written = 0;
+        let mut rejected = 0;
+        while let Some(chunk) = chunks.pop() {
+            let err = match self.write(chunk.join("\n").as_bytes(), chunk.len()).await {
+                Ok(()) => {
+                    written += chunk.len();
+                    continue;
+                }
+                Err(WriteError::Permanent { status: 400, .. })
+                    if chunk.len() > 1 && splits < MAX_SPLITS =>
+                {
+                    splits += 1;
+                    let (first, second) = chunk.split_at(chunk.len() / 2);
+                    chunks.push(second);
+                    chunks.push(first);
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            if let WriteError::Permanent { status: 400, .. } = err {
+                self.report_permanent(&err);
+                rejected += chunk.len();
+                continue;
+            }
+
+            // Any other failure would repeat for the chunks still to go.
+            let rest: Vec<&str> = chunk
+                .iter()
+                .chain(chunks.drain(..).rev().flatten())
+                .map(String::as_str)
+                .collect();
+            match err {
+                WriteError::Retryable { .. } if self.spool.is_some() => {
+                    self.buffer(rest.join("\n").as_bytes(), rest.len())
+                }
+                WriteError::Retryable { .. } => eprintln!(
+                    "{}: dropped {} lines",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    rest.len()
+                ),
+                WriteError::Permanent { .. } => self.report_permanent(&err),
+            }
+        }
+
+        if written > 0 {
+            println!(
+                "{} write {} lines to influxDB",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                written
+            );
+        }
+        if rejected > 0 {
+            eprintln!(
+                "{}: influxDB rejected {} of {} lines as malformed, dropping them",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                rejected,
+                n
+            );
+        }
+    }
+
+    /// Posts one batch, retrying retryable failures with backoff.
+    async fn write(&mut self, body: &[u8], n: usize) -> Result<(), WriteError> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.post(body).await {
+                Ok(()) => {
+                    if self.last_permanent.take().is_some() {
+                        println!(
+                            "{} influxDB writes succeed again",
+                            Local::now().format("%Y-%m-%d %H:%M:%S")
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(err) => err,
+            };
+
+            match &err {
+                WriteError::Permanent { .. } => return Err(err),
+                WriteError::Retryable { reason, .. } if attempt >= self.batch.max_retries => {
+                    eprintln!(
+                        "{}: write {} lines to influxDB failed after {} attempts. err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        n,
+                        attempt + 1,
+                        reason
+                    );
+                    return Err(err);
+                }
+                WriteError::Retryable {
+                    reason,
+                    retry_after,
+                } => {
+                    let delay = self.batch.retry_delay(attempt, *retry_after, jitter());
+                    eprintln!(
+                        "{}: write {} lines to influxDB failed, retrying in {:?}. err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        n,
+                        delay,
+                        reason
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Logs a permanent error unless it is the one already reported.
+    fn report_permanent(&mut self, err: &WriteError) {
+        if let WriteError::Permanent { status, message } = err {
+            if self.last_permanent.as_ref() != Some(err) {
+                eprintln!(
+                    "{}: influxDB rejected writes with {} ({}); data is dropped until this is fixed",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    status,
+                    message
+                );
+                self.last_permanent = Some(err.clone());
+            }
+        }
+    }
+
+    /// Appends a batch to the on-disk buffer.
+    fn buffer(&mut self, body: &[u8], n: usize) {
+        let spool = match self.spool.as_mut() {
+            Some(spool) => spool,
+            None => return,
+        };
+
+        let was_empty = spool.is_empty();
+        match spool.append(body) {
+            Ok(evicted) => {
+                if was_empty {
+                    println!(
+                        "{} influxDB unavailable, buffering writes on disk",
+                        Local::now().format("%Y-%m-%d %H:%M:%S")
+                    );
+                }
+                if evicted > 0 {
+                    eprintln!(
+                        "{}: disk buffer full, evicted {} oldest segments",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        evicted
+                    );
+                }
+            }
+            Err(e) => eprintln!(
+                "{}: cannot buffer {} lines on disk, dropping them. err = {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                n,
+                e
+            ),
+        }
+    }
+
+    /// Writes up to `DRAIN_BATCHES` buffered batches, oldest first. Returns
+    /// false if a write failed transiently or the buffer cannot be read, so
+    /// that draining pauses.
+    ///
+    /// A segment is removed only once all its batches are written or
+    /// rejected, so after a restart part-way some batches are sent again.
+    /// That is harmless because every line carries its own timestamp.
+    /// Batches the store rejects for good are dropped, so they cannot hold
+    /// up the rest of the buffer.
+    async fn drain(&mut self) -> bool {
+        let mut written = 0;
+        let mut rejected = 0;
+        let mut ok = true;
+        for _ in 0..DRAIN_BATCHES {
+            let batch = match self.next_buffered() {
+                Ok(Some(batch)) => batch,
+                Ok(None) => {
+                    println!(
+                        "{} disk buffer drained to influxDB",
+                        Local::now().format("%Y-%m-%d %H:%M:%S")
+                    );
+                    break;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{}: cannot read disk buffer. err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        e
+                    );
+                    ok = false;
+                    break;
+                }
+            };
+
+            match self.post(&batch).await {
+                Ok(()) => {
+                    self.last_permanent = None;
+                    written += 1;
+                }
+                Err(WriteError::Retryable { .. }) => {
+                    if let Some((_, batches)) = self.draining.as_mut() {
+                        batches.push_front(batch);
+                    }
+                    ok = false;
+                    break;
+                }
+                Err(e) => {
+                    self.report_permanent(&e);
+                    rejected += 1;
+                }
+            }
+        }
+
+        if rejected > 0 {
+            eprintln!(
+                "{}: influxDB rejected {} of {} buffered batches, dropping them",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                rejected,
+                written + rejected
+            );
+        }
+        ok
+    }
+
+    /// Takes the oldest buffered batch, reading the next segment once the
+    /// one being drained is used up or was evicted meanwhile.
+    fn next_buffered(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let spool = match self.spool.as_mut() {
+            Some(spool) => spool,
+            None => return Ok(None),
+        };
+
+        loop {
+            if let Some((seq, batches)) = self.draining.as_mut() {
+                if spool.oldest() == Some(*seq) {
+                    if let Some(batch) = batches.pop_front() {
+                        return Ok(Some(batch));
+                    }
+                    spool.remove(*seq)?;
+                }
+                self.draining = None;
+            }
+
+            match spool.read_oldest()? {
+                Some((seq, batches)) => self.draining = Some((seq, batches.into())),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(), WriteError> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Authorization", &self.token);
+        request = if self.batch.gzip {
+            match gzip(body) {
+                Ok(compressed) => request.header("Content-Encoding", "gzip").body(compressed),
+                Err(_) => request.body(body.to_vec()),
+            }
+        } else {
+            request.body(body.to_vec())
+        };
+
+        let response = request.send().await.map_err(|e| WriteError::Retryable {
+            reason: e.to_string(),
+            retry_after: None,
+        })?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| retry_after(v, Utc::now()));
+        let body = response.text().await.unwrap_or_default();
+        Err(WriteError::from_response(status, retry_after, &body))
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::device::DeviceId;
+    use crate::register::{Reading, Register};
+    use crate::registry::DeviceInfo;
+
+    fn snapshot(device: Option<DeviceInfo>) -> PackSnapshot {
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let timestamp = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        let mut snapshot = PackSnapshot::new(id, timestamp);
+        snapshot.device = device.map(Arc::new);
+        snapshot
+    }
+
+    fn device(name: &str, site: &str, position: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            name: name.to_string(),
+            site: site.to_string(),
+            position: position.map(str::to_string),
+            cell_count: None,
+            design_capacity_mah: None,
+            inverter: None,
+            driver: None,
+            min_command_gap_ms: None,
+        }
+    }
+
+    /// Serves the write endpoint on a local port, answering each request
+    /// with the status `status` picks for its body. Returns the URL and
+    /// the bodies received.
+    async fn serve(status: fn(&str) -> u16) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v2/write", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    loop {
+                        let mut length = 0;
+                        loop {
+                            let mut header = String::new();
+                            if socket.read_line(&mut header).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if header == "\r\n" {
+                                break;
+                            }
+                            if let Some((name, value)) = header.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; length];
+                        socket.read_exact(&mut body).await.unwrap();
+                        let body = String::from_utf8(body).unwrap();
+                        let code = status(&body);
+                        received.lock().unwrap().push(body);
+
+                        let error = if code == 400 {
+                            r#"{"code":"invalid","message":"unable to parse line"}"#
+                        } else {
+                            ""
+                        };
+                        let response = format!(
+                            "HTTP/1.1 {} Status\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                            code,
+                            error.len(),
+                            error
+                        );
+                        socket
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        (url, bodies)
+    }
+
+    fn writer(url: String, spool: Option<Spool>) -> Writer {
+        Writer {
+            client: Client::new(),
+            url,
+            token: "Token secret".to_string(),
+            batch: BatchConfig {
+                gzip: false,
+                max_retries: 0,
+                ..BatchConfig::default()
+            },
+            last_permanent: None,
+            spool,
+            draining: None,
+        }
+    }
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn escapes_tag_values() {
+        assert_eq!(escape_tag("north-depot"), "north-depot");
+        assert_eq!(escape_tag("Rack 2, shelf=3"), r"Rack\ 2\,\ shelf\=3");
+    }
+
+    #[test]
+    fn keys_series_by_device_and_registry_tags() {
+        assert_eq!(
+            series_key(&snapshot(None)),
+            "powermax_b5120,device=00:1b:2c:3d:4e:5f"
+        );
+        assert_eq!(
+            series_key(&snapshot(Some(device("Pack 1", "north", Some("A3"))))),
+            r"powermax_b5120,device=00:1b:2c:3d:4e:5f,name=Pack\ 1,site=north,position=A3"
+        );
+    }
+
+    #[test]
+    fn writes_every_field_on_one_line() {
+        let mut snapshot = snapshot(None);
+        for (register, raw) in [
+            (Register::CellVoltage(1), 3301),
+            (Register::Current, -1500),
+            (Register::Rsoc, 87),
+        ] {
+            snapshot.apply(&Reading { register, raw });
+        }
+
+        assert_eq!(
+            line(&snapshot, &snapshot.fields()),
+            "powermax_b5120,device=00:1b:2c:3d:4e:5f cell_1=3301,current=-1500,rsoc=87 1700000000123"
+        );
+    }
+
+    #[test]
+    fn escapes_registry_tags_and_stamps_the_line() {
+        let mut snapshot = snapshot(Some(device(
+            "Pack 1, left",
+            "north=depot",
+            Some("rack 2,a"),
+        )));
+        snapshot.apply(&Reading {
+            register: Register::Rsoc,
+            raw: 87,
+        });
+
+        assert_eq!(
+            line(&snapshot, &snapshot.fields()),
+            r"powermax_b5120,device=00:1b:2c:3d:4e:5f,name=Pack\ 1\,\ left,site=north\=depot,position=rack\ 2\,a rsoc=87 1700000000123"
+        );
+    }
+
+    #[test]
+    fn escapes_inverter_tags() {
+        let timestamp = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        assert_eq!(
+            mode_line(
+                "Inverter 1",
+                Some("north,depot"),
+                Some(InverterMode::Line),
+                InverterMode::Battery,
+                timestamp
+            ),
+            r#"powermax_inverter_mode,inverter=Inverter\ 1,site=north\,depot mode="battery",previous="line" 1700000000123"#
+        );
+        assert_eq!(
+            inverter_key("powermax_inverter", "a=b", None),
+            r"powermax_inverter,inverter=a\=b"
+        );
+    }
+
+    #[tokio::test]
+    async fn isolates_malformed_lines() {
+        let (url, bodies) = serve(|body| if body.contains("bad") { 400 } else { 204 }).await;
+        let mut writer = writer(url, None);
+        let mut batch = lines(&["a v=1 1", "b v=2 2", "bad", "c v=3 3", "d v=4 4"]);
+
+        writer.flush(&mut batch).await;
+
+        assert!(batch.is_empty());
+        let bodies = bodies.lock().unwrap();
+        let written: Vec<&str> = bodies
+            .iter()
+            .filter(|body| !body.contains("bad"))
+            .flat_map(|body| body.lines())
+            .collect();
+        assert_eq!(written, ["a v=1 1", "b v=2 2", "c v=3 3", "d v=4 4"]);
+        assert_eq!(bodies.iter().filter(|body| *body == "bad").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_other_failure() {
+        let (url, bodies) = serve(|_| 503).await;
+        let mut writer = writer(url, None);
+
+        writer.flush(&mut lines(&["a v=1 1", "b v=2 2"])).await;
+
+        assert_eq!(*bodies.lock().unwrap(), ["a v=1 1\nb v=2 2"]);
+    }
+
+    #[test]
+    fn maps_statuses_to_errors() {
+        let body = r#"{"code":"unauthorized","message":"read:authorizations is unauthorized"}"#;
+        for status in [400, 401, 403, 404, 413, 422] {
+            let status = StatusCode::from_u16(status).unwrap();
+            assert_eq!(
+                WriteError::from_response(status, None, body),
+                WriteError::Permanent {
+                    status: status.as_u16(),
+                    message: "unauthorized: read:authorizations is unauthorized".to_string(),
+                }
+            );
+        }
+
+        let retry_after = Some(Duration::from_secs(7));
+        assert_eq!(
+            WriteError::from_response(StatusCode::TOO_MANY_REQUESTS, retry_after, ""),
+            WriteError::Retryable {
+                reason: "429 Too Many Requests".to_string(),
+                retry_after,
+            }
+        );
+        for status in [500, 502, 503] {
+            let err =
+                WriteError::from_response(StatusCode::from_u16(status).unwrap(), None, "down");
+            assert!(
+                matches!(&err, WriteError::Retryable { reason, .. } if reason.ends_with("(down)")),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        // 2015-10-21 07:28:00 UTC
+        let now = Utc.timestamp_opt(1_445_412_480, 0).unwrap();
+        assert_eq!(retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 5 ", now), Some(Duration::from_secs(5)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("-1", now), None);
+        assert_eq!(retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn caps_retry_delays() {
+        let batch = BatchConfig {
+            retry_base: Duration::from_millis(500),
+            retry_max: Duration::from_secs(30),
+            ..BatchConfig::default()
+        };
+
+        assert_eq!(batch.retry_delay(0, None, 1.0), Duration::from_millis(500));
+        assert_eq!(batch.retry_delay(2, None, 0.5), Duration::from_secs(1));
+        assert_eq!(batch.retry_delay(6, None, 1.49), Duration::from_secs(30));
+        assert_eq!(batch.retry_delay(40, None, 1.0), Duration::from_secs(30));
+        assert_eq!(
+            batch.retry_delay(0, Some(Duration::from_secs(3)), 1.0),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            batch.retry_delay(0, Some(Duration::from_secs(3600)), 1.0),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_batches_with_a_bad_token() {
+        let dir =
+            std::env::temp_dir().join(format!("powermax-influxdb-token-{}", std::process::id()));
+        let (url, bodies) = serve(|_| 401).await;
+        let mut writer = writer(url, Some(Spool::open(&dir, 1 << 20).unwrap()));
+
+        writer.flush(&mut lines(&["a v=1 1"])).await;
+        writer.flush(&mut lines(&["b v=2 2"])).await;
+
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+        assert!(writer.spool.as_ref().unwrap().is_empty());
+        assert!(matches!(
+            writer.last_permanent,
+            Some(WriteError::Permanent { status: 401, .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drains_a_bounded_number_of_batches() {
+        let dir =
+            std::env::temp_dir().join(format!("powermax-influxdb-drain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        for n in 0..DRAIN_BATCHES + 2 {
+            spool.append(format!("m v={} {}", n, n).as_bytes()).unwrap();
+        }
+        static FAILED: AtomicBool = AtomicBool::new(false);
+        let (url, bodies) = serve(|body| {
+            if body == "m v=1 1" && !FAILED.swap(true, Ordering::SeqCst) {
+                503
+            } else {
+                204
+            }
+        })
+        .await;
+        let mut writer = writer(url, Some(spool));
+
+        // The second batch fails and is tried first on the next drain.
+        assert!(!writer.drain().await);
+        assert!(writer.drain().await);
+        assert_eq!(bodies.lock().unwrap().len(), 2 + DRAIN_BATCHES);
+        assert!(!writer.spool.as_ref().unwrap().is_empty());
+
+        assert!(writer.drain().await);
+        assert!(writer.spool.as_ref().unwrap().is_empty());
+        let bodies = bodies.lock().unwrap();
+        let expected: Vec<String> = (0..DRAIN_BATCHES + 2)
+            .map(|n| format!("m v={} {}", n, n))
+            .collect();
+        assert_eq!(bodies[..2], expected[..2]);
+        assert_eq!(bodies[2..], expected[1..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,729 @@
+//! Polling of PowerMax inverters over a serial-to-TCP bridge.
+//!
+//! Unlike battery packs, which connect to the gateway, the gateway connects
+//! out to each configured inverter and reconnects whenever the link fails.
+
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+use crate::power::{
+    InverterMode, InverterRating, InverterReading, InverterSnapshot, InverterWarnings, PowerStatus,
+};
+use crate::setting::{self, ChargingCurrents, InverterSetting, Outcome, SettingError};
+use crate::sink::{Event, Router};
+use crate::voltronic::{self, InverterError};
+
+/// Pause before reconnecting after the link fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Where an inverter is and how often it is polled.
+#[derive(Debug, Clone)]
+pub struct InverterConfig {
+    pub name: String,
+    pub site: Option<String>,
+    /// `host:port` of the serial bridge.
+    pub address: String,
+    /// Interval of the `QPIGS` status query.
+    pub poll_interval: Duration,
+    pub response_timeout: Duration,
+    pub rating_interval: Duration,
+    pub warnings_interval: Duration,
+    pub mode_interval: Duration,
+}
+
+/// Queries sent on every connection, each at its own interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Query {
+    Status,
+    Rating,
+    Warnings,
+    Mode,
+    /// Currents `MCHGC` accepts; read with the ratings.
+    ChargingCurrents,
+    /// Currents `MUCHGC` accepts; read with the ratings.
+    UtilityCurrents,
+}
+
+impl Query {
+    const ALL: [Query; 6] = [
+        Query::Status,
+        Query::Rating,
+        Query::Warnings,
+        Query::Mode,
+        Query::ChargingCurrents,
+        Query::UtilityCurrents,
+    ];
+
+    fn interval(self, config: &InverterConfig) -> Duration {
+        match self {
+            Query::Status => config.poll_interval,
+            Query::Rating | Query::ChargingCurrents | Query::UtilityCurrents => {
+                config.rating_interval
+            }
+            Query::Warnings => config.warnings_interval,
+            Query::Mode => config.mode_interval,
+        }
+    }
+
+    fn command(self) -> &'static str {
+        match self {
+            Query::Status => PowerStatus::COMMAND,
+            Query::Rating => InverterRating::COMMAND,
+            Query::Warnings => InverterWarnings::COMMAND,
+            Query::Mode => InverterMode::COMMAND,
+            Query::ChargingCurrents => ChargingCurrents::CHARGING_COMMAND,
+            Query::UtilityCurrents => ChargingCurrents::UTILITY_COMMAND,
+        }
+    }
+}
+
+/// Tracks when each query is next due on one connection.
+#[derive(Debug)]
+struct Queries {
+    /// `(query, interval, next due)`, in the order of `Query::ALL`.
+    entries: Vec<(Query, Duration, Instant)>,
+}
+
+impl Queries {
+    /// Every query is due immediately, so a new connection reads
+    /// everything.
+    fn new(config: &InverterConfig, now: Instant) -> Self {
+        Queries {
+            entries: Query::ALL
+                .iter()
+                .map(|query| (*query, query.interval(config), now))
+                .collect(),
+        }
+    }
+
+    /// The query due first and when it is due; ties keep the order of
+    /// `Query::ALL`.
+    fn next(&self) -> (Query, Instant) {
+        self.entries
+            .iter()
+            .min_by_key(|(_, _, due)| *due)
+            .map(|(query, _, due)| (*query, *due))
+            .expect("at least one query")
+    }
+
+    /// Schedules the next run of `query`, sent at `now`, one interval after
+    /// it was due. A query that fell behind by more than one interval is
+    /// not sent repeatedly to catch up.
+    fn sent(&mut self, query: Query, now: Instant) {
+        if let Some((_, interval, due)) = self.entries.iter_mut().find(|(q, _, _)| *q == query) {
+            *due += *interval;
+            if *due <= now {
+                *due = now + *interval;
+            }
+        }
+    }
+
+    /// Makes `query` due at `now`.
+    fn expedite(&mut self, query: Query, now: Instant) {
+        if let Some((_, _, due)) = self.entries.iter_mut().find(|(q, _, _)| *q == query) {
+            *due = now;
+        }
+    }
+}
+
+/// A setting waiting for the inverter's task.
+struct SettingRequest {
+    setting: InverterSetting,
+    dry_run: bool,
+    reply: oneshot::Sender<Result<Outcome, SettingError>>,
+}
+
+/// Changes the settings of a running inverter.
+///
+/// Cloning is cheap; every clone talks to the same inverter.
+#[derive(Clone)]
+pub struct InverterControl {
+    requests: mpsc::Sender<SettingRequest>,
+}
+
+impl InverterControl {
+    /// Validates `setting` against the inverter's last ratings and, unless
+    /// `dry_run`, sends it between two polls and waits for the ACK.
+    pub async fn apply(
+        &self,
+        setting: InverterSetting,
+        dry_run: bool,
+    ) -> Result<Outcome, SettingError> {
+        let (reply, outcome) = oneshot::channel();
+        self.requests
+            .send(SettingRequest {
+                setting,
+                dry_run,
+                reply,
+            })
+            .await
+            .map_err(|_| SettingError::Stopped)?;
+        outcome.await.map_err(|_| SettingError::Stopped)?
+    }
+}
+
+/// Drives one inverter for the lifetime of the gateway.
+pub struct Inverter {
+    config: InverterConfig,
+    router: Arc<Router>,
+    /// Last mode seen, kept across reconnects so a reconnect is not a
+    /// transition.
+    mode: Option<InverterMode>,
+    /// Last ratings read, used to validate settings.
+    rating: Option<InverterRating>,
+    /// Charging currents the inverter accepts, used to validate settings.
+    currents: ChargingCurrents,
+    requests: mpsc::Receiver<SettingRequest>,
+    control: InverterControl,
+}
+
+impl Inverter {
+    pub fn new(config: InverterConfig, router: Arc<Router>) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        Inverter {
+            config,
+            router,
+            mode: None,
+            rating: None,
+            currents: ChargingCurrents::default(),
+            requests: rx,
+            control: InverterControl { requests: tx },
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Handle for changing settings once `run` has been spawned.
+    pub fn control(&self) -> InverterControl {
+        self.control.clone()
+    }
+
+    /// Connects, polls until the link fails, and reconnects, forever.
+    pub async fn run(mut self) {
+        loop {
+            match self.connect().await {
+                Ok(mut stream) => {
+                    println!(
+                        "{} connected to inverter {} at {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        self.config.name,
+                        self.config.address
+                    );
+                    let e = self.poll(&mut stream).await;
+                    eprintln!(
+                        "{} inverter {} disconnected; err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        self.config.name,
+                        e
+                    );
+                }
+                Err(e) => eprintln!(
+                    "{} cannot connect to inverter {} at {}; err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    self.config.name,
+                    self.config.address,
+                    e
+                ),
+            }
+            self.wait_offline(Instant::now() + RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Waits until `deadline` while disconnected, answering dry runs and
+    /// refusing everything else.
+    async fn wait_offline(&mut self, deadline: Instant) {
+        loop {
+            tokio::select! {
+                _ = sleep_until(deadline) => return,
+                Some(request) = self.requests.recv() => {
+                    let result = match self.check(&request) {
+                        Ok(Some(outcome)) => Ok(outcome),
+                        Ok(None) => Err(SettingError::NotConnected),
+                        Err(e) => Err(e),
+                    };
+                    let _ = request.reply.send(result);
+                }
+            }
+        }
+    }
+
+    /// Validates a request; returns the outcome if it is a dry run and
+    /// `None` if the setting still has to be sent.
+    fn check(&self, request: &SettingRequest) -> Result<Option<Outcome>, SettingError> {
+        let rating = self.rating.as_ref().ok_or(SettingError::NoRating)?;
+        request.setting.validate(rating, &self.currents)?;
+        if !request.dry_run {
+            return Ok(None);
+        }
+
+        let frame = request.setting.frame();
+        println!(
+            "{} inverter {} dry run of {}: would send {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            self.config.name,
+            request.setting,
+            frame.escape_ascii()
+        );
+        Ok(Some(Outcome::DryRun(frame)))
+    }
+
+    /// Answers a request on a live link. Returns the error if sending made
+    /// the link unusable.
+    async fn apply<S>(&mut self, stream: &mut S, request: SettingRequest) -> Option<InverterError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut fatal = None;
+        let result = match self.check(&request) {
+            Ok(Some(outcome)) => Ok(outcome),
+            Ok(None) => {
+                let deadline = Instant::now() + self.config.response_timeout;
+                let sent = voltronic::query(stream, &request.setting.command(), deadline)
+                    .await
+                    .and_then(|payload| setting::parse_ack(&payload));
+                match sent {
+                    Ok(()) => Ok(Outcome::Applied),
+                    Err(e) if e.is_fatal() => {
+                        let lost = SettingError::LinkLost(e.to_string());
+                        fatal = Some(e);
+                        Err(lost)
+                    }
+                    Err(e) => Err(SettingError::Inverter(e)),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        match &result {
+            Ok(Outcome::Applied) => println!(
+                "{} inverter {} set {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                self.config.name,
+                request.setting
+            ),
+            Ok(Outcome::DryRun(_)) => (),
+            Err(e) => eprintln!(
+                "{}: inverter {} setting {} failed. err = {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                self.config.name,
+                request.setting,
+                e
+            ),
+        }
+        let _ = request.reply.send(result);
+        fatal
+    }
+
+    async fn connect(&self) -> Result<TcpStream, InverterError> {
+        let stream = timeout(
+            self.config.response_timeout,
+            TcpStream::connect(&self.config.address),
+        )
+        .await
+        .map_err(|_| InverterError::Timeout)??;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Runs every query when it is due until an error makes the link
+    /// unusable, and returns that error. All queries are sent right after
+    /// connecting.
+    ///
+    /// Settings are sent as they arrive; after one is applied the ratings
+    /// are read again straight away.
+    async fn poll<S>(&mut self, stream: &mut S) -> InverterError
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut queries = Queries::new(&self.config, Instant::now());
+        loop {
+            let (query, at) = queries.next();
+            tokio::select! {
+                _ = sleep_until(at) => (),
+                Some(request) = self.requests.recv() => {
+                    let dry_run = request.dry_run;
+                    if let Some(e) = self.apply(stream, request).await {
+                        return e;
+                    }
+                    if !dry_run {
+                        queries.expedite(Query::Rating, Instant::now());
+                    }
+                    continue;
+                }
+            }
+            queries.sent(query, Instant::now());
+
+            let deadline = Instant::now() + self.config.response_timeout;
+            let result = match voltronic::query(stream, query.command(), deadline).await {
+                Ok(payload) => self.handle(query, &payload),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => (),
+                Err(e) if e.is_fatal() => return e,
+                Err(e) => eprintln!(
+                    "{}: inverter {} {} failed. err = {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    self.config.name,
+                    query.command(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Parses the response to `query` and sends what it reports.
+    fn handle(&mut self, query: Query, payload: &str) -> Result<(), InverterError> {
+        let reading = match query {
+            Query::Status => InverterReading::Status(PowerStatus::parse(payload)?),
+            Query::Rating => {
+                let rating = InverterRating::parse(payload)?;
+                self.rating = Some(rating.clone());
+                InverterReading::Rating(rating)
+            }
+            Query::Warnings => InverterReading::Warnings(InverterWarnings::parse(payload)?),
+            Query::ChargingCurrents => {
+                self.currents.charging = Some(ChargingCurrents::parse(payload)?);
+                return Ok(());
+            }
+            Query::UtilityCurrents => {
+                self.currents.utility = Some(ChargingCurrents::parse(payload)?);
+                return Ok(());
+            }
+            Query::Mode => {
+                let mode = InverterMode::parse(payload)?;
+                if self.mode != Some(mode) {
+                    println!(
+                        "{} inverter {} mode {} -> {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        self.config.name,
+                        self.mode
+                            .map_or_else(|| "unknown".to_string(), |m| m.to_string()),
+                        mode
+                    );
+                    self.router.send(Event::InverterMode {
+                        name: self.config.name.clone(),
+                        site: self.config.site.clone(),
+                        from: self.mode.replace(mode),
+                        to: mode,
+                        timestamp: Utc::now(),
+                    });
+                }
+                return Ok(());
+            }
+        };
+
+        self.router.send(Event::Inverter(Arc::new(InverterSnapshot {
+            timestamp: Utc::now(),
+            name: self.config.name.clone(),
+            site: self.config.site.clone(),
+            reading,
+        })));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::sink::Sink;
+
+    const QPIGS: &str = "000.0 00.0 230.0 50.0 0414 0345 008 405 52.10 012 087 0038 0014 215.4 52.18 00000 00110110 00 00 00754 010";
+    const QPIRI: &str =
+        "230.0 21.7 230.0 50.0 21.7 5000 4000 48.0 46.0 42.0 56.4 54.0 2 30 080 0 1 2 9 01 0 0 54.0 0 1";
+
+    fn config() -> InverterConfig {
+        let secs = Duration::from_secs;
+        InverterConfig {
+            name: "Inverter 1".to_string(),
+            site: Some("north-depot".to_string()),
+            address: "127.0.0.1:8899".to_string(),
+            poll_interval: secs(1),
+            response_timeout: Duration::from_millis(50),
+            rating_interval: secs(10),
+            warnings_interval: secs(3),
+            mode_interval: secs(2),
+        }
+    }
+
+    /// Forwards every event to a channel.
+    struct Collect(mpsc::UnboundedSender<Event>);
+
+    impl Sink for Collect {
+        async fn handle(&mut self, event: &Event) {
+            let _ = self.0.send(event.clone());
+        }
+    }
+
+    /// Answers commands like an inverter, with the payload `answer` gives,
+    /// until `answer` gives none. Returns the commands received and the
+    /// still open stream.
+    async fn inverter(
+        mut stream: DuplexStream,
+        mut answer: impl FnMut(&str) -> Option<String>,
+    ) -> (Vec<String>, DuplexStream) {
+        let mut commands = Vec::new();
+        let mut received = Vec::new();
+        loop {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await.unwrap();
+            if byte[0] != b'\r' {
+                received.push(byte[0]);
+                continue;
+            }
+            // without the CRC
+            let command = String::from_utf8(received[..received.len() - 2].to_vec()).unwrap();
+            received.clear();
+            let payload = answer(&command);
+            commands.push(command);
+            match payload {
+                Some(payload) => {
+                    let response = voltronic::frame(&format!("({}", payload));
+                    stream.write_all(&response).await.unwrap();
+                }
+                None => return (commands, stream),
+            }
+        }
+    }
+
+    /// Runs the queries of a schedule until `end` and returns each with the
+    /// time it was sent, relative to `start`.
+    fn run(queries: &mut Queries, start: Instant, end: Duration) -> Vec<(Query, Duration)> {
+        let mut sent = Vec::new();
+        loop {
+            let (query, at) = queries.next();
+            if at > start + end {
+                return sent;
+            }
+            sent.push((query, at - start));
+            queries.sent(query, at);
+        }
+    }
+
+    #[test]
+    fn sends_every_query_first() {
+        let start = Instant::now();
+        let mut queries = Queries::new(&config(), start);
+        let first: Vec<Query> = run(&mut queries, start, Duration::ZERO)
+            .into_iter()
+            .map(|(query, _)| query)
+            .collect();
+        assert_eq!(first, Query::ALL);
+        assert_eq!(
+            queries.next(),
+            (Query::Status, start + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn sends_queries_at_their_intervals() {
+        let start = Instant::now();
+        let mut queries = Queries::new(&config(), start);
+        let sent = run(&mut queries, start, Duration::from_secs(10));
+
+        let times = |query: Query| -> Vec<u64> {
+            sent.iter()
+                .filter(|(q, _)| *q == query)
+                .map(|(_, at)| at.as_secs())
+                .collect()
+        };
+        assert_eq!(times(Query::Status), (0..=10).collect::<Vec<_>>());
+        assert_eq!(times(Query::Mode), [0, 2, 4, 6, 8, 10]);
+        assert_eq!(times(Query::Warnings), [0, 3, 6, 9]);
+        assert_eq!(times(Query::Rating), [0, 10]);
+        assert_eq!(times(Query::ChargingCurrents), [0, 10]);
+    }
+
+    #[test]
+    fn does_not_catch_up_missed_queries() {
+        let start = Instant::now();
+        let mut config = config();
+        config.warnings_interval = Duration::from_secs(3600);
+        config.mode_interval = Duration::from_secs(3600);
+        let mut queries = Queries::new(&config, start);
+        run(&mut queries, start, Duration::ZERO);
+
+        // A slow response held up the status query due at 1 s until 5.5 s.
+        queries.sent(Query::Status, start + Duration::from_millis(5500));
+        assert_eq!(
+            queries.next(),
+            (Query::Status, start + Duration::from_millis(6500))
+        );
+    }
+
+    #[test]
+    fn expedites_queries() {
+        let start = Instant::now();
+        let mut queries = Queries::new(&config(), start);
+        run(&mut queries, start, Duration::ZERO);
+
+        let now = start + Duration::from_millis(300);
+        queries.expedite(Query::Rating, now);
+        assert_eq!(queries.next(), (Query::Rating, now));
+        queries.sent(Query::Rating, now);
+        assert_eq!(
+            queries.next(),
+            (Query::Status, start + Duration::from_secs(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_readings_and_mode_transitions_until_timeout() {
+        let (tx, mut events) = mpsc::unbounded_channel();
+        let mut router = Router::new();
+        router.add("test", Collect(tx), 64);
+        let mut config = config();
+        config.mode_interval = Duration::from_millis(5);
+        let mut poller = Inverter::new(config, Arc::new(router));
+
+        let (mut stream, device) = tokio::io::duplex(1024);
+        let mut modes = ["B", "B", "L"].into_iter();
+        let device = tokio::spawn(inverter(device, move |command| {
+            match command {
+                "QPIGS" => Some(QPIGS),
+                "QPIRI" => Some(QPIRI),
+                // refused, which is not fatal
+                "QPIWS" => Some("NAK"),
+                "QMCHGCR" => Some("010 020 030 040 050 060 070 080"),
+                "QMUCHGCR" => Some("002 010 020 030"),
+                "QMOD" => modes.next(),
+                _ => None,
+            }
+            .map(str::to_string)
+        }));
+
+        let err = poller.poll(&mut stream).await;
+        assert!(matches!(err, InverterError::Timeout), "{}", err);
+        let (commands, _device) = device.await.unwrap();
+        assert_eq!(
+            commands,
+            ["QPIGS", "QPIRI", "QPIWS", "QMOD", "QMCHGCR", "QMUCHGCR", "QMOD", "QMOD", "QMOD"]
+        );
+        assert!(poller.rating.is_some());
+        assert!(poller.currents.charging.is_some() && poller.currents.utility.is_some());
+
+        drop(poller);
+        let mut kinds = Vec::new();
+        while let Some(event) = events.recv().await {
+            kinds.push(match event {
+                Event::Inverter(snapshot) => snapshot.reading.kind().to_string(),
+                Event::InverterMode { from, to, .. } => format!("{:?} -> {:?}", from, to),
+                other => panic!("unexpected event {:?}", other),
+            });
+        }
+        assert_eq!(
+            kinds,
+            [
+                "status",
+                "rating",
+                "None -> Battery",
+                "Some(Battery) -> Line"
+            ]
+        );
+    }
+
+    /// Answers the initial queries like `reports_readings...` does.
+    fn answer(command: &str) -> Option<&'static str> {
+        match command {
+            "QPIGS" => Some(QPIGS),
+            "QPIRI" => Some(QPIRI),
+            "QPIWS" => Some("00000000000000000000000000000000"),
+            "QMCHGCR" => Some("010 020 030 040 050 060 070 080"),
+            "QMUCHGCR" => Some("002 010 020 030"),
+            "QMOD" => Some("L"),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_settings_and_rereads_the_ratings() {
+        let mut poller = Inverter::new(config(), Arc::new(Router::new()));
+        let control = poller.control();
+        let (result, setting) = oneshot::channel();
+        let mut result = Some(result);
+
+        let (mut stream, device) = tokio::io::duplex(1024);
+        let mut ratings = 0;
+        let device = tokio::spawn(inverter(device, move |command| match command {
+            // the last initial query; ratings and currents are known now
+            "QMUCHGCR" => {
+                let control = control.clone();
+                let result = result.take().unwrap();
+                tokio::spawn(async move {
+                    let outcome = control
+                        .apply(InverterSetting::MaxChargingCurrent(60), false)
+                        .await;
+                    let _ = result.send(outcome);
+                });
+                answer(command).map(str::to_string)
+            }
+            "MCHGC060" => Some("ACK".to_string()),
+            // the second read ends the test
+            "QPIRI" if ratings > 0 => None,
+            "QPIRI" => {
+                ratings += 1;
+                answer(command).map(str::to_string)
+            }
+            _ => answer(command).map(str::to_string),
+        }));
+
+        let err = poller.poll(&mut stream).await;
+        assert!(matches!(err, InverterError::Timeout), "{}", err);
+        let (commands, _device) = device.await.unwrap();
+        assert_eq!(
+            commands,
+            ["QPIGS", "QPIRI", "QPIWS", "QMOD", "QMCHGCR", "QMUCHGCR", "MCHGC060", "QPIRI"]
+        );
+        assert!(matches!(setting.await.unwrap(), Ok(Outcome::Applied)));
+    }
+
+    #[tokio::test]
+    async fn reports_refused_settings() {
+        let mut poller = Inverter::new(config(), Arc::new(Router::new()));
+        poller.rating = Some(InverterRating::parse(QPIRI).unwrap());
+        poller.currents.charging = Some(vec![10, 20, 30, 40, 50, 60, 70, 80]);
+
+        let (mut stream, device) = tokio::io::duplex(1024);
+        let device = tokio::spawn(inverter(device, |command| {
+            (command == "MCHGC060").then(|| "NAK".to_string())
+        }));
+        let (reply, outcome) = oneshot::channel();
+        let request = SettingRequest {
+            setting: InverterSetting::MaxChargingCurrent(60),
+            dry_run: false,
+            reply,
+        };
+        // a NAK leaves the link usable
+        assert!(poller.apply(&mut stream, request).await.is_none());
+        assert!(matches!(
+            outcome.await.unwrap(),
+            Err(SettingError::Inverter(InverterError::Nak))
+        ));
+
+        let (reply, outcome) = oneshot::channel();
+        let request = SettingRequest {
+            setting: InverterSetting::MaxChargingCurrent(70),
+            dry_run: false,
+            reply,
+        };
+        assert!(matches!(
+            poller.apply(&mut stream, request).await,
+            Some(InverterError::Timeout)
+        ));
+        assert!(matches!(
+            outcome.await.unwrap(),
+            Err(SettingError::LinkLost(_))
+        ));
+        let (commands, _device) = device.await.unwrap();
+        assert_eq!(commands, ["MCHGC060", "MCHGC070"]);
+    }
+}
@@ -0,0 +1,56 @@
+pub mod auth;
+pub mod config;
+pub mod control;
+pub mod device;
+pub mod driver;
+pub mod error;
+pub mod handshake;
+pub mod history;
+mod http;
+pub mod influxdb;
+pub mod inverter;
+pub mod metrics;
+pub mod mqtt;
+pub mod power;
+pub mod protocol;
+pub mod register;
+pub mod registry;
+pub mod schedule;
+pub mod session;
+pub mod setting;
+pub mod sink;
+pub mod site;
+pub mod snapshot;
+pub mod spool;
+pub mod status;
+pub mod voltronic;
+
+pub use auth::{AuthError, Authenticator, KeyStore};
+pub use config::{Config, ConfigError};
+pub use control::Control;
+pub use device::DeviceId;
+pub use driver::{BatteryDriver, Sbs, B5120};
+pub use error::Error;
+pub use handshake::Hello;
+pub use history::{History, HistoryError};
+pub use influxdb::{BatchConfig, InfluxDb};
+pub use inverter::{Inverter, InverterConfig, InverterControl};
+pub use metrics::Metrics;
+pub use mqtt::Mqtt;
+pub use power::{
+    BatteryType, ChargerSourcePriority, ChargingStatus, InputVoltageRange, InverterMode,
+    InverterRating, InverterReading, InverterSnapshot, InverterWarnings, OutputSourcePriority,
+    PowerStatus,
+};
+pub use protocol::{crc8_check, FrameError, PackCodec};
+pub use register::{Reading, Register, Unit};
+pub use registry::{DeviceInfo, Registry};
+pub use schedule::{RegisterGroup, Schedule, Scheduler};
+pub use session::{BmsSession, SessionConfig, SessionStats};
+pub use setting::{ChargingCurrents, InverterSetting, Outcome, SettingError};
+pub use sink::{Event, Router, Sink};
+pub use site::{EnergyFlow, FlowConfig, Sites};
+pub use snapshot::{FieldValue, PackSnapshot};
+pub use spool::Spool;
+pub use status::{BatteryStatus, PackConfig, PackStatus};
+pub use voltronic::InverterError;
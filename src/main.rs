@@ -1,625 +1,251 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
 use chrono::prelude::*;
-use crc::{Crc, CRC_8_SMBUS};
-use reqwest::Client;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use powermax_b5120::sink::QUEUE_CAPACITY;
+use powermax_b5120::site;
+use powermax_b5120::{
+    Authenticator, BmsSession, Config, ConfigError, Control, Event, History, InfluxDb, Inverter,
+    Metrics, Mqtt, Registry, Router, Sites,
+};
 use tokio::net::TcpListener;
-use tokio::time::{sleep, timeout, Duration};
-
-const CRC_8: Crc<u8> = Crc::<u8>::new(&CRC_8_SMBUS);
-
-const TCPTIMEOUT: u64 = 10; // seconds
-const INFLUXDBURL: &str =
-    "http://localhost:9999/api/v2/write?org=kideasoft&bucket=env-sensor-data&precision=ms";
-const TOKEN: &str = "Token 1iihb5Rr-Fa5g7xun-FD1-av-3Flurp0RnORNAe-mZgiUBEpX0L1w3Zez3syS8sU_rKNxPyu2yD_rC3664dvjg==";
-
-// #[derive(Debug, Clone)]
-// struct PowerStatus {
-//     pub grid_voltage: f32,
-//     pub grid_frequency: f32,
-//     pub ac_output_voltage: f32,
-//     pub ac_output_frequency: f32,
-//     pub ac_output_apparent_power: u32,
-//     pub ac_output_active_power: u32,
-//     pub output_load_percent: u32,
-//     pub bus_voltage: u32,
-//     pub battery_voltage: f32,
-//     pub battery_charging_current: u32,
-//     pub battery_capacity: u32,
-//     pub inverter_heat_sink_temperature: u32,
-//     pub pv_input_current: u32,
-//     pub pv_input_voltage: f32,
-//     pub battery_voltage_from_scc: f32,
-//     pub battery_discharge_current: u32,
-//     pub sbu_priority: bool,
-//     pub configuration_status: bool,
-//     pub scc_firmware_version_status: bool,
-//     pub load_status: bool,
-//     pub charging_status: ChargingStatus,
-//     pub battery_voltage_offset_for_fans_on: u32,
-//     pub eeprom_version: u32,
-//     pub pv_charging_power: u32,
-//     pub charging_in_floating_mode: bool,
-//     pub switch_on: bool,
-// }
-
-// #[derive(Debug, Clone)]
-// enum ChargingStatus {
-//     Scc,
-//     Ac,
-//     SccAc,
-//     DoNothing,
-// }
+use tokio::signal::unix::{signal, SignalKind};
+
+const USAGE: &str = "usage: powermax-b5120 [--config <path>]";
+
+/// Returns the value of `--config`, if given.
+fn parse_args() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(p) => path = Some(PathBuf::from(p)),
+                None => {
+                    eprintln!("{} requires a path\n{}", arg, USAGE);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => {
+                eprintln!("unexpected argument `{}`\n{}", arg, USAGE);
+                process::exit(2);
+            }
+        }
+    }
 
-fn crc8_check(send: &[u8], rev: &[u8]) -> bool {
-    let mut data = Vec::new();
-    data.extend_from_slice(send);
-    data.extend_from_slice(rev);
+    path
+}
 
-    let len = data.len();
+fn load(path: Option<&Path>) -> Result<(Config, Authenticator, Registry), ConfigError> {
+    let config = Config::load(path)?;
+    let auth = config.authenticator()?;
+    let registry = config.registry()?;
+    Ok((config, auth, registry))
+}
 
-    if len > 3 {
-        CRC_8.checksum(&data[..len - 1]) == data[len - 1]
-    } else {
-        false
+/// Re-reads the device registry whenever the process receives SIGHUP.
+async fn reload_on_sighup(registry: Arc<Registry>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!(
+                "cannot listen for SIGHUP, registry reload disabled; err = {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match registry.reload() {
+            Ok(n) => println!(
+                "{} reloaded device registry: {} devices",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                n
+            ),
+            Err(e) => eprintln!(
+                "{} device registry reload failed, keeping previous entries; err = {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                e
+            ),
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("0.0.0.0:30278").await?;
+    let (config, auth, registry) = match load(parse_args().as_deref()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            process::exit(2);
+        }
+    };
+    let auth = Arc::new(auth);
+    let registry = Arc::new(registry);
+
+    tokio::spawn(reload_on_sighup(registry.clone()));
+
+    let listener = TcpListener::bind(&config.listen).await?;
+    let mut router = Router::new();
+    if config.influxdb.enabled {
+        let spool = match config.influxdb.spool() {
+            Ok(spool) => spool,
+            Err(e) => {
+                eprintln!("configuration error: {}", e);
+                process::exit(2);
+            }
+        };
+        router.add(
+            "influxdb",
+            InfluxDb::spawn(
+                &config.influxdb.write_url(),
+                &config.influxdb.authorization(),
+                config.influxdb.batch_config(),
+                spool,
+            ),
+            QUEUE_CAPACITY,
+        );
+    }
+    if config.mqtt.enabled {
+        router.add(
+            "mqtt",
+            Mqtt::spawn(
+                config.mqtt.options(),
+                &config.mqtt.topic_prefix,
+                &config.mqtt.discovery_prefix,
+            ),
+            QUEUE_CAPACITY,
+        );
+    }
+    if config.metrics.enabled {
+        let metrics = Metrics::new();
+        let listener = TcpListener::bind(&config.metrics.listen).await?;
+        tokio::spawn(metrics.clone().serve(listener));
+        router.add("metrics", metrics, QUEUE_CAPACITY);
+    }
+    if config.history.enabled {
+        let history = match History::open(&config.history.path, config.history.retention()) {
+            Ok(history) => history,
+            Err(e) => {
+                eprintln!(
+                    "cannot open history database {}: {}",
+                    config.history.path.display(),
+                    e
+                );
+                process::exit(2);
+            }
+        };
+        let listener = TcpListener::bind(&config.history.listen).await?;
+        tokio::spawn(history.clone().maintain());
+        tokio::spawn(history.clone().serve(listener));
+        router.add("history", history, QUEUE_CAPACITY);
+    }
+    let mut flows = None;
+    if config.sites.enabled && !config.inverters.is_empty() {
+        let inverters: Vec<_> = config
+            .inverters
+            .iter()
+            .map(|inverter| inverter.inverter_config())
+            .collect();
+        let (sites, rx) = Sites::new(&inverters, config.sites.flow_config());
+        router.add("sites", sites, QUEUE_CAPACITY);
+        flows = Some(rx);
+    }
+    let router = Arc::new(router);
+    if let Some(flows) = flows {
+        tokio::spawn(site::forward(flows, router.clone()));
+    }
+    let session_config = config.session_config();
+
+    let mut controls = BTreeMap::new();
+    for inverter in &config.inverters {
+        let inverter = Inverter::new(inverter.inverter_config(), router.clone());
+        controls.insert(inverter.name().to_string(), inverter.control());
+        tokio::spawn(inverter.run());
+    }
+    if config.control.enabled {
+        let listener = TcpListener::bind(&config.control.listen).await?;
+        tokio::spawn(Control::new(controls, &config.control.token).serve(listener));
+    }
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, peer) = listener.accept().await?;
+        let auth = auth.clone();
+        let registry = registry.clone();
+        let router = router.clone();
+        let session_config = session_config.clone();
 
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            let id: String;
-
-            match socket.read(&mut buf).await {
-                // socket closed
-                Ok(n) if n == 0 => return,
-                Ok(n) if n == 6 => {
-                    // id = 6 bytes MAC address
-                    let id_num = u64::from_be_bytes([
-                        buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
-                    ]);
-                    id = format!("{:#x}", id_num).to_string();
-
-                    // TODO:
-                    // authentication process
-
-                    // DEBUG:
-                    println!("******************************************************");
-                    println!(
-                        "{} Connected device: {}",
+            let mut session = match BmsSession::handshake(
+                socket,
+                &auth,
+                registry.clone(),
+                router.clone(),
+                session_config,
+            )
+            .await
+            {
+                Ok(session) => session,
+                Err(e) => {
+                    eprintln!(
+                        "{} rejected connection from {}; err = {}",
                         Local::now().format("%Y-%m-%d %H:%M:%S"),
-                        id
+                        peer,
+                        e
                     );
-                    println!("******************************************************");
-                }
-                Ok(n) => {
-                    println!("MAC addr error\nREV: {:#X?}", &buf[..n]);
-                    return;
-                }
-                Err(e) => {
-                    eprintln!("failed to read from socket; err = {:?}", e);
                     return;
                 }
             };
 
-            // In a loop, write command to the socket and read the data.
-            loop {
-                // Read cells voltage
-                for cmd in 0x01..=0x10 {
-                    let data_len = 2;
-                    let send = [0x0A, cmd, data_len];
-                    // println!("Send: {:#X?}", send);
-
-                    if let Err(e) = socket.write_all(&send).await {
-                        eprintln!("failed to write to socket; err = {:?}", e);
-                        return;
-                    }
-
-                    sleep(Duration::from_secs(1)).await;
-
-                    match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => {
-                            // println!("REV: {:#X?}", &buf[..n]);
-                            if n == data_len as usize + 1 {
-                                if crc8_check(&send, &buf[..n]) {
-                                    let voltage = u16::from_be_bytes([buf[0], buf[1]]);
-                                    println!("Cell {}: {}mV", cmd, voltage);
-
-                                    let id = id.clone();
-                                    tokio::spawn(async move {
-                                        let http_client = Client::new();
-                                        let res = http_client
-                                            .post(INFLUXDBURL)
-                                            .header("Authorization", TOKEN)
-                                            .body(format!(
-                                                "powermax_b5120,location={} cell_{}={}",
-                                                id, cmd, voltage
-                                            ))
-                                            .send()
-                                            .await;
-
-                                        match res {
-                                            Ok(r) => println!(
-                                                "{} write {} cell_{} voltage to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                cmd,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} cell_{} voltage to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                cmd,
-                                                e
-                                            )
-                                        };
-                                    });
-                                } else {
-                                    println!("CRC-8 checksum error");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    sleep(Duration::from_secs(1)).await;
-                }
-
-                // Read temperatures
-                for cmd in 0x13..=0x15 {
-                    let data_len = 2;
-                    let send = [0x0A, cmd, data_len];
-                    // println!("Send: {:#X?}", send);
-
-                    if let Err(e) = socket.write_all(&send).await {
-                        eprintln!("failed to write to socket; err = {:?}", e);
-                        return;
-                    }
-
-                    sleep(Duration::from_secs(1)).await;
-
-                    match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => {
-                            // println!("REV: {:#X?}", &buf[..n]);
-                            if n == data_len as usize + 1 {
-                                if crc8_check(&send, &buf[..n]) {
-                                    let temperature = i16::from_be_bytes([buf[0], buf[1]]);
-                                    let temperature = temperature as f32 / 100.0;
-                                    println!("Temperature {}: {}C°", cmd - 0x12, temperature,);
-
-                                    let id = id.clone();
-                                    tokio::spawn(async move {
-                                        let http_client = Client::new();
-                                        let res = http_client
-                                            .post(INFLUXDBURL)
-                                            .header("Authorization", TOKEN)
-                                            .body(format!(
-                                                "powermax_b5120,location={} temperature_{}={}",
-                                                id,
-                                                cmd - 0x12,
-                                                temperature
-                                            ))
-                                            .send()
-                                            .await;
-
-                                        match res {
-                                            Ok(r) => println!(
-                                                "{} write {} temperature_{} to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                cmd - 0x12,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} temperature_{} to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                cmd - 0x12,
-                                                e
-                                            )
-                                        };
-                                    });
-                                } else {
-                                    println!("CRC-8 checksum error");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    sleep(Duration::from_secs(1)).await;
-                }
-
-                // Read total voltage, current, full capacity, remaining capacity
-                for cmd in [0x11, 0x12, 0x16, 0x17] {
-                    let data_len = 4;
-                    let send = [0x0A, cmd, data_len];
-
-                    if let Err(e) = socket.write_all(&send).await {
-                        eprintln!("failed to write to socket; err = {:?}", e);
-                        return;
-                    }
-
-                    sleep(Duration::from_secs(1)).await;
-
-                    match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => {
-                            // println!("REV: {:#X?}", &buf[..n]);
-                            if n == data_len as usize + 1 {
-                                if crc8_check(&send, &buf[..n]) {
-                                    match cmd {
-                                        0x11 => {
-                                            let voltage = u32::from_be_bytes([
-                                                buf[0], buf[1], buf[2], buf[3],
-                                            ]);
-                                            println!("Total voltage: {}mV", voltage);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                "powermax_b5120,location={} total_voltage={}",
-                                                id,
-                                                voltage
-                                            ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} total_voltage to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} total_voltage to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x12 => {
-                                            let current = i32::from_be_bytes([
-                                                buf[0], buf[1], buf[2], buf[3],
-                                            ]);
-                                            println!("Current: {}mA", current);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} current={}",
-                                                        id, current,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} current to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} current to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x16 => {
-                                            let full_capacity = u32::from_be_bytes([
-                                                buf[0], buf[1], buf[2], buf[3],
-                                            ]);
-                                            println!("Full capacity: {}mAH", full_capacity);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} full_capacity={}",
-                                                        id, full_capacity,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} full_capacity to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} full_capacity to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x17 => {
-                                            let remaining_capacity = u32::from_be_bytes([
-                                                buf[0], buf[1], buf[2], buf[3],
-                                            ]);
-                                            println!(
-                                                "Remaining capacity: {}mAH",
-                                                remaining_capacity
-                                            );
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} remaining_capacity={}",
-                                                        id, remaining_capacity,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} remaining_capacity to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} remaining_capacity to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        _ => (),
-                                    }
-                                } else {
-                                    println!("CRC-8 checksum error");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    sleep(Duration::from_secs(1)).await;
-                }
-
-                // Read RSOC, cycle count, pack status, battery status, pack config
-                for cmd in 0x18..=0x1C {
-                    let data_len = 2;
-                    let send = [0x0A, cmd, data_len];
-
-                    if let Err(e) = socket.write_all(&send).await {
-                        eprintln!("failed to write to socket; err = {:?}", e);
-                        return;
-                    }
-
-                    sleep(Duration::from_secs(1)).await;
-
-                    match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => {
-                            // println!("REV: {:#X?}", &buf[..n]);
-                            if n == data_len as usize + 1 {
-                                if crc8_check(&send, &buf[..n]) {
-                                    let data = u16::from_be_bytes([buf[0], buf[1]]);
-
-                                    match cmd {
-                                        0x18 => {
-                                            println!("RSOC: {}%", data);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} RSOC={}",
-                                                        id, data,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                                    Ok(r) => println!(
-                                                        "{} write {} RSOC to influxDB: resp = {:?}",
-                                                        Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                        id,
-                                                        r
-                                                    ),
-                                                    Err(e) => eprintln!(
-                                                "{}: write {} RSOC to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            ),
-                                                };
-                                            });
-                                        }
-                                        0x19 => {
-                                            println!("Cycle count: {}", data);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} cycle_count={}",
-                                                        id, data,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} cycle count to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} cycle count to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x1A => {
-                                            println!("Pack status: {:#04X}", data);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} pack_status={}",
-                                                        id, data,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} pack_status to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} pack_status to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x1B => {
-                                            println!("Battery status: {:#04X}", data);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} battery_status={}",
-                                                        id, data,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} battery_status to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} battery_status to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        0x1C => {
-                                            println!("Pack config: {:#04X}", data);
-
-                                            let id = id.clone();
-                                            tokio::spawn(async move {
-                                                let http_client = Client::new();
-                                                let res = http_client
-                                                    .post(INFLUXDBURL)
-                                                    .header("Authorization", TOKEN)
-                                                    .body(format!(
-                                                        "powermax_b5120,location={} pack_config={}",
-                                                        id, data,
-                                                    ))
-                                                    .send()
-                                                    .await;
-
-                                                match res {
-                                            Ok(r) => println!(
-                                                "{} write {} pack_config to influxDB: resp = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                r
-                                            ),
-                                            Err(e) => eprintln!(
-                                                "{}: write {} pack_config to influxDB failed. err = {:?}",
-                                                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                                                id,
-                                                e
-                                            )
-                                        };
-                                            });
-                                        }
-                                        _ => (),
-                                    }
-                                } else {
-                                    println!("CRC-8 checksum error");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    sleep(Duration::from_secs(1)).await;
+            // DEBUG:
+            println!("******************************************************");
+            println!(
+                "{} Connected device: {} ({})",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                session.id(),
+                session.driver().name()
+            );
+            println!("******************************************************");
+            router.send(Event::Connected {
+                device_id: session.id(),
+                device: registry.get(session.id()),
+                peer,
+                timestamp: Utc::now(),
+            });
+
+            let reason = match session.run().await {
+                Ok(()) => None,
+                Err(e) => {
+                    eprintln!(
+                        "{} device {} disconnected; err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        session.id(),
+                        e
+                    );
+                    Some(e.to_string())
                 }
-            }
+            };
+            println!(
+                "{} device {} session stats: {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                session.id(),
+                session.stats()
+            );
+            router.send(Event::Disconnected {
+                device_id: session.id(),
+                reason,
+                stats: session.stats().clone(),
+                timestamp: Utc::now(),
+            });
         });
     }
 }
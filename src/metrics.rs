@@ -0,0 +1,597 @@
+//! Prometheus exporter: the latest value of every register per device,
+//! served as gauges on `GET /metrics`.
+//!
+//! Snapshots only carry the registers polled in their round, so values are
+//! merged per device and each keeps the last reading received.
+//! `b5120_last_update_timestamp` tells how fresh they are. Request outcomes
+//! of every device's sessions are counted in `b5120_session_*_total`.
+//! Inverter readings are exported as `powermax_inverter_*` gauges.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use tokio::net::TcpListener;
+
+use crate::device::DeviceId;
+use crate::http::{self, Request, Response};
+use crate::power::{self, InverterMode, InverterSnapshot};
+use crate::register::Register;
+use crate::registry::DeviceInfo;
+use crate::session::SessionStats;
+use crate::sink::{Event, Sink};
+use crate::site::{self, EnergyFlow};
+use crate::snapshot::FieldValue;
+
+/// Every register-backed metric family with its help text, in output order.
+const FAMILIES: &[(&str, &str)] = &[
+    ("b5120_cell_voltage_millivolts", "Cell voltage."),
+    ("b5120_temperature_celsius", "Temperature sensor reading."),
+    ("b5120_total_voltage_millivolts", "Pack voltage."),
+    (
+        "b5120_current_milliamps",
+        "Pack current, positive while charging.",
+    ),
+    (
+        "b5120_full_capacity_milliamp_hours",
+        "Full charge capacity.",
+    ),
+    (
+        "b5120_remaining_capacity_milliamp_hours",
+        "Remaining capacity.",
+    ),
+    ("b5120_rsoc_percent", "Relative state of charge."),
+    ("b5120_cycle_count", "Charge cycles."),
+    ("b5120_pack_status", "Raw pack status register."),
+    ("b5120_battery_status", "Raw battery status register."),
+    ("b5120_pack_config", "Raw pack configuration register."),
+];
+
+/// Metric family of a register and the label telling cells or sensors
+/// apart.
+fn family(register: Register) -> (&'static str, Option<(&'static str, u8)>) {
+    match register {
+        Register::CellVoltage(n) => ("b5120_cell_voltage_millivolts", Some(("cell", n))),
+        Register::Temperature(n) => ("b5120_temperature_celsius", Some(("sensor", n))),
+        Register::TotalVoltage => ("b5120_total_voltage_millivolts", None),
+        Register::Current => ("b5120_current_milliamps", None),
+        Register::FullCapacity => ("b5120_full_capacity_milliamp_hours", None),
+        Register::RemainingCapacity => ("b5120_remaining_capacity_milliamp_hours", None),
+        Register::Rsoc => ("b5120_rsoc_percent", None),
+        Register::CycleCount => ("b5120_cycle_count", None),
+        Register::PackStatus => ("b5120_pack_status", None),
+        Register::BatteryStatus => ("b5120_battery_status", None),
+        Register::PackConfig => ("b5120_pack_config", None),
+    }
+}
+
+/// Escapes a label value for the text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Metric name suffix of an inverter unit.
+fn unit_suffix(unit: &str) -> &'static str {
+    match unit {
+        "V" => "_volts",
+        "Hz" => "_hertz",
+        "VA" => "_voltamperes",
+        "W" => "_watts",
+        "A" => "_amperes",
+        "%" => "_percent",
+        "°C" => "_celsius",
+        _ => "",
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str) {
+    typed_header(out, name, help, "gauge");
+}
+
+fn typed_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Help text of every `SessionStats` counter, exported as
+/// `b5120_session_<name>_total`.
+const SESSION_COUNTERS: &[(&str, &str)] = &[
+    ("requests", "Requests sent to the device."),
+    ("ok", "Requests answered with a usable response."),
+    (
+        "retries",
+        "Requests repeated after a timeout or an unusable response.",
+    ),
+    ("timeouts", "Requests not answered in time."),
+    ("crc_errors", "Responses that failed the checksum."),
+    (
+        "short_frames",
+        "Responses shorter than expected at the deadline.",
+    ),
+    (
+        "long_frames",
+        "Responses longer than expected without a valid frame.",
+    ),
+    ("late_frames", "Late or unsolicited bytes received."),
+];
+
+/// What is known about one device.
+#[derive(Debug, Default)]
+struct DeviceState {
+    info: Option<Arc<DeviceInfo>>,
+    connected: bool,
+    last_update: Option<DateTime<Utc>>,
+    values: HashMap<Register, f32>,
+    /// Counters of the running session.
+    session: Option<SessionStats>,
+    /// Counters of the sessions that ended, so the totals never go down
+    /// while the gateway runs.
+    finished: HashMap<&'static str, u64>,
+}
+
+impl DeviceState {
+    /// Total of counter `name` over every session.
+    fn counter(&self, name: &str) -> u64 {
+        let session = self.session.as_ref().map_or(0, |stats| {
+            stats
+                .counters()
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map_or(0, |(_, value)| *value)
+        });
+        self.finished.get(name).copied().unwrap_or(0) + session
+    }
+
+    /// Folds the counters of a session that ended into the totals.
+    fn finish(&mut self, stats: &SessionStats) {
+        for (name, value) in stats.counters() {
+            *self.finished.entry(name).or_default() += value;
+        }
+        self.session = None;
+    }
+}
+
+/// Sink keeping the latest readings for the `/metrics` endpoint.
+///
+/// Cloning is cheap; every clone shares the same readings.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    devices: Arc<Mutex<BTreeMap<DeviceId, DeviceState>>>,
+    /// Latest readings of every inverter by name.
+    inverters: Arc<Mutex<BTreeMap<String, InverterState>>>,
+    /// Latest energy flow of every inverter's site, by inverter name.
+    flows: Arc<Mutex<BTreeMap<String, Arc<EnergyFlow>>>>,
+}
+
+#[derive(Debug, Default)]
+struct InverterState {
+    site: Option<String>,
+    /// Latest reading of each kind.
+    readings: BTreeMap<&'static str, Arc<InverterSnapshot>>,
+    mode: Option<InverterMode>,
+}
+
+impl InverterState {
+    fn last_update(&self) -> Option<DateTime<Utc>> {
+        self.readings
+            .values()
+            .map(|snapshot| snapshot.timestamp)
+            .max()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Current readings in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = self.render_packs();
+        self.render_inverters(&mut out);
+        self.render_flows(&mut out);
+        out
+    }
+
+    fn render_packs(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "b5120_device_info",
+            "Registry metadata of a device, always 1.",
+        );
+        for (id, state) in devices.iter() {
+            if let Some(info) = &state.info {
+                let _ = write!(
+                    out,
+                    "b5120_device_info{{device=\"{}\",name=\"{}\",site=\"{}\"",
+                    id,
+                    escape_label(&info.name),
+                    escape_label(&info.site)
+                );
+                if let Some(position) = &info.position {
+                    let _ = write!(out, ",position=\"{}\"", escape_label(position));
+                }
+                out.push_str("} 1\n");
+            }
+        }
+
+        header(
+            &mut out,
+            "b5120_connected",
+            "Whether the device has a session with the gateway.",
+        );
+        for (id, state) in devices.iter() {
+            let _ = writeln!(
+                out,
+                "b5120_connected{{device=\"{}\"}} {}",
+                id, state.connected as u8
+            );
+        }
+
+        header(
+            &mut out,
+            "b5120_last_update_timestamp",
+            "Unix time in seconds of the last reading received from the device.",
+        );
+        for (id, state) in devices.iter() {
+            if let Some(ts) = state.last_update {
+                let _ = writeln!(
+                    out,
+                    "b5120_last_update_timestamp{{device=\"{}\"}} {:.3}",
+                    id,
+                    ts.timestamp_millis() as f64 / 1000.0
+                );
+            }
+        }
+
+        for (counter, help) in SESSION_COUNTERS {
+            let name = format!("b5120_session_{}_total", counter);
+            typed_header(&mut out, &name, help, "counter");
+            for (id, state) in devices.iter() {
+                if state.session.is_some() || !state.finished.is_empty() {
+                    let _ = writeln!(
+                        out,
+                        "{}{{device=\"{}\"}} {}",
+                        name,
+                        id,
+                        state.counter(counter)
+                    );
+                }
+            }
+        }
+
+        for (name, help) in FAMILIES {
+            header(&mut out, name, help);
+            for (id, state) in devices.iter() {
+                for register in Register::all() {
+                    let (family, label) = family(register);
+                    if family != *name {
+                        continue;
+                    }
+                    if let Some(value) = state.values.get(&register) {
+                        let _ = match label {
+                            Some((label, n)) => writeln!(
+                                out,
+                                "{}{{device=\"{}\",{}=\"{}\"}} {}",
+                                name, id, label, n, value
+                            ),
+                            None => writeln!(out, "{}{{device=\"{}\"}} {}", name, id, value),
+                        };
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    fn render_inverters(&self, out: &mut String) {
+        let inverters = self.inverters.lock().unwrap();
+        if inverters.is_empty() {
+            return;
+        }
+
+        let labels = |name: &str, state: &InverterState| match &state.site {
+            Some(site) => format!(
+                "inverter=\"{}\",site=\"{}\"",
+                escape_label(name),
+                escape_label(site)
+            ),
+            None => format!("inverter=\"{}\"", escape_label(name)),
+        };
+
+        header(
+            out,
+            "powermax_inverter_last_update_timestamp",
+            "Unix time in seconds of the last reading received from the inverter.",
+        );
+        for (name, state) in inverters.iter() {
+            if let Some(timestamp) = state.last_update() {
+                let _ = writeln!(
+                    out,
+                    "powermax_inverter_last_update_timestamp{{{}}} {:.3}",
+                    labels(name, state),
+                    timestamp.timestamp_millis() as f64 / 1000.0
+                );
+            }
+        }
+
+        header(
+            out,
+            "powermax_inverter_mode",
+            "Operating mode of the inverter, 1 for the current mode.",
+        );
+        for (name, state) in inverters.iter() {
+            let Some(current) = state.mode else {
+                continue;
+            };
+            for mode in InverterMode::ALL {
+                let _ = writeln!(
+                    out,
+                    "powermax_inverter_mode{{{},mode=\"{}\"}} {}",
+                    labels(name, state),
+                    mode.as_str(),
+                    u8::from(mode == current)
+                );
+            }
+        }
+
+        for kind in ["status", "rating", "warnings"] {
+            // Every reading of a kind has the same fields, so the first one
+            // gives the families.
+            let fields = match inverters
+                .values()
+                .find_map(|state| state.readings.get(kind))
+            {
+                Some(snapshot) => snapshot.reading.fields(),
+                None => continue,
+            };
+            for (field, value) in fields {
+                let (name, help) = match (value, power::unit(field)) {
+                    (FieldValue::Float(_), Some(unit)) if !unit.is_empty() => (
+                        format!("powermax_inverter_{}{}", field, unit_suffix(unit)),
+                        format!("Inverter {} in {}.", field.replace('_', " "), unit),
+                    ),
+                    (FieldValue::Float(_), _) => (
+                        format!("powermax_inverter_{}", field),
+                        format!("Inverter {}.", field.replace('_', " ")),
+                    ),
+                    (FieldValue::Bool(_), _) => (
+                        format!("powermax_inverter_{}", field),
+                        format!("Inverter {} flag, 1 when set.", field.replace('_', " ")),
+                    ),
+                };
+                header(out, &name, &help);
+                for (inverter, state) in inverters.iter() {
+                    let value = state.readings.get(kind).and_then(|snapshot| {
+                        snapshot
+                            .reading
+                            .fields()
+                            .into_iter()
+                            .find(|(f, _)| *f == field)
+                            .map(|(_, value)| value)
+                    });
+                    let value = match value {
+                        Some(FieldValue::Float(v)) => v,
+                        Some(FieldValue::Bool(v)) => f32::from(u8::from(v)),
+                        None => continue,
+                    };
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels(inverter, state), value);
+                }
+            }
+        }
+    }
+
+    fn render_flows(&self, out: &mut String) {
+        let flows = self.flows.lock().unwrap();
+        // Flows without pack currents lack `bms_battery_current`, so the
+        // families come from all of them.
+        let mut fields: Vec<&str> = Vec::new();
+        for flow in flows.values() {
+            for (field, _) in flow.fields() {
+                if !fields.contains(&field) {
+                    fields.push(field);
+                }
+            }
+        }
+
+        for field in fields {
+            let (name, help) = match field {
+                "current_mismatch" => (
+                    "powermax_site_current_mismatch".to_string(),
+                    "1 when inverter and packs disagree on the battery current.".to_string(),
+                ),
+                _ => {
+                    let unit = site::unit(field);
+                    (
+                        format!("powermax_site_{}{}", field, unit_suffix(unit)),
+                        format!("Site {} in {}.", field.replace('_', " "), unit),
+                    )
+                }
+            };
+            header(out, &name, &help);
+            for flow in flows.values() {
+                let value = flow
+                    .fields()
+                    .into_iter()
+                    .find(|(f, _)| *f == field)
+                    .map(|(_, value)| value);
+                let value = match value {
+                    Some(FieldValue::Float(v)) => v,
+                    Some(FieldValue::Bool(v)) => f32::from(u8::from(v)),
+                    None => continue,
+                };
+                let labels = match &flow.site {
+                    Some(site) => format!(
+                        "inverter=\"{}\",site=\"{}\"",
+                        escape_label(&flow.inverter),
+                        escape_label(site)
+                    ),
+                    None => format!("inverter=\"{}\"", escape_label(&flow.inverter)),
+                };
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    /// Serves `GET /metrics` on `listener` until the task is dropped.
+    pub async fn serve(self, listener: TcpListener) {
+        http::serve(listener, "metrics", &["GET"], move |request: Request| {
+            let metrics = self.clone();
+            async move {
+                match request.url.path() {
+                    "/metrics" => Response::new(
+                        "200 OK",
+                        "text/plain; version=0.0.4; charset=utf-8",
+                        metrics.render(),
+                    ),
+                    _ => Response::error("404 Not Found", "not found"),
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Sink for Metrics {
+    async fn handle(&mut self, event: &Event) {
+        let mut devices = self.devices.lock().unwrap();
+        match event {
+            Event::Connected {
+                device_id, device, ..
+            } => {
+                let state = devices.entry(*device_id).or_default();
+                state.info = device.clone();
+                state.connected = true;
+                state.session = None;
+            }
+            Event::Stats {
+                device_id, stats, ..
+            } => {
+                devices.entry(*device_id).or_default().session = Some(stats.clone());
+            }
+            Event::Snapshot(snapshot) => {
+                let state = devices.entry(snapshot.device_id).or_default();
+                state.info = snapshot.device.clone();
+                for register in Register::all() {
+                    if let Some(value) = snapshot.value(register) {
+                        state.values.insert(register, value);
+                        state.last_update = Some(snapshot.timestamp);
+                    }
+                }
+            }
+            Event::Disconnected {
+                device_id, stats, ..
+            } => {
+                let state = devices.entry(*device_id).or_default();
+                state.connected = false;
+                state.finish(stats);
+            }
+            Event::Inverter(snapshot) => {
+                let mut inverters = self.inverters.lock().unwrap();
+                let state = inverters.entry(snapshot.name.clone()).or_default();
+                state.site = snapshot.site.clone();
+                state
+                    .readings
+                    .insert(snapshot.reading.kind(), snapshot.clone());
+            }
+            Event::SiteFlow(flow) => {
+                self.flows
+                    .lock()
+                    .unwrap()
+                    .insert(flow.inverter.clone(), flow.clone());
+            }
+            Event::InverterMode { name, site, to, .. } => {
+                let mut inverters = self.inverters.lock().unwrap();
+                let state = inverters.entry(name.clone()).or_default();
+                state.site = site.clone();
+                state.mode = Some(*to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::PackSnapshot;
+    use crate::status::{BatteryStatus, PackConfig, PackStatus};
+
+    fn id() -> DeviceId {
+        "00:1b:2c:3d:4e:5f".parse().unwrap()
+    }
+
+    fn stats(requests: u64, timeouts: u64) -> SessionStats {
+        SessionStats {
+            requests,
+            ok: requests - timeouts,
+            timeouts,
+            ..SessionStats::default()
+        }
+    }
+
+    fn connected() -> Event {
+        Event::Connected {
+            device_id: id(),
+            device: None,
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn running(stats: SessionStats) -> Event {
+        Event::Stats {
+            device_id: id(),
+            stats,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_requests_across_sessions() {
+        let mut metrics = Metrics::new();
+        metrics.handle(&connected()).await;
+        metrics.handle(&running(stats(10, 1))).await;
+        metrics
+            .handle(&Event::Disconnected {
+                device_id: id(),
+                reason: None,
+                stats: stats(12, 2),
+                timestamp: Utc::now(),
+            })
+            .await;
+        metrics.handle(&connected()).await;
+        metrics.handle(&running(stats(5, 0))).await;
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE b5120_session_requests_total counter\n"));
+        assert!(out.contains("b5120_session_requests_total{device=\"00:1b:2c:3d:4e:5f\"} 17\n"));
+        assert!(out.contains("b5120_session_timeouts_total{device=\"00:1b:2c:3d:4e:5f\"} 2\n"));
+        assert!(out.contains("b5120_session_ok_total{device=\"00:1b:2c:3d:4e:5f\"} 15\n"));
+    }
+
+    #[tokio::test]
+    async fn exports_status_registers_raw() {
+        let mut snapshot = PackSnapshot::new(id(), Utc::now());
+        snapshot.pack_status = Some(PackStatus(0x0041));
+        snapshot.battery_status = Some(BatteryStatus(BatteryStatus::CHARGING));
+        snapshot.pack_config = Some(PackConfig(0x0150));
+
+        let mut metrics = Metrics::new();
+        metrics.handle(&Event::Snapshot(Arc::new(snapshot))).await;
+
+        let out = metrics.render();
+        assert!(out.contains("b5120_pack_status{device=\"00:1b:2c:3d:4e:5f\"} 65\n"));
+        assert!(out.contains("b5120_battery_status{device=\"00:1b:2c:3d:4e:5f\"} 1\n"));
+        assert!(out.contains("b5120_pack_config{device=\"00:1b:2c:3d:4e:5f\"} 336\n"));
+        // The bit assignments are unverified, so no flag is exported.
+        assert!(!out.contains("_flag"));
+    }
+}
@@ -0,0 +1,697 @@
+//! MQTT output with Home Assistant discovery.
+//!
+//! Topics, with `<node>` the device MAC as 12 hex digits:
+//!
+//! - `<prefix>/<node>/state`: every field of a snapshot as one JSON object
+//! - `<prefix>/<node>/<field>`: each field on its own, e.g. `cell_3`
+//! - `<prefix>/<node>/availability`: `online` while the device's TCP
+//!   session is up, `offline` after it ends (retained)
+//! - `<prefix>/gateway/availability`: `online` while the gateway is
+//!   connected to the broker, set to `offline` by its last will (retained)
+//! - `<prefix>/inverter/<name>/state` and `<prefix>/inverter/<name>/<field>`:
+//!   inverter readings, with `<name>` the configured name in lower case and
+//!   every character other than a letter or digit replaced by `_`
+//!
+//! Discovery configs go to `<discovery_prefix>/<component>/<node>/<field>/config`
+//! when a device connects, so Home Assistant picks up packs without manual
+//! setup.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Map, Value};
+
+use crate::device::DeviceId;
+use crate::power::{self, InverterMode, InverterReading, InverterSnapshot};
+use crate::register::{Register, Unit};
+use crate::registry::DeviceInfo;
+use crate::sink::{Event, Sink};
+use crate::site::{self, EnergyFlow};
+use crate::snapshot::{FieldValue, PackSnapshot};
+
+/// Requests that may wait for the event loop before publishing blocks.
+const CLIENT_CAPACITY: usize = 256;
+
+/// Pause before reconnecting after the broker connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Node id of a device in topics and Home Assistant ids.
+fn node_id(id: DeviceId) -> String {
+    hex::encode(id.mac())
+}
+
+/// Home Assistant device class of a sensor in `unit`.
+fn device_class(unit: Unit) -> Option<&'static str> {
+    match unit {
+        Unit::Millivolt => Some("voltage"),
+        Unit::Milliamp => Some("current"),
+        Unit::Celsius => Some("temperature"),
+        Unit::Percent => Some("battery"),
+        Unit::MilliampHour | Unit::None => None,
+    }
+}
+
+/// Unit of a pack field; counters and raw status registers have none.
+fn unit(field: &str) -> Unit {
+    if field == "state_of_health" {
+        return Unit::Percent;
+    }
+    Register::all()
+        .find(|register| register.field_name() == field)
+        .map_or(Unit::None, |register| register.unit())
+}
+
+/// Home Assistant device class of an inverter field in `unit`.
+fn inverter_device_class(field: &str, unit: &str) -> Option<&'static str> {
+    match unit {
+        "V" => Some("voltage"),
+        "Hz" => Some("frequency"),
+        "VA" => Some("apparent_power"),
+        "W" => Some("power"),
+        "A" => Some("current"),
+        "°C" => Some("temperature"),
+        "%" if field == "battery_capacity" => Some("battery"),
+        _ => None,
+    }
+}
+
+/// Home Assistant device block of an inverter.
+fn inverter_device(node: &str, name: &str, site: Option<&str>) -> Value {
+    json!({
+        "identifiers": [format!("powermax_inverter_{}", node)],
+        "name": name,
+        "manufacturer": "PowerMax",
+        "model": "Inverter",
+        "suggested_area": site,
+    })
+}
+
+/// Topic-safe form of an inverter name: `Inverter 1` -> `inverter_1`.
+/// Names are checked in the configuration not to share a slug.
+pub(crate) fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// `cell_3` -> `Cell 3`
+fn title(field: &str) -> String {
+    let mut title = field.replace('_', " ");
+    if let Some(first) = title.get_mut(..1) {
+        first.make_ascii_uppercase();
+    }
+    title
+}
+
+/// Sink that publishes snapshots and device availability to an MQTT
+/// broker.
+pub struct Mqtt {
+    client: AsyncClient,
+    prefix: String,
+    discovery_prefix: String,
+    /// `<inverter>/<kind>` of the inverter readings and modes whose
+    /// discovery configs were published.
+    announced: HashSet<String>,
+}
+
+impl Mqtt {
+    /// Connects to the broker in the background. `prefix` is the root of the
+    /// state topics, `discovery_prefix` that of Home Assistant discovery
+    /// (usually `homeassistant`).
+    pub fn spawn(mut options: MqttOptions, prefix: &str, discovery_prefix: &str) -> Self {
+        let gateway = format!("{}/gateway/availability", prefix);
+        options.set_last_will(LastWill::new(&gateway, "offline", QoS::AtLeastOnce, true));
+
+        let (client, eventloop) = AsyncClient::new(options, CLIENT_CAPACITY);
+        tokio::spawn(drive(eventloop, client.clone(), gateway));
+
+        Mqtt {
+            client,
+            prefix: prefix.to_string(),
+            discovery_prefix: discovery_prefix.to_string(),
+            announced: HashSet::new(),
+        }
+    }
+
+    fn topic(&self, id: DeviceId, leaf: &str) -> String {
+        format!("{}/{}/{}", self.prefix, node_id(id), leaf)
+    }
+
+    async fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+        if let Err(e) = self
+            .client
+            .publish(&topic, QoS::AtLeastOnce, retain, payload)
+            .await
+        {
+            eprintln!(
+                "{}: mqtt publish to {} failed. err = {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                topic,
+                e
+            );
+        }
+    }
+
+    /// Publishes the discovery config of every entity of a device.
+    async fn announce(&self, id: DeviceId, info: Option<&Arc<DeviceInfo>>) {
+        for (topic, config) in self.discovery(id, info) {
+            self.publish(topic, true, config.to_string()).await;
+        }
+    }
+
+    /// Discovery topic and config of every entity of a device.
+    fn discovery(&self, id: DeviceId, info: Option<&Arc<DeviceInfo>>) -> Vec<(String, Value)> {
+        let node = node_id(id);
+        let device = json!({
+            "identifiers": [format!("powermax_{}", node)],
+            "connections": [["mac", id.to_string()]],
+            "name": info.map(|i| i.name.clone()).unwrap_or_else(|| format!("PowerMax B5120 {}", id)),
+            "manufacturer": "PowerMax",
+            "model": "B5120",
+            "suggested_area": info.map(|i| i.site.clone()),
+        });
+        let availability = json!([
+            { "topic": format!("{}/gateway/availability", self.prefix) },
+            { "topic": self.topic(id, "availability") },
+        ]);
+
+        let mut configs = Vec::new();
+        for (field, value) in PackSnapshot::template(id, info.cloned()).fields() {
+            let mut config = json!({
+                "name": title(&field),
+                "unique_id": format!("powermax_{}_{}", node, field),
+                "state_topic": self.topic(id, &field),
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            });
+            let component = match value {
+                FieldValue::Float(_) => {
+                    let unit = unit(&field);
+                    if unit != Unit::None {
+                        config["unit_of_measurement"] = json!(unit.to_string());
+                    }
+                    config["state_class"] = json!(if field == "cycle_count" {
+                        "total_increasing"
+                    } else {
+                        "measurement"
+                    });
+                    // Health is a percentage, but not a charge level.
+                    if let Some(class) = device_class(unit).filter(|_| field != "state_of_health") {
+                        config["device_class"] = json!(class);
+                    }
+                    "sensor"
+                }
+                FieldValue::Bool(_) => {
+                    config["payload_on"] = json!("true");
+                    config["payload_off"] = json!("false");
+                    "binary_sensor"
+                }
+            };
+            configs.push((self.config_topic(component, &node, &field), config));
+        }
+        configs
+    }
+
+    fn config_topic(&self, component: &str, node: &str, field: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.discovery_prefix, component, node, field
+        )
+    }
+
+    async fn publish_config(&self, component: &str, node: &str, field: &str, config: Value) {
+        let topic = self.config_topic(component, node, field);
+        self.publish(topic, true, config.to_string()).await;
+    }
+
+    /// Publishes the discovery config of every field of the snapshot's
+    /// reading.
+    async fn announce_inverter(&self, node: &str, snapshot: &InverterSnapshot) {
+        let device = inverter_device(node, &snapshot.name, snapshot.site.as_deref());
+        let availability = format!("{}/gateway/availability", self.prefix);
+        let object = format!("inverter_{}", node);
+        let warnings = matches!(snapshot.reading, InverterReading::Warnings(_));
+
+        for (field, value) in snapshot.reading.fields() {
+            let mut config = json!({
+                "name": title(field),
+                "unique_id": format!("powermax_inverter_{}_{}", node, field),
+                "state_topic": format!("{}/inverter/{}/{}", self.prefix, node, field),
+                "availability_topic": availability,
+                "device": device,
+            });
+            let component = match value {
+                FieldValue::Float(_) => {
+                    let unit = power::unit(field).unwrap_or("");
+                    // Raw counters without a physical unit are not worth an entity.
+                    if unit.is_empty() || unit == "10mV" {
+                        continue;
+                    }
+                    config["unit_of_measurement"] = json!(unit);
+                    config["state_class"] = json!("measurement");
+                    if let Some(class) = inverter_device_class(field, unit) {
+                        config["device_class"] = json!(class);
+                    }
+                    "sensor"
+                }
+                FieldValue::Bool(_) => {
+                    config["payload_on"] = json!("true");
+                    config["payload_off"] = json!("false");
+                    if warnings {
+                        config["device_class"] = json!("problem");
+                    } else if field.ends_with("_charging") {
+                        config["device_class"] = json!("battery_charging");
+                    }
+                    "binary_sensor"
+                }
+            };
+            self.publish_config(component, &object, field, config).await;
+        }
+    }
+
+    /// Publishes the discovery config of the mode sensor.
+    async fn announce_mode(&self, node: &str, name: &str, site: Option<&str>) {
+        let config = json!({
+            "name": "Mode",
+            "unique_id": format!("powermax_inverter_{}_mode", node),
+            "state_topic": format!("{}/inverter/{}/mode", self.prefix, node),
+            "availability_topic": format!("{}/gateway/availability", self.prefix),
+            "device_class": "enum",
+            "options": InverterMode::ALL.iter().map(|mode| mode.as_str()).collect::<Vec<_>>(),
+            "device": inverter_device(node, name, site),
+        });
+        self.publish_config("sensor", &format!("inverter_{}", node), "mode", config)
+            .await;
+    }
+
+    /// Publishes the reading as JSON on `state` for status readings and on
+    /// `rating` or `warnings` otherwise, and every field on its own topic.
+    async fn publish_inverter(&self, node: &str, snapshot: &InverterSnapshot) {
+        let fields = snapshot.reading.fields();
+
+        let mut state = Map::new();
+        state.insert(
+            "timestamp".to_string(),
+            json!(snapshot
+                .timestamp
+                .to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        if let InverterReading::Status(status) = &snapshot.reading {
+            state.insert(
+                "charging_status".to_string(),
+                json!(status.charging_status.to_string()),
+            );
+        }
+        for (field, value) in &fields {
+            let value = match value {
+                FieldValue::Float(v) => json!(v),
+                FieldValue::Bool(v) => json!(v),
+            };
+            state.insert(field.to_string(), value);
+        }
+        let topic = match &snapshot.reading {
+            InverterReading::Status(_) => "state",
+            reading => reading.kind(),
+        };
+        self.publish(
+            format!("{}/inverter/{}/{}", self.prefix, node, topic),
+            false,
+            Value::Object(state).to_string(),
+        )
+        .await;
+
+        for (field, value) in &fields {
+            self.publish(
+                format!("{}/inverter/{}/{}", self.prefix, node, field),
+                false,
+                value.to_string(),
+            )
+            .await;
+        }
+    }
+
+    /// Publishes the discovery config of every energy-flow figure, on the
+    /// inverter's device.
+    async fn announce_flow(&self, node: &str, flow: &EnergyFlow) {
+        let device = inverter_device(node, &flow.inverter, flow.site.as_deref());
+        let object = format!("inverter_{}", node);
+
+        // The BMS current is announced even while no pack reports it.
+        let mut fields: Vec<&str> = flow.fields().into_iter().map(|(f, _)| f).collect();
+        if !fields.contains(&"bms_battery_current") {
+            fields.push("bms_battery_current");
+        }
+        for field in fields {
+            let mut config = json!({
+                "name": title(field),
+                "unique_id": format!("powermax_site_{}_{}", node, field),
+                "state_topic": format!("{}/site/{}/{}", self.prefix, node, field),
+                "availability_topic": format!("{}/gateway/availability", self.prefix),
+                "device": device,
+            });
+            let component = if field == "current_mismatch" {
+                config["payload_on"] = json!("true");
+                config["payload_off"] = json!("false");
+                config["device_class"] = json!("problem");
+                "binary_sensor"
+            } else {
+                let unit = site::unit(field);
+                config["unit_of_measurement"] = json!(unit);
+                config["state_class"] = json!("measurement");
+                config["device_class"] = json!(if unit == "A" { "current" } else { "power" });
+                "sensor"
+            };
+            self.publish_config(component, &object, field, config).await;
+        }
+    }
+
+    /// Publishes the flow as JSON on `<prefix>/site/<inverter>/flow` and
+    /// every figure on its own topic.
+    async fn publish_flow(&self, node: &str, flow: &EnergyFlow) {
+        let fields = flow.fields();
+
+        let mut state = Map::new();
+        state.insert(
+            "timestamp".to_string(),
+            json!(flow.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        state.insert(
+            "packs".to_string(),
+            json!(flow
+                .packs
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()),
+        );
+        for (field, value) in &fields {
+            let value = match value {
+                FieldValue::Float(v) => json!(v),
+                FieldValue::Bool(v) => json!(v),
+            };
+            state.insert(field.to_string(), value);
+        }
+        self.publish(
+            format!("{}/site/{}/flow", self.prefix, node),
+            false,
+            Value::Object(state).to_string(),
+        )
+        .await;
+
+        for (field, value) in &fields {
+            self.publish(
+                format!("{}/site/{}/{}", self.prefix, node, field),
+                false,
+                value.to_string(),
+            )
+            .await;
+        }
+    }
+
+    async fn publish_snapshot(&self, snapshot: &PackSnapshot) {
+        for (topic, payload) in self.snapshot_messages(snapshot) {
+            self.publish(topic, false, payload).await;
+        }
+    }
+
+    /// The JSON state of a snapshot followed by each field on its own
+    /// topic; nothing if no field was read.
+    fn snapshot_messages(&self, snapshot: &PackSnapshot) -> Vec<(String, String)> {
+        let fields = snapshot.fields();
+        if fields.is_empty() {
+            return Vec::new();
+        }
+
+        let mut state = Map::new();
+        state.insert(
+            "timestamp".to_string(),
+            json!(snapshot
+                .timestamp
+                .to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        for (field, value) in &fields {
+            let value = match value {
+                FieldValue::Float(v) => json!(v),
+                FieldValue::Bool(v) => json!(v),
+            };
+            state.insert(field.clone(), value);
+        }
+
+        let mut messages = vec![(
+            self.topic(snapshot.device_id, "state"),
+            Value::Object(state).to_string(),
+        )];
+        for (field, value) in &fields {
+            messages.push((self.topic(snapshot.device_id, field), value.to_string()));
+        }
+        messages
+    }
+}
+
+impl Sink for Mqtt {
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Connected {
+                device_id, device, ..
+            } => {
+                self.announce(*device_id, device.as_ref()).await;
+                self.publish(self.topic(*device_id, "availability"), true, "online")
+                    .await;
+            }
+            Event::Snapshot(snapshot) => self.publish_snapshot(snapshot).await,
+            Event::Disconnected { device_id, .. } => {
+                self.publish(self.topic(*device_id, "availability"), true, "offline")
+                    .await;
+            }
+            Event::Stats { .. } => (),
+            Event::Inverter(snapshot) => {
+                let node = slug(&snapshot.name);
+                if self
+                    .announced
+                    .insert(format!("{}/{}", node, snapshot.reading.kind()))
+                {
+                    self.announce_inverter(&node, snapshot).await;
+                }
+                self.publish_inverter(&node, snapshot).await;
+            }
+            Event::SiteFlow(flow) => {
+                let node = slug(&flow.inverter);
+                if self.announced.insert(format!("{}/flow", node)) {
+                    self.announce_flow(&node, flow).await;
+                }
+                self.publish_flow(&node, flow).await;
+            }
+            Event::InverterMode { name, site, to, .. } => {
+                let node = slug(name);
+                if self.announced.insert(format!("{}/mode", node)) {
+                    self.announce_mode(&node, name, site.as_deref()).await;
+                }
+                self.publish(
+                    format!("{}/inverter/{}/mode", self.prefix, node),
+                    true,
+                    to.as_str(),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Runs the MQTT event loop, reconnecting after failures, and marks the
+/// gateway online on every connect.
+async fn drive(mut eventloop: EventLoop, client: AsyncClient, gateway: String) {
+    // Only the first failure of an outage is logged.
+    let mut failing = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                println!(
+                    "{} connected to mqtt broker",
+                    Local::now().format("%Y-%m-%d %H:%M:%S")
+                );
+                failing = false;
+                if let Err(e) = client.try_publish(&gateway, QoS::AtLeastOnce, true, "online") {
+                    eprintln!(
+                        "{}: mqtt publish to {} failed. err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        gateway,
+                        e
+                    );
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                if !failing {
+                    eprintln!(
+                        "{}: mqtt connection failed, retrying every {:?}. err = {}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        RECONNECT_DELAY,
+                        e
+                    );
+                    failing = true;
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::Reading;
+
+    fn mqtt() -> Mqtt {
+        // The event loop is never polled; the tests only build messages.
+        let (client, _) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 16);
+        Mqtt {
+            client,
+            prefix: "powermax".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            announced: HashSet::new(),
+        }
+    }
+
+    fn id() -> DeviceId {
+        "00:1b:2c:3d:4e:5f".parse().unwrap()
+    }
+
+    fn config<'a>(configs: &'a [(String, Value)], topic: &str) -> &'a Value {
+        &configs
+            .iter()
+            .find(|(t, _)| t == topic)
+            .unwrap_or_else(|| panic!("no config on {}", topic))
+            .1
+    }
+
+    #[test]
+    fn slugs_inverter_names() {
+        assert_eq!(slug("Inverter 1"), "inverter_1");
+        assert_eq!(slug("inverter-1"), "inverter_1");
+        assert_eq!(slug("garage"), "garage");
+    }
+
+    #[test]
+    fn announces_every_field_of_the_pack() {
+        let info = Arc::new(DeviceInfo {
+            name: "Pack 1".to_string(),
+            site: "north-depot".to_string(),
+            position: None,
+            cell_count: Some(4),
+            design_capacity_mah: Some(100_000),
+            inverter: None,
+            driver: None,
+            min_command_gap_ms: None,
+        });
+        let configs = mqtt().discovery(id(), Some(&info));
+
+        let topics: Vec<&str> = configs.iter().map(|(t, _)| t.as_str()).collect();
+        assert!(topics.contains(&"homeassistant/sensor/001b2c3d4e5f/cell_4/config"));
+        assert!(!topics.contains(&"homeassistant/sensor/001b2c3d4e5f/cell_5/config"));
+
+        let cell = config(&configs, "homeassistant/sensor/001b2c3d4e5f/cell_1/config");
+        assert_eq!(
+            *cell,
+            json!({
+                "name": "Cell 1",
+                "unique_id": "powermax_001b2c3d4e5f_cell_1",
+                "state_topic": "powermax/001b2c3d4e5f/cell_1",
+                "availability": [
+                    { "topic": "powermax/gateway/availability" },
+                    { "topic": "powermax/001b2c3d4e5f/availability" },
+                ],
+                "availability_mode": "all",
+                "device": {
+                    "identifiers": ["powermax_001b2c3d4e5f"],
+                    "connections": [["mac", "00:1b:2c:3d:4e:5f"]],
+                    "name": "Pack 1",
+                    "manufacturer": "PowerMax",
+                    "model": "B5120",
+                    "suggested_area": "north-depot",
+                },
+                "unit_of_measurement": "mV",
+                "state_class": "measurement",
+                "device_class": "voltage",
+            })
+        );
+
+        let cycles = config(
+            &configs,
+            "homeassistant/sensor/001b2c3d4e5f/cycle_count/config",
+        );
+        assert_eq!(cycles["state_class"], "total_increasing");
+        assert!(cycles.get("unit_of_measurement").is_none());
+
+        let health = config(
+            &configs,
+            "homeassistant/sensor/001b2c3d4e5f/state_of_health/config",
+        );
+        assert_eq!(health["unit_of_measurement"], "%");
+        assert!(health.get("device_class").is_none());
+        assert_eq!(
+            config(&configs, "homeassistant/sensor/001b2c3d4e5f/rsoc/config")["device_class"],
+            "battery"
+        );
+    }
+
+    #[test]
+    fn names_unknown_packs_by_address() {
+        let configs = mqtt().discovery(id(), None);
+        let current = config(&configs, "homeassistant/sensor/001b2c3d4e5f/current/config");
+        assert_eq!(
+            current["device"]["name"],
+            "PowerMax B5120 00:1b:2c:3d:4e:5f"
+        );
+        assert_eq!(current["device"]["suggested_area"], Value::Null);
+        assert_eq!(current["unit_of_measurement"], "mA");
+        assert!(configs
+            .iter()
+            .all(|(topic, _)| !topic.ends_with("/state_of_health/config")));
+    }
+
+    #[test]
+    fn publishes_state_then_each_field() {
+        let timestamp = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        let mut snapshot = PackSnapshot::new(id(), timestamp);
+        assert!(mqtt().snapshot_messages(&snapshot).is_empty());
+
+        snapshot.apply(&Reading {
+            register: Register::Current,
+            raw: -1500,
+        });
+        snapshot.apply(&Reading {
+            register: Register::Rsoc,
+            raw: 87,
+        });
+        let messages = mqtt().snapshot_messages(&snapshot);
+
+        assert_eq!(messages[0].0, "powermax/001b2c3d4e5f/state");
+        assert_eq!(
+            serde_json::from_str::<Value>(&messages[0].1).unwrap(),
+            json!({
+                "timestamp": "2023-11-14T22:13:20.123Z",
+                "current": -1500.0,
+                "rsoc": 87.0,
+            })
+        );
+        assert_eq!(
+            messages[1..],
+            [
+                (
+                    "powermax/001b2c3d4e5f/current".to_string(),
+                    "-1500".to_string()
+                ),
+                ("powermax/001b2c3d4e5f/rsoc".to_string(), "87".to_string()),
+            ]
+        );
+    }
+}
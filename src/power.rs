@@ -0,0 +1,969 @@
+//! Inverter state reported by `QPIGS` (general status parameters), `QPIRI`
+//! (rated information), `QPIWS` (warning status) and `QMOD` (device mode).
+//!
+//! The `QPIGS` response is a space-separated record:
+//!
+//! ```text
+//! BBB.B CC.C DDD.D EE.E FFFF GGGG HHH III JJ.JJ KKK OOO TTTT EEEE UUU.U WW.WW PPPPP b7..b0 QQ VV MMMMM b10b9b8
+//! ```
+//!
+//! Older firmware stops after the `b7..b0` status bits; the fields after it
+//! are then zero.
+
+use std::fmt;
+
+use chrono::prelude::*;
+
+use crate::snapshot::FieldValue;
+use crate::voltronic::InverterError;
+
+/// Source the battery is being charged from, bits b2..b0 of the device
+/// status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargingStatus {
+    Scc,
+    Ac,
+    SccAc,
+    DoNothing,
+}
+
+impl ChargingStatus {
+    /// Decodes bits b2 (charging on), b1 (SCC charging) and b0 (AC
+    /// charging).
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0b110 => ChargingStatus::Scc,
+            0b101 => ChargingStatus::Ac,
+            0b111 => ChargingStatus::SccAc,
+            _ => ChargingStatus::DoNothing,
+        }
+    }
+
+    pub fn scc(&self) -> bool {
+        matches!(self, ChargingStatus::Scc | ChargingStatus::SccAc)
+    }
+
+    pub fn ac(&self) -> bool {
+        matches!(self, ChargingStatus::Ac | ChargingStatus::SccAc)
+    }
+}
+
+impl fmt::Display for ChargingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChargingStatus::Scc => "SCC",
+            ChargingStatus::Ac => "AC",
+            ChargingStatus::SccAc => "SCC and AC",
+            ChargingStatus::DoNothing => "not charging",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerStatus {
+    pub grid_voltage: f32,
+    pub grid_frequency: f32,
+    pub ac_output_voltage: f32,
+    pub ac_output_frequency: f32,
+    pub ac_output_apparent_power: u32,
+    pub ac_output_active_power: u32,
+    pub output_load_percent: u32,
+    pub bus_voltage: u32,
+    pub battery_voltage: f32,
+    pub battery_charging_current: u32,
+    pub battery_capacity: u32,
+    /// Negative below freezing, e.g. `-010`.
+    pub inverter_heat_sink_temperature: i32,
+    pub pv_input_current: u32,
+    pub pv_input_voltage: f32,
+    pub battery_voltage_from_scc: f32,
+    pub battery_discharge_current: u32,
+    pub sbu_priority: bool,
+    pub configuration_status: bool,
+    pub scc_firmware_version_status: bool,
+    pub load_status: bool,
+    pub charging_status: ChargingStatus,
+    /// In units of 10 mV.
+    pub battery_voltage_offset_for_fans_on: u32,
+    pub eeprom_version: u32,
+    pub pv_charging_power: u32,
+    pub charging_in_floating_mode: bool,
+    pub switch_on: bool,
+}
+
+/// Unit of every numeric field of `PowerStatus::fields`.
+pub const UNITS: &[(&str, &str)] = &[
+    ("grid_voltage", "V"),
+    ("grid_frequency", "Hz"),
+    ("ac_output_voltage", "V"),
+    ("ac_output_frequency", "Hz"),
+    ("ac_output_apparent_power", "VA"),
+    ("ac_output_active_power", "W"),
+    ("output_load_percent", "%"),
+    ("bus_voltage", "V"),
+    ("battery_voltage", "V"),
+    ("battery_charging_current", "A"),
+    ("battery_capacity", "%"),
+    ("inverter_heat_sink_temperature", "°C"),
+    ("pv_input_current", "A"),
+    ("pv_input_voltage", "V"),
+    ("battery_voltage_from_scc", "V"),
+    ("battery_discharge_current", "A"),
+    ("battery_voltage_offset_for_fans_on", "10mV"),
+    ("eeprom_version", ""),
+    ("pv_charging_power", "W"),
+    ("grid_rating_voltage", "V"),
+    ("grid_rating_current", "A"),
+    ("ac_output_rating_voltage", "V"),
+    ("ac_output_rating_frequency", "Hz"),
+    ("ac_output_rating_current", "A"),
+    ("ac_output_rating_apparent_power", "VA"),
+    ("ac_output_rating_active_power", "W"),
+    ("battery_rating_voltage", "V"),
+    ("battery_recharge_voltage", "V"),
+    ("battery_under_voltage", "V"),
+    ("battery_bulk_voltage", "V"),
+    ("battery_float_voltage", "V"),
+    ("battery_type", ""),
+    ("max_ac_charging_current", "A"),
+    ("max_charging_current", "A"),
+    ("input_voltage_range", ""),
+    ("output_source_priority", ""),
+    ("charger_source_priority", ""),
+    ("parallel_max_num", ""),
+    ("machine_type", ""),
+    ("output_mode", ""),
+    ("battery_redischarge_voltage", "V"),
+];
+
+/// Unit of a numeric field, `None` for flags.
+pub fn unit(field: &str) -> Option<&'static str> {
+    UNITS
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, unit)| *unit)
+}
+
+/// Parses field `index` of a record.
+fn field<T: std::str::FromStr>(
+    fields: &[&str],
+    index: usize,
+    name: &str,
+) -> Result<T, InverterError> {
+    let raw = fields
+        .get(index)
+        .ok_or_else(|| InverterError::Malformed(format!("{} is missing", name)))?;
+    raw.parse()
+        .map_err(|_| InverterError::Malformed(format!("{} `{}` is not a number", name, raw)))
+}
+
+/// Bits of a `b7b6..` style field, most significant first.
+fn bits(fields: &[&str], index: usize, name: &str) -> Result<Vec<bool>, InverterError> {
+    let raw = fields
+        .get(index)
+        .ok_or_else(|| InverterError::Malformed(format!("{} is missing", name)))?;
+    raw.chars()
+        .map(|c| match c {
+            '0' => Ok(false),
+            '1' => Ok(true),
+            _ => Err(InverterError::Malformed(format!(
+                "{} `{}` is not a bit string",
+                name, raw
+            ))),
+        })
+        .collect()
+}
+
+impl PowerStatus {
+    pub const COMMAND: &'static str = "QPIGS";
+
+    /// Parses a `QPIGS` response payload.
+    pub fn parse(payload: &str) -> Result<Self, InverterError> {
+        let f: Vec<&str> = payload.split_whitespace().collect();
+
+        let status = bits(&f, 16, "device status")?;
+        if status.len() != 8 {
+            return Err(InverterError::Malformed(format!(
+                "device status has {} bits, expected 8",
+                status.len()
+            )));
+        }
+        let charging = status[5..]
+            .iter()
+            .fold(0u8, |acc, &bit| (acc << 1) | u8::from(bit));
+
+        // Fields after the status bits are missing on older firmware.
+        let optional = |index, name| -> Result<u32, InverterError> {
+            if f.len() > index {
+                field(&f, index, name)
+            } else {
+                Ok(0)
+            }
+        };
+        let status2 = if f.len() > 20 {
+            bits(&f, 20, "device status 2")?
+        } else {
+            Vec::new()
+        };
+
+        Ok(PowerStatus {
+            grid_voltage: field(&f, 0, "grid voltage")?,
+            grid_frequency: field(&f, 1, "grid frequency")?,
+            ac_output_voltage: field(&f, 2, "AC output voltage")?,
+            ac_output_frequency: field(&f, 3, "AC output frequency")?,
+            ac_output_apparent_power: field(&f, 4, "AC output apparent power")?,
+            ac_output_active_power: field(&f, 5, "AC output active power")?,
+            output_load_percent: field(&f, 6, "output load percent")?,
+            bus_voltage: field(&f, 7, "bus voltage")?,
+            battery_voltage: field(&f, 8, "battery voltage")?,
+            battery_charging_current: field(&f, 9, "battery charging current")?,
+            battery_capacity: field(&f, 10, "battery capacity")?,
+            inverter_heat_sink_temperature: field(&f, 11, "heat sink temperature")?,
+            pv_input_current: field(&f, 12, "PV input current")?,
+            pv_input_voltage: field(&f, 13, "PV input voltage")?,
+            battery_voltage_from_scc: field(&f, 14, "SCC battery voltage")?,
+            battery_discharge_current: field(&f, 15, "battery discharge current")?,
+            sbu_priority: status[0],
+            configuration_status: status[1],
+            scc_firmware_version_status: status[2],
+            load_status: status[3],
+            charging_status: ChargingStatus::from_bits(charging),
+            battery_voltage_offset_for_fans_on: optional(17, "fan voltage offset")?,
+            eeprom_version: optional(18, "EEPROM version")?,
+            pv_charging_power: optional(19, "PV charging power")?,
+            charging_in_floating_mode: status2.first().copied().unwrap_or(false),
+            switch_on: status2.get(1).copied().unwrap_or(false),
+        })
+    }
+
+    /// `(field name, value)` of every field; the charging status is split
+    /// into its SCC and AC flags.
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        let float = |v: f32| FieldValue::Float(v);
+        let int = |v: u32| FieldValue::Float(v as f32);
+        vec![
+            ("grid_voltage", float(self.grid_voltage)),
+            ("grid_frequency", float(self.grid_frequency)),
+            ("ac_output_voltage", float(self.ac_output_voltage)),
+            ("ac_output_frequency", float(self.ac_output_frequency)),
+            (
+                "ac_output_apparent_power",
+                int(self.ac_output_apparent_power),
+            ),
+            ("ac_output_active_power", int(self.ac_output_active_power)),
+            ("output_load_percent", int(self.output_load_percent)),
+            ("bus_voltage", int(self.bus_voltage)),
+            ("battery_voltage", float(self.battery_voltage)),
+            (
+                "battery_charging_current",
+                int(self.battery_charging_current),
+            ),
+            ("battery_capacity", int(self.battery_capacity)),
+            (
+                "inverter_heat_sink_temperature",
+                FieldValue::Float(self.inverter_heat_sink_temperature as f32),
+            ),
+            ("pv_input_current", int(self.pv_input_current)),
+            ("pv_input_voltage", float(self.pv_input_voltage)),
+            (
+                "battery_voltage_from_scc",
+                float(self.battery_voltage_from_scc),
+            ),
+            (
+                "battery_discharge_current",
+                int(self.battery_discharge_current),
+            ),
+            (
+                "battery_voltage_offset_for_fans_on",
+                int(self.battery_voltage_offset_for_fans_on),
+            ),
+            ("eeprom_version", int(self.eeprom_version)),
+            ("pv_charging_power", int(self.pv_charging_power)),
+            ("sbu_priority", FieldValue::Bool(self.sbu_priority)),
+            (
+                "configuration_status",
+                FieldValue::Bool(self.configuration_status),
+            ),
+            (
+                "scc_firmware_version_status",
+                FieldValue::Bool(self.scc_firmware_version_status),
+            ),
+            ("load_status", FieldValue::Bool(self.load_status)),
+            ("scc_charging", FieldValue::Bool(self.charging_status.scc())),
+            ("ac_charging", FieldValue::Bool(self.charging_status.ac())),
+            (
+                "charging_in_floating_mode",
+                FieldValue::Bool(self.charging_in_floating_mode),
+            ),
+            ("switch_on", FieldValue::Bool(self.switch_on)),
+        ]
+    }
+}
+
+/// Parses a field holding one of the codes of an enum. Codes the enum does
+/// not name are kept as its `Other` value, since firmware keeps adding them.
+fn code<T>(
+    fields: &[&str],
+    index: usize,
+    name: &str,
+    from_code: fn(u8) -> T,
+) -> Result<T, InverterError> {
+    field(fields, index, name).map(from_code)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryType {
+    Agm,
+    Flooded,
+    User,
+    /// A code this version does not know.
+    Other(u8),
+}
+
+impl BatteryType {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => BatteryType::Agm,
+            1 => BatteryType::Flooded,
+            2 => BatteryType::User,
+            other => BatteryType::Other(other),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            BatteryType::Agm => 0,
+            BatteryType::Flooded => 1,
+            BatteryType::User => 2,
+            BatteryType::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for BatteryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BatteryType::Agm => "AGM",
+            BatteryType::Flooded => "flooded",
+            BatteryType::User => "user",
+            BatteryType::Other(code) => return write!(f, "unknown ({})", code),
+        })
+    }
+}
+
+/// AC input voltage window the inverter accepts as grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputVoltageRange {
+    Appliance,
+    Ups,
+    /// A code this version does not know.
+    Other(u8),
+}
+
+impl InputVoltageRange {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => InputVoltageRange::Appliance,
+            1 => InputVoltageRange::Ups,
+            other => InputVoltageRange::Other(other),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            InputVoltageRange::Appliance => 0,
+            InputVoltageRange::Ups => 1,
+            InputVoltageRange::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for InputVoltageRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputVoltageRange::Appliance => "appliance",
+            InputVoltageRange::Ups => "UPS",
+            InputVoltageRange::Other(code) => return write!(f, "unknown ({})", code),
+        })
+    }
+}
+
+/// Source the load is supplied from first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSourcePriority {
+    Utility,
+    Solar,
+    Sbu,
+    /// A code this version does not know.
+    Other(u8),
+}
+
+impl OutputSourcePriority {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => OutputSourcePriority::Utility,
+            1 => OutputSourcePriority::Solar,
+            2 => OutputSourcePriority::Sbu,
+            other => OutputSourcePriority::Other(other),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            OutputSourcePriority::Utility => 0,
+            OutputSourcePriority::Solar => 1,
+            OutputSourcePriority::Sbu => 2,
+            OutputSourcePriority::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for OutputSourcePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputSourcePriority::Utility => "utility first",
+            OutputSourcePriority::Solar => "solar first",
+            OutputSourcePriority::Sbu => "SBU first",
+            OutputSourcePriority::Other(code) => return write!(f, "unknown ({})", code),
+        })
+    }
+}
+
+/// Source the battery is charged from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargerSourcePriority {
+    Utility,
+    Solar,
+    SolarAndUtility,
+    OnlySolar,
+    /// A code this version does not know.
+    Other(u8),
+}
+
+impl ChargerSourcePriority {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => ChargerSourcePriority::Utility,
+            1 => ChargerSourcePriority::Solar,
+            2 => ChargerSourcePriority::SolarAndUtility,
+            3 => ChargerSourcePriority::OnlySolar,
+            other => ChargerSourcePriority::Other(other),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            ChargerSourcePriority::Utility => 0,
+            ChargerSourcePriority::Solar => 1,
+            ChargerSourcePriority::SolarAndUtility => 2,
+            ChargerSourcePriority::OnlySolar => 3,
+            ChargerSourcePriority::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ChargerSourcePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChargerSourcePriority::Utility => "utility first",
+            ChargerSourcePriority::Solar => "solar first",
+            ChargerSourcePriority::SolarAndUtility => "solar and utility",
+            ChargerSourcePriority::OnlySolar => "only solar",
+            ChargerSourcePriority::Other(code) => return write!(f, "unknown ({})", code),
+        })
+    }
+}
+
+/// Ratings and settings reported by `QPIRI`:
+///
+/// ```text
+/// BBB.B CC.C DDD.D EE.E FF.F HHHH IIII JJ.J KK.K JJ.J KK.K LL.L O PP QQ0 O P Q R SS T U VV.V W X
+/// ```
+///
+/// The last three fields are missing on older firmware and are then zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InverterRating {
+    pub grid_rating_voltage: f32,
+    pub grid_rating_current: f32,
+    pub ac_output_rating_voltage: f32,
+    pub ac_output_rating_frequency: f32,
+    pub ac_output_rating_current: f32,
+    pub ac_output_rating_apparent_power: u32,
+    pub ac_output_rating_active_power: u32,
+    pub battery_rating_voltage: f32,
+    pub battery_recharge_voltage: f32,
+    pub battery_under_voltage: f32,
+    pub battery_bulk_voltage: f32,
+    pub battery_float_voltage: f32,
+    pub battery_type: BatteryType,
+    pub max_ac_charging_current: u32,
+    pub max_charging_current: u32,
+    pub input_voltage_range: InputVoltageRange,
+    pub output_source_priority: OutputSourcePriority,
+    pub charger_source_priority: ChargerSourcePriority,
+    pub parallel_max_num: u32,
+    /// 0 grid tie, 1 off grid, 10 hybrid.
+    pub machine_type: u32,
+    pub transformer: bool,
+    /// 0 single machine, 1 parallel, 2..4 phase 1..3 of a three-phase
+    /// system.
+    pub output_mode: u32,
+    pub battery_redischarge_voltage: f32,
+    pub pv_ok_condition_for_parallel: bool,
+    pub pv_power_balance: bool,
+}
+
+impl InverterRating {
+    pub const COMMAND: &'static str = "QPIRI";
+
+    /// Parses a `QPIRI` response payload.
+    pub fn parse(payload: &str) -> Result<Self, InverterError> {
+        let f: Vec<&str> = payload.split_whitespace().collect();
+
+        let optional = |index, name| -> Result<u32, InverterError> {
+            if f.len() > index {
+                field(&f, index, name)
+            } else {
+                Ok(0)
+            }
+        };
+
+        Ok(InverterRating {
+            grid_rating_voltage: field(&f, 0, "grid rating voltage")?,
+            grid_rating_current: field(&f, 1, "grid rating current")?,
+            ac_output_rating_voltage: field(&f, 2, "AC output rating voltage")?,
+            ac_output_rating_frequency: field(&f, 3, "AC output rating frequency")?,
+            ac_output_rating_current: field(&f, 4, "AC output rating current")?,
+            ac_output_rating_apparent_power: field(&f, 5, "AC output rating apparent power")?,
+            ac_output_rating_active_power: field(&f, 6, "AC output rating active power")?,
+            battery_rating_voltage: field(&f, 7, "battery rating voltage")?,
+            battery_recharge_voltage: field(&f, 8, "battery re-charge voltage")?,
+            battery_under_voltage: field(&f, 9, "battery under voltage")?,
+            battery_bulk_voltage: field(&f, 10, "battery bulk voltage")?,
+            battery_float_voltage: field(&f, 11, "battery float voltage")?,
+            battery_type: code(&f, 12, "battery type", BatteryType::from_code)?,
+            max_ac_charging_current: field(&f, 13, "max AC charging current")?,
+            max_charging_current: field(&f, 14, "max charging current")?,
+            input_voltage_range: code(&f, 15, "input voltage range", InputVoltageRange::from_code)?,
+            output_source_priority: code(
+                &f,
+                16,
+                "output source priority",
+                OutputSourcePriority::from_code,
+            )?,
+            charger_source_priority: code(
+                &f,
+                17,
+                "charger source priority",
+                ChargerSourcePriority::from_code,
+            )?,
+            parallel_max_num: field(&f, 18, "parallel max num")?,
+            machine_type: field(&f, 19, "machine type")?,
+            transformer: field::<u8>(&f, 20, "topology")? == 1,
+            output_mode: field(&f, 21, "output mode")?,
+            battery_redischarge_voltage: if f.len() > 22 {
+                field(&f, 22, "battery re-discharge voltage")?
+            } else {
+                0.0
+            },
+            pv_ok_condition_for_parallel: optional(23, "PV OK condition")? == 1,
+            pv_power_balance: optional(24, "PV power balance")? == 1,
+        })
+    }
+
+    /// `(field name, value)` of every field; settings with named values are
+    /// given as their numeric code.
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        let float = |v: f32| FieldValue::Float(v);
+        let int = |v: u32| FieldValue::Float(v as f32);
+        let code = |v: u8| FieldValue::Float(f32::from(v));
+        vec![
+            ("grid_rating_voltage", float(self.grid_rating_voltage)),
+            ("grid_rating_current", float(self.grid_rating_current)),
+            (
+                "ac_output_rating_voltage",
+                float(self.ac_output_rating_voltage),
+            ),
+            (
+                "ac_output_rating_frequency",
+                float(self.ac_output_rating_frequency),
+            ),
+            (
+                "ac_output_rating_current",
+                float(self.ac_output_rating_current),
+            ),
+            (
+                "ac_output_rating_apparent_power",
+                int(self.ac_output_rating_apparent_power),
+            ),
+            (
+                "ac_output_rating_active_power",
+                int(self.ac_output_rating_active_power),
+            ),
+            ("battery_rating_voltage", float(self.battery_rating_voltage)),
+            (
+                "battery_recharge_voltage",
+                float(self.battery_recharge_voltage),
+            ),
+            ("battery_under_voltage", float(self.battery_under_voltage)),
+            ("battery_bulk_voltage", float(self.battery_bulk_voltage)),
+            ("battery_float_voltage", float(self.battery_float_voltage)),
+            ("battery_type", code(self.battery_type.code())),
+            ("max_ac_charging_current", int(self.max_ac_charging_current)),
+            ("max_charging_current", int(self.max_charging_current)),
+            ("input_voltage_range", code(self.input_voltage_range.code())),
+            (
+                "output_source_priority",
+                code(self.output_source_priority.code()),
+            ),
+            (
+                "charger_source_priority",
+                code(self.charger_source_priority.code()),
+            ),
+            ("parallel_max_num", int(self.parallel_max_num)),
+            ("machine_type", int(self.machine_type)),
+            ("transformer", FieldValue::Bool(self.transformer)),
+            ("output_mode", int(self.output_mode)),
+            (
+                "battery_redischarge_voltage",
+                float(self.battery_redischarge_voltage),
+            ),
+            (
+                "pv_ok_condition_for_parallel",
+                FieldValue::Bool(self.pv_ok_condition_for_parallel),
+            ),
+            ("pv_power_balance", FieldValue::Bool(self.pv_power_balance)),
+        ]
+    }
+}
+
+/// Name of every `QPIWS` bit, by position; unused bits have no name.
+const WARNINGS: &[Option<&str>] = &[
+    None,
+    Some("inverter_fault"),
+    Some("bus_over"),
+    Some("bus_under"),
+    Some("bus_soft_fail"),
+    Some("line_fail"),
+    Some("opv_short"),
+    Some("inverter_voltage_too_low"),
+    Some("inverter_voltage_too_high"),
+    Some("over_temperature"),
+    Some("fan_locked"),
+    Some("battery_voltage_high"),
+    Some("battery_low_alarm"),
+    None,
+    Some("battery_under_shutdown"),
+    None,
+    Some("over_load"),
+    Some("eeprom_fault"),
+    Some("inverter_over_current"),
+    Some("inverter_soft_fail"),
+    Some("self_test_fail"),
+    Some("op_dc_voltage_over"),
+    Some("battery_open"),
+    Some("current_sensor_fail"),
+    Some("battery_short"),
+    Some("power_limit"),
+    Some("pv_voltage_high"),
+    Some("mppt_overload_fault"),
+    Some("mppt_overload_warning"),
+    Some("battery_too_low_to_charge"),
+];
+
+/// Warning and fault bits reported by `QPIWS`, `a0a1..a31`.
+///
+/// Several conditions are a fault rather than a warning when
+/// `inverter_fault` is set as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InverterWarnings {
+    /// Bit `aN` is `1 << N`.
+    bits: u64,
+}
+
+impl InverterWarnings {
+    pub const COMMAND: &'static str = "QPIWS";
+
+    /// Parses a `QPIWS` response payload.
+    pub fn parse(payload: &str) -> Result<Self, InverterError> {
+        let fields = [payload.trim()];
+        let bits = bits(&fields, 0, "warning status")?;
+        if bits.len() < 32 || bits.len() > 64 {
+            return Err(InverterError::Malformed(format!(
+                "warning status has {} bits, expected 32 to 64",
+                bits.len()
+            )));
+        }
+        Ok(InverterWarnings {
+            bits: bits
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, &set)| acc | (u64::from(set) << i)),
+        })
+    }
+
+    /// Whether the named warning is set; unknown names are never set.
+    pub fn is_set(&self, name: &str) -> bool {
+        WARNINGS
+            .iter()
+            .position(|w| *w == Some(name))
+            .is_some_and(|bit| self.bits & (1 << bit) != 0)
+    }
+
+    /// Names of the warnings that are set.
+    pub fn active(&self) -> Vec<&'static str> {
+        self.fields()
+            .into_iter()
+            .filter(|(_, value)| *value == FieldValue::Bool(true))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// `(warning name, set)` of every named bit.
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        WARNINGS
+            .iter()
+            .enumerate()
+            .filter_map(|(bit, name)| {
+                name.map(|name| (name, FieldValue::Bool(self.bits & (1 << bit) != 0)))
+            })
+            .collect()
+    }
+}
+
+/// Operating mode reported by `QMOD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InverterMode {
+    PowerOn,
+    Standby,
+    Line,
+    Battery,
+    Fault,
+    /// Power saving.
+    Eco,
+    Shutdown,
+}
+
+impl InverterMode {
+    pub const COMMAND: &'static str = "QMOD";
+
+    pub const ALL: [InverterMode; 7] = [
+        InverterMode::PowerOn,
+        InverterMode::Standby,
+        InverterMode::Line,
+        InverterMode::Battery,
+        InverterMode::Fault,
+        InverterMode::Eco,
+        InverterMode::Shutdown,
+    ];
+
+    /// Parses a `QMOD` response payload, a single letter.
+    pub fn parse(payload: &str) -> Result<Self, InverterError> {
+        match payload.trim() {
+            "P" => Ok(InverterMode::PowerOn),
+            "S" => Ok(InverterMode::Standby),
+            "L" => Ok(InverterMode::Line),
+            "B" => Ok(InverterMode::Battery),
+            "F" => Ok(InverterMode::Fault),
+            "H" => Ok(InverterMode::Eco),
+            "D" => Ok(InverterMode::Shutdown),
+            other => Err(InverterError::Malformed(format!(
+                "mode `{}` is not known",
+                other
+            ))),
+        }
+    }
+
+    /// Identifier used in metric labels and sink payloads.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InverterMode::PowerOn => "power_on",
+            InverterMode::Standby => "standby",
+            InverterMode::Line => "line",
+            InverterMode::Battery => "battery",
+            InverterMode::Fault => "fault",
+            InverterMode::Eco => "eco",
+            InverterMode::Shutdown => "shutdown",
+        }
+    }
+}
+
+impl fmt::Display for InverterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InverterMode::PowerOn => "power on",
+            InverterMode::Standby => "standby",
+            InverterMode::Line => "line",
+            InverterMode::Battery => "battery",
+            InverterMode::Fault => "fault",
+            InverterMode::Eco => "ECO",
+            InverterMode::Shutdown => "shutdown",
+        })
+    }
+}
+
+/// What an inverter reading contains; each query has its own interval.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InverterReading {
+    Status(PowerStatus),
+    Rating(InverterRating),
+    Warnings(InverterWarnings),
+}
+
+impl InverterReading {
+    /// `status`, `rating` or `warnings`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InverterReading::Status(_) => "status",
+            InverterReading::Rating(_) => "rating",
+            InverterReading::Warnings(_) => "warnings",
+        }
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        match self {
+            InverterReading::Status(status) => status.fields(),
+            InverterReading::Rating(rating) => rating.fields(),
+            InverterReading::Warnings(warnings) => warnings.fields(),
+        }
+    }
+}
+
+/// One reading of a named inverter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InverterSnapshot {
+    /// When the response was received.
+    pub timestamp: DateTime<Utc>,
+    /// Name of the inverter in the configuration.
+    pub name: String,
+    pub site: Option<String>,
+    pub reading: InverterReading,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `QPIGS` of a unit charging from PV while feeding the load.
+    const QPIGS: &str = "000.0 00.0 230.0 50.0 0414 0345 008 405 52.10 012 087 0038 0014 215.4 52.18 00000 00110110 00 00 00754 010";
+
+    #[test]
+    fn parses_status() {
+        let status = PowerStatus::parse(QPIGS).unwrap();
+        assert_eq!(status.grid_voltage, 0.0);
+        assert_eq!(status.ac_output_voltage, 230.0);
+        assert_eq!(status.ac_output_active_power, 345);
+        assert_eq!(status.battery_voltage, 52.1);
+        assert_eq!(status.battery_charging_current, 12);
+        assert_eq!(status.battery_capacity, 87);
+        assert_eq!(status.inverter_heat_sink_temperature, 38);
+        assert_eq!(status.pv_input_voltage, 215.4);
+        assert!(!status.sbu_priority);
+        assert!(status.load_status);
+        assert_eq!(status.charging_status, ChargingStatus::Scc);
+        assert_eq!(status.pv_charging_power, 754);
+        assert!(!status.charging_in_floating_mode);
+        assert!(status.switch_on);
+    }
+
+    #[test]
+    fn parses_status_of_older_firmware() {
+        let status = PowerStatus::parse(
+            "229.8 49.9 229.8 49.9 0183 0140 003 360 26.40 000 100 -010 0000 000.0 00.00 00002 00010101",
+        )
+        .unwrap();
+        assert_eq!(status.inverter_heat_sink_temperature, -10);
+        assert_eq!(status.battery_discharge_current, 2);
+        assert_eq!(status.charging_status, ChargingStatus::Ac);
+        assert_eq!(status.pv_charging_power, 0);
+        assert!(!status.switch_on);
+    }
+
+    #[test]
+    fn rejects_bad_status() {
+        assert!(PowerStatus::parse("229.8 49.9").is_err());
+        let bad_bits = QPIGS.replace("00110110", "0011011");
+        assert!(PowerStatus::parse(&bad_bits).is_err());
+    }
+
+    /// `QPIRI` of a 5 kVA, 48 V unit set up for a lithium battery.
+    const QPIRI: &str =
+        "230.0 21.7 230.0 50.0 21.7 5000 4000 48.0 46.0 42.0 56.4 54.0 3 30 080 0 1 2 9 01 0 0 54.0 0 1";
+
+    #[test]
+    fn parses_rating() {
+        let rating = InverterRating::parse(QPIRI).unwrap();
+        assert_eq!(rating.ac_output_rating_apparent_power, 5000);
+        assert_eq!(rating.battery_rating_voltage, 48.0);
+        assert_eq!(rating.battery_type, BatteryType::Other(3));
+        assert_eq!(rating.max_ac_charging_current, 30);
+        assert_eq!(rating.max_charging_current, 80);
+        assert_eq!(rating.input_voltage_range, InputVoltageRange::Appliance);
+        assert_eq!(rating.output_source_priority, OutputSourcePriority::Solar);
+        assert_eq!(
+            rating.charger_source_priority,
+            ChargerSourcePriority::SolarAndUtility
+        );
+        assert_eq!(rating.machine_type, 1);
+        assert_eq!(rating.battery_redischarge_voltage, 54.0);
+        assert!(rating.pv_power_balance);
+    }
+
+    #[test]
+    fn parses_rating_of_older_firmware() {
+        let rating = InverterRating::parse(
+            "230.0 13.0 230.0 50.0 13.0 3000 2400 24.0 23.0 21.0 28.2 27.0 0 20 30 0 0 2 1 01 0 0",
+        )
+        .unwrap();
+        assert_eq!(rating.battery_type, BatteryType::Agm);
+        assert_eq!(rating.output_source_priority, OutputSourcePriority::Utility);
+        assert_eq!(rating.battery_redischarge_voltage, 0.0);
+        assert!(!rating.pv_power_balance);
+    }
+
+    #[test]
+    fn keeps_unknown_codes() {
+        assert_eq!(BatteryType::from_code(8), BatteryType::Other(8));
+        assert_eq!(BatteryType::Other(8).code(), 8);
+        assert_eq!(ChargerSourcePriority::from_code(3).code(), 3);
+        assert_eq!(OutputSourcePriority::Other(7).to_string(), "unknown (7)");
+    }
+
+    #[test]
+    fn rejects_short_rating() {
+        assert!(matches!(
+            InverterRating::parse("230.0 21.7 230.0"),
+            Err(InverterError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn parses_warnings() {
+        // a1 inverter fault, a9 over temperature, a11 battery voltage high
+        let warnings = InverterWarnings::parse("01000000010100000000000000000000").unwrap();
+        assert!(warnings.is_set("inverter_fault"));
+        assert!(warnings.is_set("over_temperature"));
+        assert!(!warnings.is_set("fan_locked"));
+        assert_eq!(
+            warnings.active(),
+            ["inverter_fault", "over_temperature", "battery_voltage_high"]
+        );
+
+        let none = InverterWarnings::parse("000000000000000000000000000000000000").unwrap();
+        assert!(none.active().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_warnings() {
+        assert!(InverterWarnings::parse("0100").is_err());
+        assert!(InverterWarnings::parse("0100000001010000000000000000000x").is_err());
+    }
+
+    #[test]
+    fn parses_mode() {
+        assert_eq!(InverterMode::parse("B").unwrap(), InverterMode::Battery);
+        assert_eq!(InverterMode::parse("L").unwrap(), InverterMode::Line);
+        assert_eq!(InverterMode::parse("H").unwrap().as_str(), "eco");
+        assert!(InverterMode::parse("X").is_err());
+    }
+}
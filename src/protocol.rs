@@ -0,0 +1,388 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use crc::{Crc, CRC_8_SMBUS};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::driver::BatteryDriver;
+use crate::register::Register;
+
+pub const CRC_8: Crc<u8> = Crc::<u8>::new(&CRC_8_SMBUS);
+
+/// Checks the CRC-8 of a response.
+///
+/// The checksum covers the command that was sent followed by the response
+/// payload; the last byte of `rev` is the checksum itself.
+pub fn crc8_check(send: &[u8], rev: &[u8]) -> bool {
+    let mut data = Vec::new();
+    data.extend_from_slice(send);
+    data.extend_from_slice(rev);
+
+    let len = data.len();
+
+    if len > 3 {
+        CRC_8.checksum(&data[..len - 1]) == data[len - 1]
+    } else {
+        false
+    }
+}
+
+/// Upper bound on bytes buffered while waiting for a response. Anything
+/// longer cannot be a pack frame and is discarded.
+const MAX_BUFFERED: usize = 64;
+
+/// A response that could not be turned into a reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The response had the expected length but failed the driver's
+    /// checksum.
+    Crc {
+        register: Register,
+        received: Vec<u8>,
+    },
+    /// Fewer bytes than expected arrived before the response deadline.
+    Short {
+        register: Register,
+        expected: usize,
+        received: Vec<u8>,
+    },
+    /// More bytes than expected arrived and no valid frame was found in them.
+    Long {
+        register: Register,
+        expected: usize,
+        received: Vec<u8>,
+    },
+    /// Bytes arrived that do not belong to the outstanding request, e.g. the
+    /// tail of a reply to an earlier request.
+    Late(Vec<u8>),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Crc { register, received } => {
+                write!(f, "{}: checksum error, REV: {:02X?}", register, received)
+            }
+            FrameError::Short {
+                register,
+                expected,
+                received,
+            } => write!(
+                f,
+                "{}: short frame, expected {} bytes, REV: {:02X?}",
+                register, expected, received
+            ),
+            FrameError::Long {
+                register,
+                expected,
+                received,
+            } => write!(
+                f,
+                "{}: long frame, expected {} bytes, REV: {:02X?}",
+                register, expected, received
+            ),
+            FrameError::Late(received) => write!(f, "late or unsolicited bytes: {:02X?}", received),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Codec for the request/response framing of a pack, with the wire format
+/// supplied by its driver. Items are complete responses that passed the
+/// driver's check, still to be decoded by the driver.
+///
+/// Responses carry no header, so the decoder can only make sense of the
+/// input relative to the request that was last encoded. It accumulates bytes
+/// until a complete response is buffered, and when more bytes than expected
+/// are present it looks for a CRC-valid frame among them, reporting whatever
+/// precedes it as `FrameError::Late`.
+///
+/// Only the tail of a reply to an expired request can arrive ahead of the
+/// response, so once no valid frame is found and the buffer is longer than
+/// the response plus that tail, the bytes are reported as a `Crc` or `Long`
+/// frame without waiting for the deadline.
+///
+/// Decoding errors are yielded as items so that a bad frame does not end the
+/// stream; only I/O errors do.
+#[derive(Debug)]
+pub struct PackCodec {
+    driver: Arc<dyn BatteryDriver>,
+    pending: Option<Register>,
+    /// Bytes of the reply to an expired request that may still arrive.
+    late: usize,
+}
+
+impl PackCodec {
+    pub fn new(driver: Arc<dyn BatteryDriver>) -> Self {
+        PackCodec {
+            driver,
+            pending: None,
+            late: 0,
+        }
+    }
+
+    pub fn driver(&self) -> &Arc<dyn BatteryDriver> {
+        &self.driver
+    }
+
+    /// The register whose response is outstanding.
+    pub fn pending(&self) -> Option<Register> {
+        self.pending
+    }
+
+    /// Gives up on the outstanding request, e.g. when its deadline passed.
+    ///
+    /// Whatever is left in `buf` is reported as a short or long frame.
+    pub fn expire(&mut self, buf: &mut BytesMut) -> Option<FrameError> {
+        let register = self.pending.take()?;
+        let expected = self.driver.response_len(register);
+        self.late = expected.saturating_sub(buf.len());
+        if buf.is_empty() {
+            return None;
+        }
+
+        let received = buf.split().to_vec();
+        if received.len() < expected {
+            Some(FrameError::Short {
+                register,
+                expected,
+                received,
+            })
+        } else {
+            Some(FrameError::Long {
+                register,
+                expected,
+                received,
+            })
+        }
+    }
+}
+
+impl Encoder<Register> for PackCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, register: Register, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(&self.driver.request(register));
+        self.pending = Some(register);
+        Ok(())
+    }
+}
+
+impl Decoder for PackCodec {
+    type Item = Result<BytesMut, FrameError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let register = match self.pending {
+            Some(register) => register,
+            None => {
+                self.late = self.late.saturating_sub(buf.len());
+                return Ok(Some(Err(FrameError::Late(buf.split().to_vec()))));
+            }
+        };
+
+        let len = self.driver.response_len(register);
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let found = (0..=buf.len() - len).find(|&o| self.driver.check(register, &buf[o..o + len]));
+        match found {
+            Some(0) => {
+                self.pending = None;
+                self.late = 0;
+                Ok(Some(Ok(buf.split_to(len))))
+            }
+            // Resynchronise: drop what precedes the frame, which is then
+            // decoded on the next call.
+            Some(o) => {
+                self.late = self.late.saturating_sub(o);
+                Ok(Some(Err(FrameError::Late(buf.split_to(o).to_vec()))))
+            }
+            // The frame may still be arriving behind late bytes.
+            None if buf.len() < len + self.late && buf.len() <= MAX_BUFFERED => Ok(None),
+            None => {
+                self.pending = None;
+                self.late = 0;
+                let received = buf.split().to_vec();
+                if received.len() == len {
+                    Ok(Some(Err(FrameError::Crc { register, received })))
+                } else {
+                    Ok(Some(Err(FrameError::Long {
+                        register,
+                        expected: len,
+                        received,
+                    })))
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None => Ok(self.expire(buf).map(Err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::B5120;
+
+    fn codec(register: Register) -> PackCodec {
+        let mut codec = PackCodec::new(Arc::new(B5120));
+        codec.encode(register, &mut BytesMut::new()).unwrap();
+        codec
+    }
+
+    /// Valid response of `register` holding `value`.
+    fn response(register: Register, value: u32) -> Vec<u8> {
+        let len = register.data_len() as usize;
+        let mut response = value.to_be_bytes()[4 - len..].to_vec();
+        let mut data = register.request().to_vec();
+        data.extend_from_slice(&response);
+        response.push(CRC_8.checksum(&data));
+        response
+    }
+
+    #[test]
+    fn checks_crc() {
+        let request = Register::Rsoc.request();
+        assert!(crc8_check(&request, &response(Register::Rsoc, 87)));
+        assert!(!crc8_check(&request, &[0x00, 0x57, 0x00]));
+        assert!(!crc8_check(&[], &[0x00]));
+    }
+
+    #[test]
+    fn decodes_fragmented_response() {
+        let mut codec = codec(Register::Current);
+        let frame = response(Register::Current, 1500);
+        let mut buf = BytesMut::new();
+        for &byte in &frame[..frame.len() - 1] {
+            buf.extend_from_slice(&[byte]);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame[..].into())));
+        assert_eq!(codec.pending(), None);
+    }
+
+    #[test]
+    fn reports_bytes_after_the_response_as_late() {
+        let mut codec = codec(Register::Rsoc);
+        let frame = response(Register::Rsoc, 87);
+        let mut buf = BytesMut::from(&frame[..]);
+        buf.extend_from_slice(&[0xAA, 0xBB]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame[..].into())));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Late(vec![0xAA, 0xBB])))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn skips_garbage_before_the_response() {
+        let mut codec = codec(Register::Rsoc);
+        let frame = response(Register::Rsoc, 87);
+        let mut buf = BytesMut::from(&[0x01, 0x02][..]);
+        buf.extend_from_slice(&frame);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Late(vec![0x01, 0x02])))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame[..].into())));
+    }
+
+    #[test]
+    fn waits_for_the_response_behind_the_tail_of_an_expired_one() {
+        let mut codec = codec(Register::Rsoc);
+        let expired = response(Register::Rsoc, 87);
+        let mut buf = BytesMut::from(&expired[..1]);
+        assert!(matches!(
+            codec.expire(&mut buf),
+            Some(FrameError::Short { expected: 3, .. })
+        ));
+
+        codec
+            .encode(Register::CycleCount, &mut BytesMut::new())
+            .unwrap();
+        let frame = response(Register::CycleCount, 42);
+        buf.extend_from_slice(&expired[1..]);
+        buf.extend_from_slice(&frame[..1]);
+        // Three bytes are buffered but two of them may be the late tail.
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&frame[1..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Late(expired[1..].to_vec())))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame[..].into())));
+    }
+
+    #[test]
+    fn reports_crc_error_without_waiting() {
+        let mut codec = codec(Register::Rsoc);
+        let mut buf = BytesMut::from(&[0x00, 0x57, 0x00][..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Crc {
+                register: Register::Rsoc,
+                received: vec![0x00, 0x57, 0x00],
+            }))
+        );
+        assert_eq!(codec.pending(), None);
+    }
+
+    #[test]
+    fn reports_long_frame_without_waiting() {
+        let mut codec = codec(Register::Rsoc);
+        let mut buf = BytesMut::from(&[0x00, 0x57, 0x00, 0x00][..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Long {
+                register: Register::Rsoc,
+                expected: 3,
+                received: vec![0x00, 0x57, 0x00, 0x00],
+            }))
+        );
+    }
+
+    #[test]
+    fn reports_short_frame_at_the_deadline() {
+        let mut codec = codec(Register::TotalVoltage);
+        let mut buf = BytesMut::from(&[0x00, 0x00][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(
+            codec.expire(&mut buf),
+            Some(FrameError::Short {
+                register: Register::TotalVoltage,
+                expected: 5,
+                received: vec![0x00, 0x00],
+            })
+        );
+        assert_eq!(codec.expire(&mut buf), None);
+    }
+
+    #[test]
+    fn reports_unsolicited_bytes_as_late() {
+        let mut codec = PackCodec::new(Arc::new(B5120));
+        let mut buf = BytesMut::from(&[0x10][..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(FrameError::Late(vec![0x10])))
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use std::fmt;
+
+use crate::status::{BatteryStatus, PackConfig, PackStatus};
+
+/// SMBus-style slave address of the B5120 pack, first byte of every command.
+pub const ADDRESS: u8 = 0x0A;
+
+/// Number of cell voltage registers (`0x01..=0x10`).
+pub const CELL_COUNT: u8 = 16;
+
+/// Number of temperature registers (`0x13..=0x15`).
+pub const TEMPERATURE_COUNT: u8 = 3;
+
+/// A readable register of the B5120 pack.
+///
+/// Cells and temperature sensors are numbered from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Register {
+    CellVoltage(u8),
+    TotalVoltage,
+    Current,
+    Temperature(u8),
+    FullCapacity,
+    RemainingCapacity,
+    Rsoc,
+    CycleCount,
+    PackStatus,
+    BatteryStatus,
+    PackConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Millivolt,
+    Milliamp,
+    Celsius,
+    MilliampHour,
+    Percent,
+    None,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Unit::Millivolt => "mV",
+            Unit::Milliamp => "mA",
+            Unit::Celsius => "°C",
+            Unit::MilliampHour => "mAh",
+            Unit::Percent => "%",
+            Unit::None => "",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Register {
+    /// Every register, in command byte order.
+    pub fn all() -> impl Iterator<Item = Register> {
+        (0x01..=0x1C).filter_map(Register::from_command)
+    }
+
+    pub fn from_command(cmd: u8) -> Option<Register> {
+        let register = match cmd {
+            0x01..=0x10 => Register::CellVoltage(cmd),
+            0x11 => Register::TotalVoltage,
+            0x12 => Register::Current,
+            0x13..=0x15 => Register::Temperature(cmd - 0x12),
+            0x16 => Register::FullCapacity,
+            0x17 => Register::RemainingCapacity,
+            0x18 => Register::Rsoc,
+            0x19 => Register::CycleCount,
+            0x1A => Register::PackStatus,
+            0x1B => Register::BatteryStatus,
+            0x1C => Register::PackConfig,
+            _ => return None,
+        };
+        Some(register)
+    }
+
+    pub fn command(&self) -> u8 {
+        match *self {
+            Register::CellVoltage(n) => n,
+            Register::TotalVoltage => 0x11,
+            Register::Current => 0x12,
+            Register::Temperature(n) => 0x12 + n,
+            Register::FullCapacity => 0x16,
+            Register::RemainingCapacity => 0x17,
+            Register::Rsoc => 0x18,
+            Register::CycleCount => 0x19,
+            Register::PackStatus => 0x1A,
+            Register::BatteryStatus => 0x1B,
+            Register::PackConfig => 0x1C,
+        }
+    }
+
+    /// Payload length of the response, not counting the trailing CRC byte.
+    pub fn data_len(&self) -> u8 {
+        match self {
+            Register::TotalVoltage
+            | Register::Current
+            | Register::FullCapacity
+            | Register::RemainingCapacity => 4,
+            _ => 2,
+        }
+    }
+
+    pub fn signed(&self) -> bool {
+        matches!(self, Register::Current | Register::Temperature(_))
+    }
+
+    /// Factor that turns the raw register value into `unit()`.
+    pub fn scale(&self) -> f32 {
+        match self {
+            Register::Temperature(_) => 0.01,
+            _ => 1.0,
+        }
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self {
+            Register::CellVoltage(_) | Register::TotalVoltage => Unit::Millivolt,
+            Register::Current => Unit::Milliamp,
+            Register::Temperature(_) => Unit::Celsius,
+            Register::FullCapacity | Register::RemainingCapacity => Unit::MilliampHour,
+            Register::Rsoc => Unit::Percent,
+            _ => Unit::None,
+        }
+    }
+
+    /// Whether the register is a bitfield rather than a measurement.
+    pub fn is_status(&self) -> bool {
+        matches!(
+            self,
+            Register::PackStatus | Register::BatteryStatus | Register::PackConfig
+        )
+    }
+
+    /// Field name used when the reading is stored.
+    pub fn field_name(&self) -> String {
+        match self {
+            Register::CellVoltage(n) => format!("cell_{}", n),
+            Register::TotalVoltage => "total_voltage".to_string(),
+            Register::Current => "current".to_string(),
+            Register::Temperature(n) => format!("temperature_{}", n),
+            Register::FullCapacity => "full_capacity".to_string(),
+            Register::RemainingCapacity => "remaining_capacity".to_string(),
+            Register::Rsoc => "rsoc".to_string(),
+            Register::CycleCount => "cycle_count".to_string(),
+            Register::PackStatus => "pack_status".to_string(),
+            Register::BatteryStatus => "battery_status".to_string(),
+            Register::PackConfig => "pack_config".to_string(),
+        }
+    }
+
+    /// The command sent to the pack to read this register.
+    pub fn request(&self) -> [u8; 3] {
+        [ADDRESS, self.command(), self.data_len()]
+    }
+
+    /// Decodes the response payload (without the CRC byte).
+    ///
+    /// Returns `None` if `data` is not exactly `data_len()` bytes long.
+    pub fn decode(&self, data: &[u8]) -> Option<Reading> {
+        if data.len() != self.data_len() as usize {
+            return None;
+        }
+
+        let raw = match (data.len(), self.signed()) {
+            (2, false) => u16::from_be_bytes([data[0], data[1]]) as i64,
+            (2, true) => i16::from_be_bytes([data[0], data[1]]) as i64,
+            (4, false) => u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as i64,
+            _ => i32::from_be_bytes([data[0], data[1], data[2], data[3]]) as i64,
+        };
+
+        Some(Reading {
+            register: *self,
+            raw,
+        })
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::CellVoltage(n) => write!(f, "Cell {}", n),
+            Register::TotalVoltage => f.write_str("Total voltage"),
+            Register::Current => f.write_str("Current"),
+            Register::Temperature(n) => write!(f, "Temperature {}", n),
+            Register::FullCapacity => f.write_str("Full capacity"),
+            Register::RemainingCapacity => f.write_str("Remaining capacity"),
+            Register::Rsoc => f.write_str("RSOC"),
+            Register::CycleCount => f.write_str("Cycle count"),
+            Register::PackStatus => f.write_str("Pack status"),
+            Register::BatteryStatus => f.write_str("Battery status"),
+            Register::PackConfig => f.write_str("Pack config"),
+        }
+    }
+}
+
+/// A decoded register value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub register: Register,
+    /// Value as sent by the pack, before scaling.
+    pub raw: i64,
+}
+
+impl Reading {
+    /// Value in `register.unit()`.
+    pub fn value(&self) -> f32 {
+        self.raw as f32 * self.register.scale()
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = self.raw as u16;
+        match self.register {
+            Register::PackStatus => {
+                write!(f, "{}: {:#06X} ({})", self.register, bits, PackStatus(bits))
+            }
+            Register::BatteryStatus => write!(
+                f,
+                "{}: {:#06X} ({})",
+                self.register,
+                bits,
+                BatteryStatus(bits)
+            ),
+            Register::PackConfig => {
+                write!(f, "{}: {:#06X} ({})", self.register, bits, PackConfig(bits))
+            }
+            register if register.scale() == 1.0 => {
+                write!(f, "{}: {}{}", register, self.raw, register.unit())
+            }
+            register => write!(f, "{}: {}{}", register, self.value(), register.unit()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(register: Register, data: &[u8]) -> Reading {
+        register.decode(data).unwrap()
+    }
+
+    #[test]
+    fn maps_commands_both_ways() {
+        assert_eq!(Register::all().count(), 0x1C);
+        for register in Register::all() {
+            assert_eq!(Register::from_command(register.command()), Some(register));
+        }
+        assert_eq!(Register::from_command(0x00), None);
+        assert_eq!(Register::from_command(0x1D), None);
+        assert_eq!(Register::Temperature(1).request(), [ADDRESS, 0x13, 2]);
+        assert_eq!(Register::Current.request(), [ADDRESS, 0x12, 4]);
+    }
+
+    #[test]
+    fn decodes_current_as_signed() {
+        let discharging = decode(Register::Current, &[0xFF, 0xFF, 0xEC, 0x78]);
+        assert_eq!(discharging.raw, -5000);
+        assert_eq!(discharging.value(), -5000.0);
+        let charging = decode(Register::Current, &[0x00, 0x00, 0x13, 0x88]);
+        assert_eq!(charging.raw, 5000);
+    }
+
+    #[test]
+    fn decodes_temperature_as_signed_hundredths() {
+        let cold = decode(Register::Temperature(2), &[0xFC, 0x18]);
+        assert_eq!(cold.raw, -1000);
+        assert_eq!(cold.value(), -10.0);
+        let warm = decode(Register::Temperature(1), &[0x09, 0xC4]);
+        assert_eq!(warm.value(), 25.0);
+        assert_eq!(warm.to_string(), "Temperature 1: 25°C");
+    }
+
+    #[test]
+    fn decodes_other_registers_as_unsigned() {
+        assert_eq!(decode(Register::CellVoltage(3), &[0xFF, 0xFF]).raw, 65535);
+        assert_eq!(
+            decode(Register::TotalVoltage, &[0x80, 0x00, 0x00, 0x00]).raw,
+            0x8000_0000
+        );
+        assert_eq!(
+            decode(Register::RemainingCapacity, &[0x00, 0x01, 0x86, 0xA0]).value(),
+            100_000.0
+        );
+        let rsoc = decode(Register::Rsoc, &[0x00, 0x57]);
+        assert_eq!(rsoc.to_string(), "RSOC: 87%");
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        assert_eq!(Register::Current.decode(&[0x00, 0x01]), None);
+        assert_eq!(Register::Rsoc.decode(&[0x00, 0x01, 0x02]), None);
+        assert_eq!(Register::CellVoltage(1).decode(&[]), None);
+    }
+}
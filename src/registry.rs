@@ -0,0 +1,297 @@
+//! Registry of known devices and what they are.
+//!
+//! Loaded from a TOML file of the form
+//!
+//! ```toml
+//! [devices."aa:bb:cc:dd:ee:ff"]
+//! name = "Pack 1"
+//! site = "north-depot"
+//! position = "string 1, rack 2"
+//! cell_count = 16
+//! design_capacity_mah = 100000
+//! inverter = "Inverter 1"
+//! driver = "b5120"
+//! min_command_gap_ms = 50
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::config::ConfigError;
+use crate::device::DeviceId;
+use crate::driver;
+use crate::register::CELL_COUNT;
+
+/// Metadata of one device.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub site: String,
+    /// String/rack position within the site.
+    #[serde(default)]
+    pub position: Option<String>,
+    /// Nominal number of cells in series.
+    #[serde(default)]
+    pub cell_count: Option<u8>,
+    /// Capacity when new, against which the state of health is reported.
+    #[serde(default)]
+    pub design_capacity_mah: Option<u32>,
+    /// Inverter the pack is wired to, by its configured name; needed only
+    /// when the site has more than one.
+    #[serde(default)]
+    pub inverter: Option<String>,
+    /// Battery protocol of the pack; without it the driver is chosen from
+    /// the hello.
+    #[serde(default)]
+    pub driver: Option<String>,
+    /// Pause between the end of a response and the next command, replacing
+    /// `session.min_command_gap_ms` for this pack.
+    #[serde(default)]
+    pub min_command_gap_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    #[serde(default)]
+    devices: HashMap<String, DeviceInfo>,
+}
+
+/// File-backed device registry, shared by all sessions and reloadable at
+/// runtime.
+#[derive(Debug)]
+pub struct Registry {
+    path: Option<PathBuf>,
+    /// Whether devices missing from the registry may connect.
+    allow_unknown: bool,
+    /// Names of the configured inverters, which `inverter` must be one of.
+    inverters: Vec<String>,
+    devices: RwLock<HashMap<DeviceId, Arc<DeviceInfo>>>,
+}
+
+impl Registry {
+    /// An empty registry that is not backed by a file.
+    pub fn empty(allow_unknown: bool) -> Self {
+        Registry {
+            path: None,
+            allow_unknown,
+            inverters: Vec::new(),
+            devices: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Loads the registry at `path`; `inverters` are the names of the
+    /// configured inverters.
+    pub fn load(
+        path: &Path,
+        allow_unknown: bool,
+        inverters: Vec<String>,
+    ) -> Result<Self, ConfigError> {
+        let devices = read(path, &inverters)?;
+        Ok(Registry {
+            path: Some(path.to_path_buf()),
+            allow_unknown,
+            inverters,
+            devices: RwLock::new(devices),
+        })
+    }
+
+    /// Re-reads the file. On error the current entries are kept.
+    pub fn reload(&self) -> Result<usize, ConfigError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+
+        let devices = read(path, &self.inverters)?;
+        let n = devices.len();
+        *self.devices.write().unwrap() = devices;
+        Ok(n)
+    }
+
+    pub fn get(&self, id: DeviceId) -> Option<Arc<DeviceInfo>> {
+        self.devices.read().unwrap().get(&id).cloned()
+    }
+
+    /// Whether `id` may connect.
+    pub fn admits(&self, id: DeviceId) -> bool {
+        self.allow_unknown || self.devices.read().unwrap().contains_key(&id)
+    }
+
+    /// Every registered device with its metadata.
+    pub fn devices(&self) -> Vec<(DeviceId, Arc<DeviceInfo>)> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, info)| (*id, info.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn read(
+    path: &Path,
+    inverters: &[String],
+) -> Result<HashMap<DeviceId, Arc<DeviceInfo>>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    let file: RegistryFile =
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+    let mut devices = HashMap::new();
+    for (id, info) in file.devices {
+        let invalid = |msg: String| ConfigError::Invalid(format!("{}: {}", path.display(), msg));
+
+        let id: DeviceId = id.parse().map_err(invalid)?;
+        if info.name.is_empty() || info.site.is_empty() || info.position.as_deref() == Some("") {
+            return Err(invalid(format!(
+                "{}: name, site and position must not be empty",
+                id
+            )));
+        }
+        if let Some(n) = info.cell_count {
+            if n == 0 || n > CELL_COUNT {
+                return Err(invalid(format!(
+                    "{}: cell_count must be between 1 and {}",
+                    id, CELL_COUNT
+                )));
+            }
+        }
+        if info.design_capacity_mah == Some(0) {
+            return Err(invalid(format!(
+                "{}: design_capacity_mah must not be 0",
+                id
+            )));
+        }
+        if let Some(name) = &info.inverter {
+            if !inverters.contains(name) {
+                return Err(invalid(format!(
+                    "{}: inverter `{}` is not configured",
+                    id, name
+                )));
+            }
+        }
+        if let Some(name) = &info.driver {
+            if driver::by_name(name).is_none() {
+                return Err(invalid(format!("{}: unknown driver `{}`", id, name)));
+            }
+        }
+        devices.insert(id, Arc::new(info));
+    }
+
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `text` to a registry file unique to `name`.
+    fn file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "powermax-registry-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_device_profile() {
+        let path = file(
+            "profile",
+            r#"
+            [devices."00:1b:2c:3d:4e:5f"]
+            name = "Pack 1"
+            site = "north-depot"
+            driver = "b5120"
+            min_command_gap_ms = 50
+            "#,
+        );
+        let registry = Registry::load(&path, false, Vec::new()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let device = registry.get(id).unwrap();
+        assert_eq!(device.min_command_gap_ms, Some(50));
+        assert_eq!(device.driver.as_deref(), Some("b5120"));
+        assert!(registry.admits(id));
+        assert!(!registry.admits("00:1b:2c:3d:4e:60".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_unknown_driver() {
+        let path = file(
+            "driver",
+            r#"
+            [devices."00:1b:2c:3d:4e:5f"]
+            name = "Pack 1"
+            site = "north-depot"
+            driver = "nope"
+            "#,
+        );
+        let result = Registry::load(&path, false, Vec::new());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_empty_tags() {
+        for (name, entry) in [
+            ("name", "name = \"\"\nsite = \"north-depot\""),
+            ("site", "name = \"Pack 1\"\nsite = \"\""),
+            (
+                "position",
+                "name = \"Pack 1\"\nsite = \"north-depot\"\nposition = \"\"",
+            ),
+        ] {
+            let path = file(
+                &format!("empty-{}", name),
+                &format!("[devices.\"00:1b:2c:3d:4e:5f\"]\n{}\n", entry),
+            );
+            let result = Registry::load(&path, false, Vec::new());
+            fs::remove_file(&path).unwrap();
+            assert!(matches!(result, Err(ConfigError::Invalid(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn checks_inverter_on_load_and_reload() {
+        let pack = |inverter: &str| {
+            format!(
+                "[devices.\"00:1b:2c:3d:4e:5f\"]\nname = \"Pack 1\"\nsite = \"north-depot\"\ninverter = \"{}\"\n",
+                inverter
+            )
+        };
+        let inverters = vec!["Inverter 1".to_string()];
+        let path = file("inverter", &pack("Inverter 2"));
+        let result = Registry::load(&path, false, inverters.clone());
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+
+        fs::write(&path, pack("Inverter 1")).unwrap();
+        let registry = Registry::load(&path, false, inverters).unwrap();
+        fs::write(&path, pack("inverter 1")).unwrap();
+        let reloaded = registry.reload();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(reloaded, Err(ConfigError::Invalid(_))));
+        // The previous entries are kept.
+        let id = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        assert_eq!(
+            registry.get(id).unwrap().inverter.as_deref(),
+            Some("Inverter 1")
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt;
+use std::str::FromStr;
+
+use tokio::time::{Duration, Instant};
+
+/// Registers that are always polled together; which registers they are is
+/// up to the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterGroup {
+    Cells,
+    Temperatures,
+    TotalVoltage,
+    Current,
+    FullCapacity,
+    RemainingCapacity,
+    Rsoc,
+    CycleCount,
+    /// Pack status and battery status.
+    Status,
+    PackConfig,
+}
+
+impl RegisterGroup {
+    pub const ALL: [RegisterGroup; 10] = [
+        RegisterGroup::Cells,
+        RegisterGroup::Temperatures,
+        RegisterGroup::TotalVoltage,
+        RegisterGroup::Current,
+        RegisterGroup::FullCapacity,
+        RegisterGroup::RemainingCapacity,
+        RegisterGroup::Rsoc,
+        RegisterGroup::CycleCount,
+        RegisterGroup::Status,
+        RegisterGroup::PackConfig,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegisterGroup::Cells => "cells",
+            RegisterGroup::Temperatures => "temperatures",
+            RegisterGroup::TotalVoltage => "total_voltage",
+            RegisterGroup::Current => "current",
+            RegisterGroup::FullCapacity => "full_capacity",
+            RegisterGroup::RemainingCapacity => "remaining_capacity",
+            RegisterGroup::Rsoc => "rsoc",
+            RegisterGroup::CycleCount => "cycle_count",
+            RegisterGroup::Status => "status",
+            RegisterGroup::PackConfig => "pack_config",
+        }
+    }
+}
+
+impl fmt::Display for RegisterGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RegisterGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RegisterGroup::ALL
+            .iter()
+            .find(|group| group.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown register group `{}`", s))
+    }
+}
+
+/// Polling interval of every register group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    intervals: Vec<(RegisterGroup, Duration)>,
+}
+
+impl Schedule {
+    /// A schedule without any group.
+    pub fn new() -> Self {
+        Schedule {
+            intervals: Vec::new(),
+        }
+    }
+
+    /// This schedule with the intervals of the groups `overrides` also has
+    /// taken from there.
+    pub fn overridden_by(&self, overrides: &Schedule) -> Schedule {
+        Schedule {
+            intervals: self
+                .intervals
+                .iter()
+                .map(|(group, interval)| {
+                    let interval = overrides
+                        .intervals
+                        .iter()
+                        .find(|(g, _)| g == group)
+                        .map_or(*interval, |(_, interval)| *interval);
+                    (*group, interval)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn interval(&self, group: RegisterGroup) -> Duration {
+        self.intervals
+            .iter()
+            .find(|(g, _)| *g == group)
+            .map(|(_, interval)| *interval)
+            .unwrap_or(Duration::from_secs(10))
+    }
+
+    pub fn set(&mut self, group: RegisterGroup, interval: Duration) {
+        match self.intervals.iter_mut().find(|(g, _)| *g == group) {
+            Some(entry) => entry.1 = interval,
+            None => self.intervals.push((group, interval)),
+        }
+    }
+
+    pub fn intervals(&self) -> &[(RegisterGroup, Duration)] {
+        &self.intervals
+    }
+}
+
+impl Default for Schedule {
+    /// Every group of the B5120: fast-moving values every couple of
+    /// seconds, values that only change with cycling or configuration
+    /// hourly.
+    fn default() -> Self {
+        let secs = Duration::from_secs;
+        Schedule {
+            intervals: vec![
+                (RegisterGroup::Current, secs(2)),
+                (RegisterGroup::TotalVoltage, secs(2)),
+                (RegisterGroup::Status, secs(5)),
+                (RegisterGroup::Cells, secs(10)),
+                (RegisterGroup::Temperatures, secs(10)),
+                (RegisterGroup::RemainingCapacity, secs(10)),
+                (RegisterGroup::Rsoc, secs(10)),
+                (RegisterGroup::FullCapacity, secs(3600)),
+                (RegisterGroup::CycleCount, secs(3600)),
+                (RegisterGroup::PackConfig, secs(3600)),
+            ],
+        }
+    }
+}
+
+/// Tracks when each register group is next due on one connection.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// `(group, interval, next due)`
+    entries: Vec<(RegisterGroup, Duration, Instant)>,
+}
+
+impl Scheduler {
+    /// Every group is due immediately, so the first round reads everything.
+    pub fn new(schedule: &Schedule, now: Instant) -> Self {
+        Scheduler {
+            entries: schedule
+                .intervals()
+                .iter()
+                .map(|(group, interval)| (*group, *interval, now))
+                .collect(),
+        }
+    }
+
+    /// When the next group becomes due.
+    pub fn next_due(&self) -> Instant {
+        self.entries
+            .iter()
+            .map(|(_, _, due)| *due)
+            .min()
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(1))
+    }
+
+    /// Groups due at `now`, most overdue first, and schedules their next
+    /// poll. A group that fell behind by more than one interval is not
+    /// polled repeatedly to catch up.
+    pub fn due(&mut self, now: Instant) -> Vec<RegisterGroup> {
+        let mut due: Vec<(Instant, RegisterGroup)> = Vec::new();
+
+        for (group, interval, next) in self.entries.iter_mut() {
+            if *next <= now {
+                due.push((*next, *group));
+                *next += *interval;
+                if *next <= now {
+                    *next = now + *interval;
+                }
+            }
+        }
+
+        due.sort_by_key(|(next, _)| *next);
+        due.into_iter().map(|(_, group)| group).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> Schedule {
+        let mut schedule = Schedule::new();
+        schedule.set(RegisterGroup::Current, Duration::from_secs(2));
+        schedule.set(RegisterGroup::Status, Duration::from_secs(5));
+        schedule.set(RegisterGroup::CycleCount, Duration::from_secs(3600));
+        schedule
+    }
+
+    #[test]
+    fn polls_everything_first() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(&schedule(), start);
+        assert_eq!(scheduler.next_due(), start);
+        assert_eq!(
+            scheduler.due(start),
+            [
+                RegisterGroup::Current,
+                RegisterGroup::Status,
+                RegisterGroup::CycleCount
+            ]
+        );
+        assert!(scheduler.due(start).is_empty());
+        assert_eq!(scheduler.next_due(), start + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn polls_groups_at_their_intervals() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut scheduler = Scheduler::new(&schedule(), start);
+        scheduler.due(start);
+
+        assert_eq!(scheduler.due(start + secs(2)), [RegisterGroup::Current]);
+        assert_eq!(scheduler.due(start + secs(4)), [RegisterGroup::Current]);
+        assert_eq!(scheduler.next_due(), start + secs(5));
+        // Status became due first, so it goes first.
+        assert_eq!(
+            scheduler.due(start + secs(6)),
+            [RegisterGroup::Status, RegisterGroup::Current]
+        );
+        assert_eq!(scheduler.next_due(), start + secs(8));
+    }
+
+    #[test]
+    fn does_not_catch_up_missed_polls() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut scheduler = Scheduler::new(&schedule(), start);
+        scheduler.due(start);
+
+        assert_eq!(
+            scheduler.due(start + secs(11)),
+            [RegisterGroup::Current, RegisterGroup::Status]
+        );
+        assert!(scheduler.due(start + secs(12)).is_empty());
+        assert_eq!(scheduler.next_due(), start + secs(13));
+    }
+
+    #[test]
+    fn overrides_driver_intervals() {
+        let mut overrides = Schedule::new();
+        overrides.set(RegisterGroup::Status, Duration::from_secs(1));
+        overrides.set(RegisterGroup::Cells, Duration::from_secs(30));
+
+        let schedule = schedule().overridden_by(&overrides);
+        assert_eq!(
+            schedule.interval(RegisterGroup::Status),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            schedule.interval(RegisterGroup::Current),
+            Duration::from_secs(2)
+        );
+        // Groups the driver does not poll stay out of the schedule.
+        assert!(schedule
+            .intervals()
+            .iter()
+            .all(|(group, _)| *group != RegisterGroup::Cells));
+    }
+
+    #[test]
+    fn parses_group_names() {
+        for group in RegisterGroup::ALL {
+            assert_eq!(group.name().parse::<RegisterGroup>(), Ok(group));
+        }
+        assert!("voltage".parse::<RegisterGroup>().is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use chrono::prelude::*;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep_until, timeout_at, Duration, Instant};
+use tokio_util::codec::Framed;
+
+use crate::auth::Authenticator;
+use crate::device::DeviceId;
+use crate::driver::{self, BatteryDriver};
+use crate::error::Error;
+use crate::handshake::{read_hello, Hello};
+use crate::protocol::{FrameError, PackCodec};
+use crate::register::Register;
+use crate::registry::Registry;
+use crate::schedule::{Schedule, Scheduler};
+use crate::sink::{Event, Router};
+use crate::snapshot::PackSnapshot;
+
+/// Default response deadline in seconds.
+pub const TCPTIMEOUT: u64 = 10;
+
+/// Timing and failure policy of a session.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// How long to wait for the hello and for each response.
+    pub response_timeout: Duration,
+    /// Extra attempts per register after a timeout or an unusable response.
+    pub retries: u32,
+    /// The session is closed after this many timeouts in a row.
+    pub max_consecutive_timeouts: u32,
+    /// Minimum time between the end of one response and the next command,
+    /// for devices that cannot take back-to-back requests.
+    pub min_command_gap: Duration,
+    /// Polling intervals overriding those of the driver.
+    pub schedule: Schedule,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            response_timeout: Duration::from_secs(TCPTIMEOUT),
+            retries: 2,
+            max_consecutive_timeouts: 5,
+            min_command_gap: Duration::ZERO,
+            schedule: Schedule::new(),
+        }
+    }
+}
+
+/// Per-session counters of request outcomes.
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    pub requests: u64,
+    pub ok: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    pub crc_errors: u64,
+    pub short_frames: u64,
+    pub long_frames: u64,
+    pub late_frames: u64,
+    /// Timeouts since the last response, reset by any response.
+    pub consecutive_timeouts: u32,
+}
+
+impl SessionStats {
+    /// `(name, value)` of every counter.
+    pub fn counters(&self) -> [(&'static str, u64); 8] {
+        [
+            ("requests", self.requests),
+            ("ok", self.ok),
+            ("retries", self.retries),
+            ("timeouts", self.timeouts),
+            ("crc_errors", self.crc_errors),
+            ("short_frames", self.short_frames),
+            ("long_frames", self.long_frames),
+            ("late_frames", self.late_frames),
+        ]
+    }
+
+    fn count(&mut self, e: &FrameError) {
+        match e {
+            FrameError::Crc { .. } => self.crc_errors += 1,
+            FrameError::Short { .. } => self.short_frames += 1,
+            FrameError::Long { .. } => self.long_frames += 1,
+            FrameError::Late(_) => self.late_frames += 1,
+        }
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "requests={} ok={} retries={} timeouts={} crc_errors={} short={} long={} late={}",
+            self.requests,
+            self.ok,
+            self.retries,
+            self.timeouts,
+            self.crc_errors,
+            self.short_frames,
+            self.long_frames,
+            self.late_frames
+        )
+    }
+}
+
+/// Result of a single request/response exchange.
+enum Outcome {
+    /// A response that passed the driver's check.
+    Frame(BytesMut),
+    /// The device answered, but not with a usable frame.
+    Invalid,
+    Timeout,
+}
+
+/// Drives one connected device: handshake, then polls the register groups
+/// of its driver as they become due and hands one `PackSnapshot` and the
+/// session counters per round to the sinks.
+pub struct BmsSession {
+    framed: Framed<TcpStream, PackCodec>,
+    hello: Hello,
+    registry: Arc<Registry>,
+    router: Arc<Router>,
+    config: SessionConfig,
+    stats: SessionStats,
+    /// When the previous exchange ended, for `min_command_gap`.
+    last_exchange: Option<Instant>,
+}
+
+impl BmsSession {
+    /// Reads the device hello from a freshly accepted connection, checks
+    /// the device against the registry, authenticates it and picks its
+    /// driver. A command gap in the device's registry entry replaces the
+    /// configured one.
+    pub async fn handshake(
+        mut socket: TcpStream,
+        auth: &Authenticator,
+        registry: Arc<Registry>,
+        router: Arc<Router>,
+        mut config: SessionConfig,
+    ) -> Result<Self, Error> {
+        let hello = read_hello(&mut socket, config.response_timeout).await?;
+        if !registry.admits(hello.id) {
+            return Err(Error::NotRegistered(hello.id));
+        }
+        auth.authenticate(&mut socket, &hello, config.response_timeout)
+            .await?;
+        let device = registry.get(hello.id);
+        let driver = driver::select(&hello, device.as_deref())?;
+        if let Some(ms) = device.and_then(|device| device.min_command_gap_ms) {
+            config.min_command_gap = Duration::from_millis(ms);
+        }
+
+        Ok(BmsSession {
+            framed: Framed::new(socket, PackCodec::new(driver)),
+            hello,
+            registry,
+            router,
+            config,
+            stats: SessionStats::default(),
+            last_exchange: None,
+        })
+    }
+
+    pub fn id(&self) -> DeviceId {
+        self.hello.id
+    }
+
+    pub fn hello(&self) -> &Hello {
+        &self.hello
+    }
+
+    pub fn driver(&self) -> &dyn BatteryDriver {
+        self.framed.codec().driver().as_ref()
+    }
+
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// Polls the device until the connection fails, is closed, or the device
+    /// stops answering.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let schedule = self
+            .driver()
+            .schedule()
+            .overridden_by(&self.config.schedule);
+        let mut scheduler = Scheduler::new(&schedule, Instant::now());
+
+        // In a loop, write command to the socket and read the data. A round
+        // reads every group that is due; each command goes out as soon as the
+        // previous response is complete.
+        loop {
+            sleep_until(scheduler.next_due()).await;
+
+            let mut snapshot = PackSnapshot::new(self.hello.id, Utc::now());
+            snapshot.device = self.registry.get(self.hello.id);
+
+            for group in scheduler.due(Instant::now()) {
+                for register in self.driver().registers(group) {
+                    if self.poll(register, &mut snapshot).await? {
+                        snapshot.timestamp = Utc::now();
+                    } else {
+                        snapshot.mark_failed(register);
+                    }
+                }
+            }
+
+            self.router.send(Event::Snapshot(Arc::new(snapshot)));
+            self.router.send(Event::Stats {
+                device_id: self.hello.id,
+                stats: self.stats.clone(),
+                timestamp: Utc::now(),
+            });
+        }
+    }
+
+    /// Reads one register into `snapshot`, retrying as configured.
+    /// `Ok(false)` means no usable response was received.
+    async fn poll(
+        &mut self,
+        register: Register,
+        snapshot: &mut PackSnapshot,
+    ) -> Result<bool, Error> {
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                self.stats.retries += 1;
+            }
+
+            match self.request(register).await? {
+                Outcome::Frame(frame) => {
+                    self.stats.consecutive_timeouts = 0;
+                    match self.driver().decode(register, &frame, snapshot) {
+                        Some(reading) => {
+                            self.stats.ok += 1;
+                            println!("{}", reading);
+                            return Ok(true);
+                        }
+                        None => println!(
+                            "{} {} {}: cannot decode {:02X?}",
+                            Local::now().format("%Y-%m-%d %H:%M:%S"),
+                            self.hello.id,
+                            register,
+                            &frame[..]
+                        ),
+                    }
+                }
+                Outcome::Invalid => self.stats.consecutive_timeouts = 0,
+                Outcome::Timeout => {
+                    self.stats.timeouts += 1;
+                    self.stats.consecutive_timeouts += 1;
+                    println!(
+                        "{} {} {}: no response within {:?}",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        self.hello.id,
+                        register,
+                        self.config.response_timeout
+                    );
+                    if self.stats.consecutive_timeouts >= self.config.max_consecutive_timeouts {
+                        return Err(Error::Timeout);
+                    }
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Sends one request and waits for its response until the deadline.
+    async fn request(&mut self, register: Register) -> Result<Outcome, Error> {
+        if !self.framed.read_buffer().is_empty() {
+            let late = self.framed.read_buffer_mut().split().to_vec();
+            self.frame_error(FrameError::Late(late));
+        }
+
+        if let Some(last) = self.last_exchange {
+            sleep_until(last + self.config.min_command_gap).await;
+        }
+
+        self.stats.requests += 1;
+        self.framed.send(register).await?;
+        let deadline = Instant::now() + self.config.response_timeout;
+
+        let outcome = self.response(deadline).await;
+        self.last_exchange = Some(Instant::now());
+        outcome
+    }
+
+    /// Waits for the response to the outstanding request.
+    async fn response(&mut self, deadline: Instant) -> Result<Outcome, Error> {
+        loop {
+            let next = match timeout_at(deadline, self.framed.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let mut buf = self.framed.read_buffer_mut().split();
+                    return match self.framed.codec_mut().expire(&mut buf) {
+                        Some(e) => {
+                            self.frame_error(e);
+                            Ok(Outcome::Invalid)
+                        }
+                        None => Ok(Outcome::Timeout),
+                    };
+                }
+            };
+
+            match next {
+                // socket closed
+                None => return Err(Error::Closed),
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(Ok(frame))) => return Ok(Outcome::Frame(frame)),
+                // Keep waiting for the actual response.
+                Some(Ok(Err(e @ FrameError::Late(_)))) => self.frame_error(e),
+                Some(Ok(Err(e))) => {
+                    self.frame_error(e);
+                    return Ok(Outcome::Invalid);
+                }
+            }
+        }
+    }
+
+    fn frame_error(&mut self, e: FrameError) {
+        self.stats.count(&e);
+        self.log_frame_error(&e);
+    }
+
+    fn log_frame_error(&self, e: &FrameError) {
+        println!(
+            "{} {} {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            self.hello.id,
+            e
+        );
+    }
+}
@@ -0,0 +1,488 @@
+//! Inverter settings that can be changed remotely.
+//!
+//! Every setting is checked against the inverter's last `QPIRI` reading,
+//! and the charging currents against the values it offers in `QMCHGCR` and
+//! `QMUCHGCR`, before it is sent; the inverter answers `(ACK` or `(NAK`.
+
+use std::fmt;
+
+use crate::power::{ChargerSourcePriority, InverterRating, OutputSourcePriority};
+use crate::voltronic::{self, InverterError};
+
+/// Allowed battery re-charge voltage (`PBCV`) per 12 V of rated battery
+/// voltage.
+const RECHARGE_RANGE: (f32, f32) = (11.0, 12.75);
+
+/// Allowed battery re-discharge voltage (`PBDV`) per 12 V of rated battery
+/// voltage; 0 means "battery full".
+const REDISCHARGE_RANGE: (f32, f32) = (12.0, 14.5);
+
+/// Charging currents the inverter accepts, as listed by `QMCHGCR` and
+/// `QMUCHGCR`; `None` until read or if the firmware does not answer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChargingCurrents {
+    /// Values of `MCHGC`, in A.
+    pub charging: Option<Vec<u32>>,
+    /// Values of `MUCHGC`, in A.
+    pub utility: Option<Vec<u32>>,
+}
+
+impl ChargingCurrents {
+    pub const CHARGING_COMMAND: &'static str = "QMCHGCR";
+    pub const UTILITY_COMMAND: &'static str = "QMUCHGCR";
+
+    /// Parses a `QMCHGCR` or `QMUCHGCR` payload, e.g. `010 020 030`.
+    pub fn parse(payload: &str) -> Result<Vec<u32>, InverterError> {
+        let currents = payload
+            .split_whitespace()
+            .map(|raw| {
+                raw.parse().map_err(|_| {
+                    InverterError::Malformed(format!("current `{}` is not a number", raw))
+                })
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+        if currents.is_empty() {
+            return Err(InverterError::Malformed("no currents listed".to_string()));
+        }
+        Ok(currents)
+    }
+}
+
+/// `v` rounded to the tenths the commands carry, with -0.0 turned into 0.0.
+fn tenths(v: f32) -> f32 {
+    (v * 10.0).round() / 10.0 + 0.0
+}
+
+/// Checks `a` against the currents the inverter offers for `name`.
+fn offered(name: &str, a: u32, currents: Option<&[u32]>) -> Result<(), SettingError> {
+    match currents {
+        Some(currents) if currents.contains(&a) => Ok(()),
+        Some(currents) => Err(SettingError::Invalid(format!(
+            "{} {} A is not one of {}",
+            name,
+            a,
+            currents
+                .iter()
+                .map(|a| format!("{} A", a))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+        None => Err(SettingError::Invalid(format!(
+            "{} cannot be checked: the inverter has not listed the currents it accepts",
+            name
+        ))),
+    }
+}
+
+/// A setting and its new value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InverterSetting {
+    /// `POP`.
+    OutputSourcePriority(OutputSourcePriority),
+    /// `PCP`.
+    ChargerSourcePriority(ChargerSourcePriority),
+    /// `MCHGC`, total charging current in A.
+    MaxChargingCurrent(u32),
+    /// `MUCHGC`, charging current from utility in A.
+    MaxUtilityChargingCurrent(u32),
+    /// `PBCV`, voltage in V at which charging from utility starts again.
+    BatteryRechargeVoltage(f32),
+    /// `PBDV`, voltage in V at which discharging starts again; 0 waits for
+    /// a full battery.
+    BatteryRedischargeVoltage(f32),
+}
+
+impl InverterSetting {
+    /// Names accepted by `parse`.
+    pub const NAMES: [&'static str; 6] = [
+        "output_source_priority",
+        "charger_source_priority",
+        "max_charging_current",
+        "max_utility_charging_current",
+        "battery_recharge_voltage",
+        "battery_redischarge_voltage",
+    ];
+
+    /// The setting called `name` with `value` as the command carries it: a
+    /// code for the priorities, A or V for the others.
+    pub fn parse(name: &str, value: &str) -> Result<Self, String> {
+        let number = |what: &str| format!("{} `{}` is not a number", what, value);
+        match name {
+            "output_source_priority" => value
+                .parse()
+                .map(|code| {
+                    InverterSetting::OutputSourcePriority(OutputSourcePriority::from_code(code))
+                })
+                .map_err(|_| number("priority code")),
+            "charger_source_priority" => value
+                .parse()
+                .map(|code| {
+                    InverterSetting::ChargerSourcePriority(ChargerSourcePriority::from_code(code))
+                })
+                .map_err(|_| number("priority code")),
+            "max_charging_current" => value
+                .parse()
+                .map(InverterSetting::MaxChargingCurrent)
+                .map_err(|_| number("current")),
+            "max_utility_charging_current" => value
+                .parse()
+                .map(InverterSetting::MaxUtilityChargingCurrent)
+                .map_err(|_| number("current")),
+            "battery_recharge_voltage" => value
+                .parse()
+                .map(InverterSetting::BatteryRechargeVoltage)
+                .map_err(|_| number("voltage")),
+            "battery_redischarge_voltage" => value
+                .parse()
+                .map(InverterSetting::BatteryRedischargeVoltage)
+                .map_err(|_| number("voltage")),
+            _ => Err(format!(
+                "unknown setting `{}`; expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+
+    /// The command without framing, e.g. `POP02` or `PBCV46.0`.
+    pub fn command(&self) -> String {
+        match self {
+            InverterSetting::OutputSourcePriority(p) => format!("POP{:02}", p.code()),
+            InverterSetting::ChargerSourcePriority(p) => format!("PCP{:02}", p.code()),
+            InverterSetting::MaxChargingCurrent(a) => format!("MCHGC{:03}", a),
+            InverterSetting::MaxUtilityChargingCurrent(a) => format!("MUCHGC{:03}", a),
+            InverterSetting::BatteryRechargeVoltage(v) => format!("PBCV{:04.1}", tenths(*v)),
+            InverterSetting::BatteryRedischargeVoltage(v) => format!("PBDV{:04.1}", tenths(*v)),
+        }
+    }
+
+    /// The exact bytes sent to the inverter, including CRC and CR.
+    pub fn frame(&self) -> Vec<u8> {
+        voltronic::frame(&self.command())
+    }
+
+    /// Checks the value against the inverter's ratings, current settings
+    /// and accepted charging currents. Voltages are checked as the command
+    /// sends them, rounded to tenths.
+    pub fn validate(
+        &self,
+        rating: &InverterRating,
+        currents: &ChargingCurrents,
+    ) -> Result<(), SettingError> {
+        // The voltage ranges are given for a 12 V battery.
+        let blocks = rating.battery_rating_voltage / 12.0;
+        let invalid = |msg: String| Err(SettingError::Invalid(msg));
+
+        match *self {
+            InverterSetting::OutputSourcePriority(OutputSourcePriority::Other(code)) => {
+                invalid(format!("output source priority {} is not known", code))
+            }
+            InverterSetting::ChargerSourcePriority(ChargerSourcePriority::Other(code)) => {
+                invalid(format!("charger source priority {} is not known", code))
+            }
+            InverterSetting::OutputSourcePriority(_)
+            | InverterSetting::ChargerSourcePriority(_) => Ok(()),
+            InverterSetting::MaxChargingCurrent(a) => {
+                offered("max charging current", a, currents.charging.as_deref())?;
+                if a < rating.max_ac_charging_current {
+                    return invalid(format!(
+                        "max charging current {} A is below the utility charging current of {} A",
+                        a, rating.max_ac_charging_current
+                    ));
+                }
+                Ok(())
+            }
+            InverterSetting::MaxUtilityChargingCurrent(a) => {
+                offered("utility charging current", a, currents.utility.as_deref())?;
+                if a > rating.max_charging_current {
+                    return invalid(format!(
+                        "utility charging current {} A is above the max charging current of {} A",
+                        a, rating.max_charging_current
+                    ));
+                }
+                Ok(())
+            }
+            InverterSetting::BatteryRechargeVoltage(v) => {
+                let v = tenths(v);
+                let (min, max) = (RECHARGE_RANGE.0 * blocks, RECHARGE_RANGE.1 * blocks);
+                if !(min..=max).contains(&v) {
+                    return invalid(format!(
+                        "re-charge voltage {:.1} V is not between {:.1} and {:.1} V for a {} V battery",
+                        v, min, max, rating.battery_rating_voltage
+                    ));
+                }
+                if rating.battery_redischarge_voltage > 0.0
+                    && v >= rating.battery_redischarge_voltage
+                {
+                    return invalid(format!(
+                        "re-charge voltage {:.1} V is not below the re-discharge voltage of {:.1} V",
+                        v, rating.battery_redischarge_voltage
+                    ));
+                }
+                Ok(())
+            }
+            InverterSetting::BatteryRedischargeVoltage(v) => {
+                let v = tenths(v);
+                if v == 0.0 {
+                    return Ok(());
+                }
+                let (min, max) = (REDISCHARGE_RANGE.0 * blocks, REDISCHARGE_RANGE.1 * blocks);
+                if !(min..=max).contains(&v) {
+                    return invalid(format!(
+                        "re-discharge voltage {:.1} V is neither 0 nor between {:.1} and {:.1} V for a {} V battery",
+                        v, min, max, rating.battery_rating_voltage
+                    ));
+                }
+                if v <= rating.battery_recharge_voltage {
+                    return invalid(format!(
+                        "re-discharge voltage {:.1} V is not above the re-charge voltage of {:.1} V",
+                        v, rating.battery_recharge_voltage
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for InverterSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InverterSetting::OutputSourcePriority(p) => write!(f, "output source priority {}", p),
+            InverterSetting::ChargerSourcePriority(p) => {
+                write!(f, "charger source priority {}", p)
+            }
+            InverterSetting::MaxChargingCurrent(a) => write!(f, "max charging current {} A", a),
+            InverterSetting::MaxUtilityChargingCurrent(a) => {
+                write!(f, "max utility charging current {} A", a)
+            }
+            InverterSetting::BatteryRechargeVoltage(v) => {
+                write!(f, "battery re-charge voltage {:.1} V", tenths(*v))
+            }
+            InverterSetting::BatteryRedischargeVoltage(v) => {
+                write!(f, "battery re-discharge voltage {:.1} V", tenths(*v))
+            }
+        }
+    }
+}
+
+/// Checks the response payload to a setting command.
+pub fn parse_ack(payload: &str) -> Result<(), InverterError> {
+    match payload.trim() {
+        "ACK" => Ok(()),
+        other => Err(InverterError::Malformed(format!(
+            "expected ACK, got `{}`",
+            other.escape_debug()
+        ))),
+    }
+}
+
+/// What happened to a setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The inverter acknowledged the setting.
+    Applied,
+    /// Dry run: the setting passed validation and this frame would have
+    /// been sent.
+    DryRun(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum SettingError {
+    /// The value is out of range for this inverter.
+    Invalid(String),
+    /// The ratings have not been read yet, so the value cannot be checked.
+    NoRating,
+    /// The inverter is not connected.
+    NotConnected,
+    /// The inverter's task has stopped.
+    Stopped,
+    /// The link failed while the setting was sent; it may or may not have
+    /// been applied.
+    LinkLost(String),
+    /// The inverter refused the setting or answered something else.
+    Inverter(InverterError),
+}
+
+impl fmt::Display for SettingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingError::Invalid(msg) => f.write_str(msg),
+            SettingError::NoRating => f.write_str("inverter ratings have not been read yet"),
+            SettingError::NotConnected => f.write_str("inverter is not connected"),
+            SettingError::Stopped => f.write_str("inverter task has stopped"),
+            SettingError::LinkLost(e) => write!(f, "link lost while sending: {}", e),
+            SettingError::Inverter(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SettingError {}
+
+impl From<InverterError> for SettingError {
+    fn from(e: InverterError) -> Self {
+        SettingError::Inverter(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 48 V unit charging at up to 80 A, 30 A of it from utility, with
+    /// re-charge at 46 V and re-discharge at 54 V.
+    fn rating() -> InverterRating {
+        InverterRating::parse(
+            "230.0 21.7 230.0 50.0 21.7 5000 4000 48.0 46.0 42.0 56.4 54.0 2 30 080 0 1 2 9 01 0 0 54.0 0 1",
+        )
+        .unwrap()
+    }
+
+    fn currents() -> ChargingCurrents {
+        ChargingCurrents {
+            charging: Some(
+                ChargingCurrents::parse("010 020 030 040 050 060 070 080 090 100").unwrap(),
+            ),
+            utility: Some(ChargingCurrents::parse("002 010 020 030 040 050 060").unwrap()),
+        }
+    }
+
+    fn check(setting: InverterSetting) -> Result<(), SettingError> {
+        setting.validate(&rating(), &currents())
+    }
+
+    #[test]
+    fn formats_commands() {
+        assert_eq!(
+            InverterSetting::OutputSourcePriority(OutputSourcePriority::Sbu).command(),
+            "POP02"
+        );
+        assert_eq!(
+            InverterSetting::MaxChargingCurrent(60).command(),
+            "MCHGC060"
+        );
+        assert_eq!(
+            InverterSetting::MaxUtilityChargingCurrent(2).command(),
+            "MUCHGC002"
+        );
+        assert_eq!(
+            InverterSetting::BatteryRechargeVoltage(46.0).command(),
+            "PBCV46.0"
+        );
+        assert_eq!(
+            InverterSetting::BatteryRedischargeVoltage(0.0).command(),
+            "PBDV00.0"
+        );
+    }
+
+    #[test]
+    fn checks_charging_current() {
+        assert!(check(InverterSetting::MaxChargingCurrent(60)).is_ok());
+        assert!(check(InverterSetting::MaxChargingCurrent(100)).is_ok());
+        // not offered by the inverter
+        assert!(check(InverterSetting::MaxChargingCurrent(900)).is_err());
+        assert!(check(InverterSetting::MaxChargingCurrent(65)).is_err());
+        // below the utility charging current of 30 A
+        assert!(check(InverterSetting::MaxChargingCurrent(20)).is_err());
+    }
+
+    #[test]
+    fn checks_utility_charging_current() {
+        assert!(check(InverterSetting::MaxUtilityChargingCurrent(2)).is_ok());
+        assert!(check(InverterSetting::MaxUtilityChargingCurrent(60)).is_ok());
+        assert!(check(InverterSetting::MaxUtilityChargingCurrent(0)).is_err());
+        assert!(check(InverterSetting::MaxUtilityChargingCurrent(25)).is_err());
+        // above the max charging current of 80 A
+        let mut currents = currents();
+        currents.utility = Some(vec![60, 100]);
+        assert!(InverterSetting::MaxUtilityChargingCurrent(100)
+            .validate(&rating(), &currents)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_currents_without_a_list() {
+        let unknown = ChargingCurrents::default();
+        assert!(InverterSetting::MaxChargingCurrent(60)
+            .validate(&rating(), &unknown)
+            .is_err());
+        assert!(InverterSetting::MaxUtilityChargingCurrent(30)
+            .validate(&rating(), &unknown)
+            .is_err());
+    }
+
+    #[test]
+    fn checks_voltages_as_sent() {
+        // 51.04 V is sent as 51.0 V, the top of the range.
+        let setting = InverterSetting::BatteryRechargeVoltage(51.04);
+        assert_eq!(setting.command(), "PBCV51.0");
+        assert!(check(setting).is_ok());
+        let setting = InverterSetting::BatteryRechargeVoltage(51.05);
+        assert_eq!(setting.command(), "PBCV51.1");
+        assert!(check(setting).is_err());
+        assert!(check(InverterSetting::BatteryRechargeVoltage(43.96)).is_ok());
+
+        // A tiny negative value is sent as 0, not as -0.0.
+        let setting = InverterSetting::BatteryRedischargeVoltage(-0.04);
+        assert_eq!(setting.command(), "PBDV00.0");
+        assert!(check(setting).is_ok());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(-0.06)).is_err());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(f32::NAN)).is_err());
+    }
+
+    #[test]
+    fn checks_voltages() {
+        // 44.0..=51.0 V for 48 V, below the re-discharge voltage of 54 V
+        assert!(check(InverterSetting::BatteryRechargeVoltage(44.0)).is_ok());
+        assert!(check(InverterSetting::BatteryRechargeVoltage(51.0)).is_ok());
+        assert!(check(InverterSetting::BatteryRechargeVoltage(43.9)).is_err());
+        assert!(check(InverterSetting::BatteryRechargeVoltage(51.1)).is_err());
+
+        // 48.0..=58.0 V or 0, above the re-charge voltage of 46 V
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(0.0)).is_ok());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(48.0)).is_ok());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(58.0)).is_ok());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(47.9)).is_err());
+        assert!(check(InverterSetting::BatteryRedischargeVoltage(58.1)).is_err());
+    }
+
+    #[test]
+    fn refuses_unknown_priorities() {
+        assert!(check(InverterSetting::ChargerSourcePriority(
+            ChargerSourcePriority::OnlySolar
+        ))
+        .is_ok());
+        assert!(check(InverterSetting::OutputSourcePriority(
+            OutputSourcePriority::Other(5)
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(
+            InverterSetting::parse("output_source_priority", "2"),
+            Ok(InverterSetting::OutputSourcePriority(
+                OutputSourcePriority::Sbu
+            ))
+        );
+        assert_eq!(
+            InverterSetting::parse("max_charging_current", "60"),
+            Ok(InverterSetting::MaxChargingCurrent(60))
+        );
+        assert_eq!(
+            InverterSetting::parse("battery_recharge_voltage", "46.5"),
+            Ok(InverterSetting::BatteryRechargeVoltage(46.5))
+        );
+        assert!(InverterSetting::parse("max_charging_current", "-1").is_err());
+        assert!(InverterSetting::parse("buzzer", "1").is_err());
+    }
+
+    #[test]
+    fn parses_ack() {
+        assert!(parse_ack("ACK").is_ok());
+        assert!(parse_ack("NAK").is_err());
+        assert!(ChargingCurrents::parse("").is_err());
+        assert!(ChargingCurrents::parse("010 0x0").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Output backends and the router that fans events out to them.
+//!
+//! Every sink runs in its own task behind its own bounded queue, so a slow
+//! or unreachable backend only loses its own events and never stalls
+//! polling.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::device::DeviceId;
+use crate::power::{InverterMode, InverterSnapshot};
+use crate::registry::DeviceInfo;
+use crate::session::SessionStats;
+use crate::site::EnergyFlow;
+use crate::snapshot::PackSnapshot;
+
+/// Events queued per sink before new ones are dropped.
+pub const QUEUE_CAPACITY: usize = 1024;
+
+/// Something that happened on a device connection.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A device passed the handshake.
+    Connected {
+        device_id: DeviceId,
+        device: Option<Arc<DeviceInfo>>,
+        peer: SocketAddr,
+        timestamp: DateTime<Utc>,
+    },
+    /// One polling round.
+    Snapshot(Arc<PackSnapshot>),
+    /// Counters of a running session, sent after every polling round.
+    Stats {
+        device_id: DeviceId,
+        stats: SessionStats,
+        timestamp: DateTime<Utc>,
+    },
+    /// The session ended; `reason` is the error that ended it, if any.
+    Disconnected {
+        device_id: DeviceId,
+        reason: Option<String>,
+        stats: SessionStats,
+        timestamp: DateTime<Utc>,
+    },
+    /// One reading of an inverter.
+    Inverter(Arc<InverterSnapshot>),
+    /// An inverter changed its operating mode; `from` is `None` for the
+    /// first mode seen since the gateway started.
+    InverterMode {
+        name: String,
+        site: Option<String>,
+        from: Option<InverterMode>,
+        to: InverterMode,
+        timestamp: DateTime<Utc>,
+    },
+    /// Energy flow of an inverter's site, computed from one status reading.
+    SiteFlow(Arc<EnergyFlow>),
+}
+
+impl Event {
+    /// The battery pack the event is about; `None` for inverter and site
+    /// events.
+    pub fn device_id(&self) -> Option<DeviceId> {
+        match self {
+            Event::Connected { device_id, .. } => Some(*device_id),
+            Event::Snapshot(snapshot) => Some(snapshot.device_id),
+            Event::Stats { device_id, .. } => Some(*device_id),
+            Event::Disconnected { device_id, .. } => Some(*device_id),
+            Event::Inverter(_) | Event::InverterMode { .. } | Event::SiteFlow(_) => None,
+        }
+    }
+}
+
+/// Destination for events.
+///
+/// `handle` is awaited by the sink's own task, one event at a time; it may
+/// do I/O. Sinks ignore the events they have no use for.
+pub trait Sink: Send + 'static {
+    fn handle(&mut self, event: &Event) -> impl Future<Output = ()> + Send;
+}
+
+/// A sink's queue as seen by the router.
+struct Queue {
+    name: String,
+    events: mpsc::Sender<Event>,
+    /// Events dropped because the queue was full.
+    dropped: AtomicU64,
+}
+
+/// Sends every event to all sinks.
+#[derive(Default)]
+pub struct Router {
+    queues: Vec<Queue>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Spawns a task feeding `sink` from a queue of `capacity` events.
+    pub fn add<S: Sink>(&mut self, name: &str, mut sink: S, capacity: usize) {
+        let (tx, mut rx) = mpsc::channel::<Event>(capacity);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                sink.handle(&event).await;
+            }
+        });
+
+        self.queues.push(Queue {
+            name: name.to_string(),
+            events: tx,
+            dropped: AtomicU64::new(0),
+        });
+    }
+
+    /// Queues `event` for every sink without waiting. A sink whose queue is
+    /// full misses the event; the first drop and every thousandth after it
+    /// are logged.
+    pub fn send(&self, event: Event) {
+        for queue in &self.queues {
+            if queue.events.try_send(event.clone()).is_err() {
+                let dropped = queue.dropped.fetch_add(1, Ordering::Relaxed);
+                if dropped % 1000 == 0 {
+                    eprintln!(
+                        "{}: {} sink queue full, dropped {} events so far",
+                        Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        queue.name,
+                        dropped + 1
+                    );
+                }
+            }
+        }
+    }
+
+    /// Names of the sinks, in the order they were added.
+    pub fn sinks(&self) -> impl Iterator<Item = &str> {
+        self.queues.iter().map(|queue| queue.name.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
@@ -0,0 +1,525 @@
+//! Energy flow of a site, combining an inverter with its battery packs.
+//!
+//! A pack belongs to the inverter named by `inverter` in its registry
+//! entry or, without one, to the only inverter at the same site. Every
+//! inverter status reading produces one `EnergyFlow` from the inverter's
+//! power figures and the latest current of each of its packs.
+//!
+//! Grid import is `grid_to_load` plus `grid_to_battery`; PV left over once
+//! battery and load are served is `pv_to_grid`, which an inverter that
+//! does not export curtails instead.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::device::DeviceId;
+use crate::inverter::InverterConfig;
+use crate::power::{InverterReading, InverterSnapshot, PowerStatus};
+use crate::registry::DeviceInfo;
+use crate::sink::{Event, Router, Sink};
+use crate::snapshot::FieldValue;
+
+/// When the inverter's and the packs' battery currents count as
+/// disagreeing, and which pack readings are recent enough to use.
+#[derive(Debug, Clone, Copy)]
+pub struct FlowConfig {
+    /// Difference always tolerated, in A.
+    pub current_tolerance_a: f32,
+    /// Difference tolerated relative to the larger of the two currents.
+    pub current_tolerance_percent: f32,
+    /// Pack currents older than this are left out.
+    pub pack_max_age: chrono::Duration,
+}
+
+/// Where the power of a site goes at one moment, in W.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyFlow {
+    pub timestamp: DateTime<Utc>,
+    pub inverter: String,
+    pub site: Option<String>,
+    /// Packs whose current was included in `bms_battery_current`.
+    pub packs: Vec<DeviceId>,
+    pub pv_power: f32,
+    pub load_power: f32,
+    /// Positive while the battery charges.
+    pub battery_power: f32,
+    pub pv_to_battery: f32,
+    pub pv_to_load: f32,
+    /// PV left over once battery and load are served.
+    pub pv_to_grid: f32,
+    pub grid_to_load: f32,
+    pub grid_to_battery: f32,
+    pub battery_to_load: f32,
+    /// Battery current in A as the inverter sees it, positive while
+    /// charging.
+    pub inverter_battery_current: f32,
+    /// Sum of the pack currents in A, if any pack reported one.
+    pub bms_battery_current: Option<f32>,
+    /// The two battery currents differ by more than the tolerance.
+    pub current_mismatch: bool,
+}
+
+impl EnergyFlow {
+    /// Splits the inverter's power figures into flows. PV feeds the
+    /// battery first, then the load; the grid covers whatever charging and
+    /// load PV and battery do not.
+    pub fn compute(snapshot: &InverterSnapshot, status: &PowerStatus) -> Self {
+        let pv_power = if status.pv_charging_power > 0 {
+            status.pv_charging_power as f32
+        } else {
+            status.pv_input_voltage * status.pv_input_current as f32
+        };
+        let load_power = status.ac_output_active_power as f32;
+        let current =
+            status.battery_charging_current as f32 - status.battery_discharge_current as f32;
+        let battery_power = status.battery_voltage * current;
+
+        let charge = battery_power.max(0.0);
+        let discharge = (-battery_power).max(0.0);
+        let pv_to_battery = pv_power.min(charge);
+        let pv_to_load = (pv_power - pv_to_battery).min(load_power);
+        let pv_to_grid = pv_power - pv_to_battery - pv_to_load;
+        let battery_to_load = discharge.min(load_power - pv_to_load);
+        let grid_to_load = (load_power - pv_to_load - battery_to_load).max(0.0);
+        let grid_to_battery = charge - pv_to_battery;
+
+        EnergyFlow {
+            timestamp: snapshot.timestamp,
+            inverter: snapshot.name.clone(),
+            site: snapshot.site.clone(),
+            packs: Vec::new(),
+            pv_power,
+            load_power,
+            battery_power,
+            pv_to_battery,
+            pv_to_load,
+            pv_to_grid,
+            grid_to_load,
+            grid_to_battery,
+            battery_to_load,
+            inverter_battery_current: current,
+            bms_battery_current: None,
+            current_mismatch: false,
+        }
+    }
+
+    /// `(field name, value)` of every figure; the BMS current is left out
+    /// when no pack reported one.
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        let mut fields = vec![
+            ("pv_power", FieldValue::Float(self.pv_power)),
+            ("load_power", FieldValue::Float(self.load_power)),
+            ("battery_power", FieldValue::Float(self.battery_power)),
+            ("pv_to_battery", FieldValue::Float(self.pv_to_battery)),
+            ("pv_to_load", FieldValue::Float(self.pv_to_load)),
+            ("pv_to_grid", FieldValue::Float(self.pv_to_grid)),
+            ("grid_to_load", FieldValue::Float(self.grid_to_load)),
+            ("grid_to_battery", FieldValue::Float(self.grid_to_battery)),
+            ("battery_to_load", FieldValue::Float(self.battery_to_load)),
+            (
+                "inverter_battery_current",
+                FieldValue::Float(self.inverter_battery_current),
+            ),
+        ];
+        if let Some(current) = self.bms_battery_current {
+            fields.push(("bms_battery_current", FieldValue::Float(current)));
+        }
+        fields.push(("current_mismatch", FieldValue::Bool(self.current_mismatch)));
+        fields
+    }
+}
+
+/// Unit of a numeric field of `EnergyFlow::fields`.
+pub fn unit(field: &str) -> &'static str {
+    if field.ends_with("_current") {
+        "A"
+    } else {
+        "W"
+    }
+}
+
+/// Latest current of a pack.
+struct PackCurrent {
+    device: Option<Arc<DeviceInfo>>,
+    current_ma: i32,
+    timestamp: DateTime<Utc>,
+}
+
+/// Sink computing the energy flow of every inverter's site. The flows are
+/// sent back through the router as `Event::SiteFlow` by `forward`.
+pub struct Sites {
+    config: FlowConfig,
+    /// Inverters by name, with their site.
+    inverters: HashMap<String, Option<String>>,
+    packs: BTreeMap<DeviceId, PackCurrent>,
+    /// Inverters whose currents disagree, so only changes are logged.
+    mismatched: HashMap<String, bool>,
+    flows: mpsc::Sender<Event>,
+}
+
+impl Sites {
+    /// Returns the sink and the receiving end of its flows, to be passed
+    /// to `forward` once the router is built.
+    pub fn new(inverters: &[InverterConfig], config: FlowConfig) -> (Self, mpsc::Receiver<Event>) {
+        let (tx, rx) = mpsc::channel(crate::sink::QUEUE_CAPACITY);
+        let sites = Sites {
+            config,
+            inverters: inverters
+                .iter()
+                .map(|inverter| (inverter.name.clone(), inverter.site.clone()))
+                .collect(),
+            packs: BTreeMap::new(),
+            mismatched: HashMap::new(),
+            flows: tx,
+        };
+        (sites, rx)
+    }
+
+    /// Whether the pack described by `device` belongs to `inverter`.
+    fn belongs_to(&self, device: &DeviceInfo, inverter: &str) -> bool {
+        if let Some(name) = &device.inverter {
+            return name == inverter;
+        }
+        let mut at_site = self
+            .inverters
+            .iter()
+            .filter(|(_, site)| site.as_deref() == Some(device.site.as_str()));
+        matches!(
+            (at_site.next(), at_site.next()),
+            (Some((name, _)), None) if name == inverter
+        )
+    }
+
+    fn flow(&mut self, snapshot: &InverterSnapshot, status: &PowerStatus) -> EnergyFlow {
+        let mut flow = EnergyFlow::compute(snapshot, status);
+
+        let oldest = snapshot.timestamp - self.config.pack_max_age;
+        let mut total_ma = 0i64;
+        for (id, pack) in &self.packs {
+            let paired = pack
+                .device
+                .as_deref()
+                .is_some_and(|device| self.belongs_to(device, &snapshot.name));
+            if paired && pack.timestamp >= oldest {
+                flow.packs.push(*id);
+                total_ma += i64::from(pack.current_ma);
+            }
+        }
+        if flow.packs.is_empty() {
+            return flow;
+        }
+
+        let bms = total_ma as f32 / 1000.0;
+        let inverter = flow.inverter_battery_current;
+        let tolerance = self
+            .config
+            .current_tolerance_a
+            .max(inverter.abs().max(bms.abs()) * self.config.current_tolerance_percent / 100.0);
+        flow.bms_battery_current = Some(bms);
+        flow.current_mismatch = (inverter - bms).abs() > tolerance;
+
+        let was = self
+            .mismatched
+            .insert(snapshot.name.clone(), flow.current_mismatch)
+            .unwrap_or(false);
+        if flow.current_mismatch && !was {
+            eprintln!(
+                "{}: inverter {} reports {:.1} A battery current but its packs report {:.1} A",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                snapshot.name,
+                inverter,
+                bms
+            );
+        } else if was && !flow.current_mismatch {
+            println!(
+                "{} inverter {} and its packs agree on battery current again",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                snapshot.name
+            );
+        }
+        flow
+    }
+}
+
+impl Sink for Sites {
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Snapshot(snapshot) => {
+                if let Some(current_ma) = snapshot.current_ma {
+                    self.packs.insert(
+                        snapshot.device_id,
+                        PackCurrent {
+                            device: snapshot.device.clone(),
+                            current_ma,
+                            timestamp: snapshot.timestamp,
+                        },
+                    );
+                }
+            }
+            Event::Disconnected { device_id, .. } => {
+                self.packs.remove(device_id);
+            }
+            Event::Inverter(snapshot) => {
+                if let InverterReading::Status(status) = &snapshot.reading {
+                    let flow = self.flow(snapshot, status);
+                    // Only fails once `forward` has stopped with the router.
+                    let _ = self.flows.send(Event::SiteFlow(Arc::new(flow))).await;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Sends the flows computed by `Sites` to every sink.
+pub async fn forward(mut flows: mpsc::Receiver<Event>, router: Arc<Router>) {
+    while let Some(event) = flows.recv().await {
+        router.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::session::SessionStats;
+    use crate::snapshot::PackSnapshot;
+
+    const QPIGS: &str = "000.0 00.0 230.0 50.0 0414 0345 008 405 52.10 012 087 0038 0014 215.4 52.18 00000 00110110 00 00 00754 010";
+
+    /// Status with the given PV and load power in W and battery current
+    /// in A, positive while charging, at 50 V.
+    fn status(pv: u32, load: u32, battery: i32) -> PowerStatus {
+        let mut status = PowerStatus::parse(QPIGS).unwrap();
+        status.pv_charging_power = pv;
+        status.pv_input_current = 0;
+        status.ac_output_active_power = load;
+        status.battery_voltage = 50.0;
+        status.battery_charging_current = battery.max(0) as u32;
+        status.battery_discharge_current = (-battery).max(0) as u32;
+        status
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn snapshot(name: &str, status: &PowerStatus) -> InverterSnapshot {
+        InverterSnapshot {
+            timestamp: at(0),
+            name: name.to_string(),
+            site: Some("north-depot".to_string()),
+            reading: InverterReading::Status(status.clone()),
+        }
+    }
+
+    fn inverter(name: &str, site: &str) -> InverterConfig {
+        InverterConfig {
+            name: name.to_string(),
+            site: Some(site.to_string()),
+            address: "127.0.0.1:8899".to_string(),
+            poll_interval: Duration::from_secs(1),
+            response_timeout: Duration::from_secs(1),
+            rating_interval: Duration::from_secs(60),
+            warnings_interval: Duration::from_secs(60),
+            mode_interval: Duration::from_secs(60),
+        }
+    }
+
+    fn sites(inverters: &[InverterConfig]) -> Sites {
+        let config = FlowConfig {
+            current_tolerance_a: 2.0,
+            current_tolerance_percent: 10.0,
+            pack_max_age: chrono::Duration::seconds(30),
+        };
+        Sites::new(inverters, config).0
+    }
+
+    /// Pack `n` at `site`, wired to `inverter` if given.
+    fn pack(n: u8, site: &str, inverter: Option<&str>, current_ma: i32, secs: i64) -> Event {
+        let mut snapshot = PackSnapshot::new(DeviceId::new([0, 0, 0, 0, 0, n]), at(secs));
+        snapshot.device = Some(Arc::new(DeviceInfo {
+            name: format!("pack {}", n),
+            site: site.to_string(),
+            position: None,
+            cell_count: None,
+            design_capacity_mah: None,
+            inverter: inverter.map(str::to_string),
+            driver: None,
+            min_command_gap_ms: None,
+        }));
+        snapshot.current_ma = Some(current_ma);
+        Event::Snapshot(Arc::new(snapshot))
+    }
+
+    /// `(pv_to_battery, pv_to_load, pv_to_grid, grid_to_load,
+    /// grid_to_battery, battery_to_load)`.
+    fn flows(flow: &EnergyFlow) -> [f32; 6] {
+        [
+            flow.pv_to_battery,
+            flow.pv_to_load,
+            flow.pv_to_grid,
+            flow.grid_to_load,
+            flow.grid_to_battery,
+            flow.battery_to_load,
+        ]
+    }
+
+    fn compute(status: &PowerStatus) -> EnergyFlow {
+        EnergyFlow::compute(&snapshot("Inverter 1", status), status)
+    }
+
+    #[test]
+    fn charges_from_pv_first() {
+        // 1000 W charging, PV covers it and part of the load
+        let flow = compute(&status(1500, 800, 20));
+        assert_eq!(flow.battery_power, 1000.0);
+        assert_eq!(flow.inverter_battery_current, 20.0);
+        assert_eq!(flows(&flow), [1000.0, 500.0, 0.0, 300.0, 0.0, 0.0]);
+
+        // too little PV: the grid charges the rest
+        let flow = compute(&status(400, 800, 20));
+        assert_eq!(flows(&flow), [400.0, 0.0, 0.0, 800.0, 600.0, 0.0]);
+    }
+
+    #[test]
+    fn discharges_into_the_load() {
+        let flow = compute(&status(300, 800, -6));
+        assert_eq!(flow.battery_power, -300.0);
+        assert_eq!(flow.inverter_battery_current, -6.0);
+        assert_eq!(flows(&flow), [0.0, 300.0, 0.0, 200.0, 0.0, 300.0]);
+
+        // no grid needed
+        let flow = compute(&status(300, 600, -6));
+        assert_eq!(flows(&flow), [0.0, 300.0, 0.0, 0.0, 0.0, 300.0]);
+    }
+
+    #[test]
+    fn exports_surplus_pv() {
+        let flow = compute(&status(2000, 500, 10));
+        assert_eq!(flows(&flow), [500.0, 500.0, 1000.0, 0.0, 0.0, 0.0]);
+
+        let flow = compute(&status(0, 500, 0));
+        assert_eq!(flows(&flow), [0.0, 0.0, 0.0, 500.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn falls_back_to_pv_voltage_and_current() {
+        let mut status = status(0, 0, 0);
+        status.pv_input_voltage = 200.0;
+        status.pv_input_current = 3;
+        assert_eq!(compute(&status).pv_power, 600.0);
+    }
+
+    #[tokio::test]
+    async fn sums_the_packs_of_the_inverter() {
+        let mut sites = sites(&[inverter("Inverter 1", "north-depot")]);
+        sites.handle(&pack(1, "north-depot", None, 9_000, 0)).await;
+        sites
+            .handle(&pack(2, "north-depot", None, 10_000, -10))
+            .await;
+        // another site
+        sites.handle(&pack(3, "south-depot", None, 50_000, 0)).await;
+
+        let status = status(1500, 800, 20);
+        let flow = sites.flow(&snapshot("Inverter 1", &status), &status);
+        assert_eq!(
+            flow.packs,
+            [
+                DeviceId::new([0, 0, 0, 0, 0, 1]),
+                DeviceId::new([0, 0, 0, 0, 0, 2])
+            ]
+        );
+        assert_eq!(flow.bms_battery_current, Some(19.0));
+        assert!(!flow.current_mismatch);
+    }
+
+    #[tokio::test]
+    async fn leaves_out_missing_packs() {
+        let mut sites = sites(&[inverter("Inverter 1", "north-depot")]);
+        let status = status(1500, 800, 20);
+        let flow = sites.flow(&snapshot("Inverter 1", &status), &status);
+        assert!(flow.packs.is_empty());
+        assert_eq!(flow.bms_battery_current, None);
+        assert!(!flow.current_mismatch);
+        assert!(!flow
+            .fields()
+            .iter()
+            .any(|(name, _)| *name == "bms_battery_current"));
+
+        // too old
+        sites
+            .handle(&pack(1, "north-depot", None, 20_000, -31))
+            .await;
+        // not in the registry
+        let mut unknown = PackSnapshot::new(DeviceId::new([0, 0, 0, 0, 0, 2]), at(0));
+        unknown.current_ma = Some(20_000);
+        sites.handle(&Event::Snapshot(Arc::new(unknown))).await;
+        // disconnected since
+        sites.handle(&pack(3, "north-depot", None, 20_000, 0)).await;
+        sites
+            .handle(&Event::Disconnected {
+                device_id: DeviceId::new([0, 0, 0, 0, 0, 3]),
+                reason: None,
+                stats: SessionStats::default(),
+                timestamp: at(0),
+            })
+            .await;
+
+        let flow = sites.flow(&snapshot("Inverter 1", &status), &status);
+        assert!(flow.packs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pairs_packs_at_shared_sites_by_inverter() {
+        let mut sites = sites(&[
+            inverter("Inverter 1", "north-depot"),
+            inverter("Inverter 2", "north-depot"),
+        ]);
+        // ambiguous without an inverter
+        sites.handle(&pack(1, "north-depot", None, 5_000, 0)).await;
+        sites
+            .handle(&pack(2, "north-depot", Some("Inverter 2"), 20_000, 0))
+            .await;
+
+        let status = status(1500, 800, 20);
+        let flow = sites.flow(&snapshot("Inverter 1", &status), &status);
+        assert!(flow.packs.is_empty());
+        let flow = sites.flow(&snapshot("Inverter 2", &status), &status);
+        assert_eq!(flow.packs, [DeviceId::new([0, 0, 0, 0, 0, 2])]);
+    }
+
+    #[tokio::test]
+    async fn flags_currents_beyond_the_tolerance() {
+        // (inverter A, packs mA, mismatch); 2 A or 10 % of the larger
+        let cases = [
+            (0, 2_000, false),
+            (0, 2_100, true),
+            (-1, 1_000, false),
+            (-1, 1_100, true),
+            (20, 18_000, false),
+            (20, 17_900, true),
+            (40, 44_000, false),
+            (40, 44_500, true),
+            (-40, -36_000, false),
+            (-40, -35_900, true),
+        ];
+        for (inverter_a, pack_ma, mismatch) in cases {
+            let mut sites = sites(&[inverter("Inverter 1", "north-depot")]);
+            sites
+                .handle(&pack(1, "north-depot", None, pack_ma, 0))
+                .await;
+            let status = status(0, 0, inverter_a);
+            let flow = sites.flow(&snapshot("Inverter 1", &status), &status);
+            assert_eq!(
+                flow.current_mismatch, mismatch,
+                "{} A against {} mA",
+                inverter_a, pack_ma
+            );
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+
+use crate::device::DeviceId;
+use crate::register::{Reading, Register, CELL_COUNT, TEMPERATURE_COUNT};
+use crate::registry::DeviceInfo;
+use crate::status::{BatteryStatus, PackConfig, PackStatus};
+
+/// Value of a stored field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Float(f32),
+    Bool(bool),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Float(v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// State of one pack assembled from a single polling round.
+///
+/// A register that was not read in the round is `None`; if it was read but
+/// the response was unusable (bad CRC, wrong length) it is also listed in
+/// `failed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackSnapshot {
+    /// When the last response of the round was received.
+    pub timestamp: DateTime<Utc>,
+    pub device_id: DeviceId,
+    /// Registry entry of the device, if it has one.
+    pub device: Option<Arc<DeviceInfo>>,
+    /// Cell voltages in mV, index 0 is cell 1.
+    pub cells: Vec<Option<u16>>,
+    /// Temperatures in °C, index 0 is sensor 1.
+    pub temperatures: Vec<Option<f32>>,
+    pub total_voltage_mv: Option<u32>,
+    /// Positive while charging, negative while discharging.
+    pub current_ma: Option<i32>,
+    pub full_capacity_mah: Option<u32>,
+    pub remaining_capacity_mah: Option<u32>,
+    pub rsoc: Option<u16>,
+    pub cycle_count: Option<u16>,
+    pub pack_status: Option<PackStatus>,
+    pub battery_status: Option<BatteryStatus>,
+    pub pack_config: Option<PackConfig>,
+    pub failed: Vec<Register>,
+}
+
+impl PackSnapshot {
+    pub fn new(device_id: DeviceId, timestamp: DateTime<Utc>) -> Self {
+        PackSnapshot {
+            timestamp,
+            device_id,
+            device: None,
+            cells: vec![None; CELL_COUNT as usize],
+            temperatures: vec![None; TEMPERATURE_COUNT as usize],
+            total_voltage_mv: None,
+            current_ma: None,
+            full_capacity_mah: None,
+            remaining_capacity_mah: None,
+            rsoc: None,
+            cycle_count: None,
+            pack_status: None,
+            battery_status: None,
+            pack_config: None,
+            failed: Vec::new(),
+        }
+    }
+
+    /// Snapshot of the pack described by `device` in which every register
+    /// was read as zero, so that `fields()` lists every field such a pack
+    /// reports.
+    pub fn template(device_id: DeviceId, device: Option<Arc<DeviceInfo>>) -> Self {
+        let cells = device
+            .as_ref()
+            .and_then(|d| d.cell_count)
+            .unwrap_or(CELL_COUNT);
+        let mut snapshot = PackSnapshot::new(device_id, Utc::now());
+        snapshot.device = device;
+        for register in Register::all() {
+            match register {
+                Register::CellVoltage(n) if n > cells => (),
+                register => snapshot.apply(&Reading { register, raw: 0 }),
+            }
+        }
+        snapshot
+    }
+
+    /// Stores a decoded reading in the matching field.
+    pub fn apply(&mut self, reading: &Reading) {
+        let raw = reading.raw;
+        match reading.register {
+            Register::CellVoltage(n) => {
+                if let Some(cell) = self.cells.get_mut(n as usize - 1) {
+                    *cell = Some(raw as u16);
+                }
+            }
+            Register::Temperature(n) => {
+                if let Some(temperature) = self.temperatures.get_mut(n as usize - 1) {
+                    *temperature = Some(reading.value());
+                }
+            }
+            Register::TotalVoltage => self.total_voltage_mv = Some(raw as u32),
+            Register::Current => self.current_ma = Some(raw as i32),
+            Register::FullCapacity => self.full_capacity_mah = Some(raw as u32),
+            Register::RemainingCapacity => self.remaining_capacity_mah = Some(raw as u32),
+            Register::Rsoc => self.rsoc = Some(raw as u16),
+            Register::CycleCount => self.cycle_count = Some(raw as u16),
+            Register::PackStatus => self.pack_status = Some(PackStatus(raw as u16)),
+            Register::BatteryStatus => self.battery_status = Some(BatteryStatus(raw as u16)),
+            Register::PackConfig => self.pack_config = Some(PackConfig(raw as u16)),
+        }
+    }
+
+    /// Records that `register` was polled but its response was unusable.
+    pub fn mark_failed(&mut self, register: Register) {
+        if !self.failed.contains(&register) {
+            self.failed.push(register);
+        }
+    }
+
+    /// Value of `register` in its unit, if it was read in this round.
+    pub fn value(&self, register: Register) -> Option<f32> {
+        match register {
+            Register::CellVoltage(n) => self
+                .cells
+                .get((n as usize).wrapping_sub(1))
+                .copied()
+                .flatten()
+                .map(f32::from),
+            Register::Temperature(n) => self
+                .temperatures
+                .get((n as usize).wrapping_sub(1))
+                .copied()
+                .flatten(),
+            Register::TotalVoltage => self.total_voltage_mv.map(|v| v as f32),
+            Register::Current => self.current_ma.map(|v| v as f32),
+            Register::FullCapacity => self.full_capacity_mah.map(|v| v as f32),
+            Register::RemainingCapacity => self.remaining_capacity_mah.map(|v| v as f32),
+            Register::Rsoc => self.rsoc.map(f32::from),
+            Register::CycleCount => self.cycle_count.map(f32::from),
+            Register::PackStatus => self.pack_status.map(|s| f32::from(s.bits())),
+            Register::BatteryStatus => self.battery_status.map(|s| f32::from(s.bits())),
+            Register::PackConfig => self.pack_config.map(|c| f32::from(c.bits())),
+        }
+    }
+
+    /// Full capacity as a percentage of the registry's design capacity.
+    pub fn state_of_health(&self) -> Option<f32> {
+        let design = self.device.as_ref()?.design_capacity_mah?;
+        let full = self.full_capacity_mah?;
+        Some(full as f32 / design as f32 * 100.0)
+    }
+
+    /// `(field name, value)` for every register read in this round,
+    /// followed by the state of health.
+    ///
+    /// Status registers are listed by their raw value only: the bit
+    /// assignments in `status` are unverified, so the decoded flags are not
+    /// stored anywhere.
+    pub fn fields(&self) -> Vec<(String, FieldValue)> {
+        let mut fields: Vec<(String, FieldValue)> = Register::all()
+            .filter_map(|register| {
+                self.value(register)
+                    .map(|value| (register.field_name(), FieldValue::Float(value)))
+            })
+            .collect();
+
+        if let Some(soh) = self.state_of_health() {
+            fields.push(("state_of_health".to_string(), FieldValue::Float(soh)));
+        }
+
+        fields
+    }
+
+    pub fn is_empty(&self) -> bool {
+        Register::all().all(|register| self.value(register).is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_lists_every_field() {
+        let id: DeviceId = "00:1b:2c:3d:4e:5f".parse().unwrap();
+        let device = DeviceInfo {
+            name: "Pack 1".to_string(),
+            site: "north-depot".to_string(),
+            position: None,
+            cell_count: Some(13),
+            design_capacity_mah: Some(100_000),
+            inverter: None,
+            driver: None,
+            min_command_gap_ms: None,
+        };
+        let names: Vec<String> = PackSnapshot::template(id, Some(Arc::new(device)))
+            .fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        assert!(names.contains(&"cell_13".to_string()));
+        assert!(!names.contains(&"cell_14".to_string()));
+        for name in [
+            "full_capacity",
+            "remaining_capacity",
+            "cycle_count",
+            "pack_status",
+            "battery_status",
+            "pack_config",
+            "state_of_health",
+        ] {
+            assert!(names.contains(&name.to_string()), "{} is missing", name);
+        }
+        // Decoded status flags are not stored while unverified.
+        assert!(!names.contains(&"charging".to_string()));
+    }
+}
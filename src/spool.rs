@@ -0,0 +1,320 @@
+//! Durable on-disk buffer for line protocol batches that could not be
+//! written.
+//!
+//! Batches are appended to numbered segment files in a directory. Each
+//! record is `[len: u32 LE][crc32: u32 LE][payload]`; a record that is cut
+//! short or fails its CRC ends the readable part of a segment. Segments are
+//! drained oldest first, and before the buffer would grow past its size cap
+//! the oldest segments are evicted, the open one included if need be.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+const CRC_32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+const EXTENSION: &str = "seg";
+const HEADER_LEN: usize = 8;
+
+/// Segments are closed once they reach this size, or a quarter of the size
+/// cap if that is smaller, so that eviction frees the cap in small steps.
+pub const SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// `(sequence, size)` of every segment, oldest first.
+    segments: VecDeque<(u64, u64)>,
+    /// The newest segment while it is open for appending.
+    open: Option<(u64, File)>,
+    next_seq: u64,
+}
+
+impl Spool {
+    /// Opens the buffer in `dir`, creating the directory if needed, and picks
+    /// up segments left by a previous run.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let seq = match path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                Some(seq) => seq,
+                None => continue,
+            };
+            segments.push((seq, fs::metadata(&path)?.len()));
+        }
+        segments.sort_unstable();
+
+        let next_seq = segments.last().map(|(seq, _)| seq + 1).unwrap_or(1);
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            segment_bytes: (max_bytes / 4).clamp(1, SEGMENT_BYTES),
+            segments: segments.into(),
+            open: None,
+            next_seq,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Bytes on disk.
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Sequence number of the oldest segment.
+    pub fn oldest(&self) -> Option<u64> {
+        self.segments.front().map(|(seq, _)| *seq)
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:016}.{}", seq, EXTENSION))
+    }
+
+    /// Appends one batch and syncs it to disk. Returns how many old segments
+    /// were evicted to stay under the size cap; a batch larger than the cap
+    /// is refused.
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<usize> {
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&CRC_32.checksum(payload).to_le_bytes());
+        record.extend_from_slice(payload);
+
+        let len = record.len() as u64;
+        if len > self.max_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "batch of {} bytes exceeds the buffer cap of {} bytes",
+                    len, self.max_bytes
+                ),
+            ));
+        }
+
+        let mut evicted = 0;
+        while self.size() + len > self.max_bytes {
+            self.remove_oldest()?;
+            evicted += 1;
+        }
+
+        let full = match (&self.open, self.segments.back()) {
+            (Some((seq, _)), Some((last, size))) => seq != last || *size >= self.segment_bytes,
+            _ => true,
+        };
+        if full {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.path(seq))?;
+            self.open = Some((seq, file));
+            self.segments.push_back((seq, 0));
+        }
+
+        let (_, file) = self.open.as_mut().expect("segment opened above");
+        file.write_all(&record)?;
+        file.sync_data()?;
+        if let Some((_, size)) = self.segments.back_mut() {
+            *size += len;
+        }
+
+        Ok(evicted)
+    }
+
+    /// Reads every intact batch of the oldest segment, with its sequence
+    /// number. The segment stays on disk until it is removed.
+    pub fn read_oldest(&mut self) -> io::Result<Option<(u64, Vec<Vec<u8>>)>> {
+        let seq = match self.segments.front() {
+            Some((seq, _)) => *seq,
+            None => return Ok(None),
+        };
+
+        // Later batches go to a fresh segment so this one can be removed.
+        if matches!(self.open, Some((open, _)) if open == seq) {
+            self.open = None;
+        }
+
+        let data = fs::read(self.path(seq))?;
+        let mut batches = Vec::new();
+        let mut rest = &data[..];
+        while rest.len() >= HEADER_LEN {
+            let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let crc = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]);
+            let payload = match rest.get(HEADER_LEN..HEADER_LEN + len) {
+                Some(payload) if CRC_32.checksum(payload) == crc => payload,
+                // torn write or corruption
+                _ => break,
+            };
+            batches.push(payload.to_vec());
+            rest = &rest[HEADER_LEN + len..];
+        }
+
+        Ok(Some((seq, batches)))
+    }
+
+    /// Deletes segment `seq`, unless it is already gone.
+    pub fn remove(&mut self, seq: u64) -> io::Result<()> {
+        let index = match self.segments.iter().position(|(s, _)| *s == seq) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        self.segments.remove(index);
+        if matches!(self.open, Some((open, _)) if open == seq) {
+            self.open = None;
+        }
+        match fs::remove_file(self.path(seq)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_oldest(&mut self) -> io::Result<()> {
+        match self.oldest() {
+            Some(seq) => self.remove(seq),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory unique to `name`.
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("powermax-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Payload of `len` bytes that names batch `n`.
+    fn batch(n: usize, len: usize) -> Vec<u8> {
+        let mut payload = format!("batch {}", n).into_bytes();
+        payload.resize(len, b'.');
+        payload
+    }
+
+    fn oldest_batches(spool: &mut Spool) -> Vec<Vec<u8>> {
+        spool
+            .read_oldest()
+            .unwrap()
+            .map(|(_, b)| b)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn stops_reading_at_a_bad_crc() {
+        let dir = dir("crc");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        for n in 0..3 {
+            spool.append(&batch(n, 20)).unwrap();
+        }
+
+        let path = spool.path(spool.oldest().unwrap());
+        let mut data = fs::read(&path).unwrap();
+        // a payload byte of the second record
+        data[HEADER_LEN + 20 + HEADER_LEN + 5] ^= 0x01;
+        fs::write(&path, data).unwrap();
+
+        assert_eq!(oldest_batches(&mut spool), [batch(0, 20)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_a_torn_tail() {
+        let dir = dir("torn");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        spool.append(&batch(0, 20)).unwrap();
+        spool.append(&batch(1, 20)).unwrap();
+        let seq = spool.oldest().unwrap();
+        drop(spool);
+
+        // The second write was cut short by a crash.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.join(format!("{:016}.{}", seq, EXTENSION)))
+            .unwrap();
+        file.set_len((HEADER_LEN + 20 + HEADER_LEN + 7) as u64)
+            .unwrap();
+
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        spool.append(&batch(2, 20)).unwrap();
+        assert_eq!(oldest_batches(&mut spool), [batch(0, 20)]);
+        spool.remove(seq).unwrap();
+        assert_eq!(oldest_batches(&mut spool), [batch(2, 20)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopens_an_existing_spool() {
+        let dir = dir("reopen");
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        spool.append(&batch(0, 20)).unwrap();
+        spool.append(&batch(1, 20)).unwrap();
+        let (seq, size) = (spool.oldest().unwrap(), spool.size());
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 1 << 20).unwrap();
+        assert_eq!(spool.oldest(), Some(seq));
+        assert_eq!(spool.size(), size);
+
+        // New batches go to a segment of their own.
+        spool.append(&batch(2, 20)).unwrap();
+        assert_eq!(oldest_batches(&mut spool), [batch(0, 20), batch(1, 20)]);
+        spool.remove(seq).unwrap();
+        let (next, batches) = spool.read_oldest().unwrap().unwrap();
+        assert!(next > seq);
+        assert_eq!(batches, [batch(2, 20)]);
+        spool.remove(next).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_oldest_segments_first() {
+        let dir = dir("evict");
+        // Records of 50 bytes in segments of 100 bytes, two per segment.
+        let mut spool = Spool::open(&dir, 400).unwrap();
+        let mut evicted = 0;
+        for n in 0..10 {
+            evicted += spool.append(&batch(n, 50 - HEADER_LEN)).unwrap();
+            assert!(spool.size() <= 400);
+        }
+
+        assert_eq!(evicted, 1);
+        assert_eq!(spool.size(), 400);
+        assert_eq!(oldest_batches(&mut spool), [batch(2, 42), batch(3, 42)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_a_cap_below_one_segment() {
+        let dir = dir("small");
+        let mut spool = Spool::open(&dir, 120).unwrap();
+        for n in 0..5 {
+            spool.append(&batch(n, 50 - HEADER_LEN)).unwrap();
+            assert!(spool.size() <= 120);
+        }
+        assert_eq!(oldest_batches(&mut spool), [batch(3, 42)]);
+
+        let err = spool.append(&batch(5, 200)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Bitfield registers of the B5120: pack status (0x1A), battery status (0x1B)
+//! and pack config (0x1C).
+//!
+//! No protocol document for these registers is available, so the bit
+//! assignments below are unverified. The decoded flags only appear in the
+//! gateway's log, always next to the raw value; sinks get the raw registers
+//! alone until the tables are checked against the vendor's documentation.
+
+use std::fmt;
+
+/// Writes the names of the set flags joined by `|`, or `none`.
+fn fmt_flags(f: &mut fmt::Formatter<'_>, bits: u16, table: &[(u16, &str)]) -> fmt::Result {
+    let mut first = true;
+    for (mask, name) in table {
+        if bits & mask != 0 {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+    }
+    if first {
+        f.write_str("none")?;
+    }
+    Ok(())
+}
+
+/// Protection and fault flags, register 0x1A.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PackStatus(pub u16);
+
+impl PackStatus {
+    pub const CELL_OVER_VOLTAGE: u16 = 1 << 0;
+    pub const CELL_UNDER_VOLTAGE: u16 = 1 << 1;
+    pub const PACK_OVER_VOLTAGE: u16 = 1 << 2;
+    pub const PACK_UNDER_VOLTAGE: u16 = 1 << 3;
+    pub const CHARGE_OVER_CURRENT: u16 = 1 << 4;
+    pub const DISCHARGE_OVER_CURRENT: u16 = 1 << 5;
+    pub const SHORT_CIRCUIT: u16 = 1 << 6;
+    pub const CHARGE_OVER_TEMPERATURE: u16 = 1 << 7;
+    pub const DISCHARGE_OVER_TEMPERATURE: u16 = 1 << 8;
+    pub const CHARGE_UNDER_TEMPERATURE: u16 = 1 << 9;
+    pub const DISCHARGE_UNDER_TEMPERATURE: u16 = 1 << 10;
+    pub const MOSFET_OVER_TEMPERATURE: u16 = 1 << 11;
+    pub const CELL_IMBALANCE: u16 = 1 << 12;
+
+    const FLAGS: &'static [(u16, &'static str)] = &[
+        (Self::CELL_OVER_VOLTAGE, "cell_over_voltage"),
+        (Self::CELL_UNDER_VOLTAGE, "cell_under_voltage"),
+        (Self::PACK_OVER_VOLTAGE, "pack_over_voltage"),
+        (Self::PACK_UNDER_VOLTAGE, "pack_under_voltage"),
+        (Self::CHARGE_OVER_CURRENT, "charge_over_current"),
+        (Self::DISCHARGE_OVER_CURRENT, "discharge_over_current"),
+        (Self::SHORT_CIRCUIT, "short_circuit"),
+        (Self::CHARGE_OVER_TEMPERATURE, "charge_over_temperature"),
+        (
+            Self::DISCHARGE_OVER_TEMPERATURE,
+            "discharge_over_temperature",
+        ),
+        (Self::CHARGE_UNDER_TEMPERATURE, "charge_under_temperature"),
+        (
+            Self::DISCHARGE_UNDER_TEMPERATURE,
+            "discharge_under_temperature",
+        ),
+        (Self::MOSFET_OVER_TEMPERATURE, "mosfet_over_temperature"),
+        (Self::CELL_IMBALANCE, "cell_imbalance"),
+    ];
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// Whether any protection has tripped.
+    pub fn is_fault(&self) -> bool {
+        self.0 != 0
+    }
+
+    /// `(name, set)` for every known flag.
+    pub fn flags(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        Self::FLAGS
+            .iter()
+            .map(move |(mask, name)| (*name, self.0 & mask != 0))
+    }
+}
+
+impl fmt::Display for PackStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_flags(f, self.0, Self::FLAGS)
+    }
+}
+
+/// Operating state flags, register 0x1B.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BatteryStatus(pub u16);
+
+impl BatteryStatus {
+    pub const CHARGING: u16 = 1 << 0;
+    pub const DISCHARGING: u16 = 1 << 1;
+    pub const CHARGE_MOSFET_ON: u16 = 1 << 2;
+    pub const DISCHARGE_MOSFET_ON: u16 = 1 << 3;
+    pub const BALANCING_ACTIVE: u16 = 1 << 4;
+    pub const FULLY_CHARGED: u16 = 1 << 5;
+    pub const FULLY_DISCHARGED: u16 = 1 << 6;
+    pub const PRECHARGE_MOSFET_ON: u16 = 1 << 7;
+
+    const FLAGS: &'static [(u16, &'static str)] = &[
+        (Self::CHARGING, "charging"),
+        (Self::DISCHARGING, "discharging"),
+        (Self::CHARGE_MOSFET_ON, "charge_mosfet_on"),
+        (Self::DISCHARGE_MOSFET_ON, "discharge_mosfet_on"),
+        (Self::BALANCING_ACTIVE, "balancing_active"),
+        (Self::FULLY_CHARGED, "fully_charged"),
+        (Self::FULLY_DISCHARGED, "fully_discharged"),
+        (Self::PRECHARGE_MOSFET_ON, "precharge_mosfet_on"),
+    ];
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// `(name, set)` for every known flag.
+    pub fn flags(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        Self::FLAGS
+            .iter()
+            .map(move |(mask, name)| (*name, self.0 & mask != 0))
+    }
+}
+
+impl fmt::Display for BatteryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_flags(f, self.0, Self::FLAGS)
+    }
+}
+
+/// Pack configuration, register 0x1C.
+///
+/// Bits 0-4 hold the number of cells in series, bits 5-7 the number of
+/// temperature sensors fitted; the high byte holds feature flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PackConfig(pub u16);
+
+impl PackConfig {
+    pub const BALANCING_ENABLED: u16 = 1 << 8;
+    pub const CURRENT_LIMIT_ENABLED: u16 = 1 << 9;
+    pub const HEATER_ENABLED: u16 = 1 << 10;
+    pub const PRECHARGE_ENABLED: u16 = 1 << 11;
+
+    const FLAGS: &'static [(u16, &'static str)] = &[
+        (Self::BALANCING_ENABLED, "balancing_enabled"),
+        (Self::CURRENT_LIMIT_ENABLED, "current_limit_enabled"),
+        (Self::HEATER_ENABLED, "heater_enabled"),
+        (Self::PRECHARGE_ENABLED, "precharge_enabled"),
+    ];
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// Number of cells in series.
+    pub fn series_cells(&self) -> u8 {
+        (self.0 & 0x1F) as u8
+    }
+
+    pub fn temperature_sensors(&self) -> u8 {
+        ((self.0 >> 5) & 0x07) as u8
+    }
+
+    /// `(name, set)` for every known flag.
+    pub fn flags(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        Self::FLAGS
+            .iter()
+            .map(move |(mask, name)| (*name, self.0 & mask != 0))
+    }
+}
+
+impl fmt::Display for PackConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}S, {} sensors, ",
+            self.series_cells(),
+            self.temperature_sensors()
+        )?;
+        fmt_flags(f, self.0, Self::FLAGS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_pack_status() {
+        let status = PackStatus(PackStatus::SHORT_CIRCUIT | PackStatus::CELL_IMBALANCE);
+        assert!(status.is_fault());
+        assert!(status.contains(PackStatus::SHORT_CIRCUIT));
+        assert!(!status.contains(PackStatus::SHORT_CIRCUIT | PackStatus::CELL_OVER_VOLTAGE));
+        let set: Vec<&str> = status
+            .flags()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(set, ["short_circuit", "cell_imbalance"]);
+        assert_eq!(status.flags().count(), 13);
+        assert!(!PackStatus(0).is_fault());
+    }
+
+    #[test]
+    fn formats_set_flags() {
+        assert_eq!(PackStatus(0).to_string(), "none");
+        assert_eq!(
+            BatteryStatus(BatteryStatus::CHARGING | BatteryStatus::CHARGE_MOSFET_ON).to_string(),
+            "charging | charge_mosfet_on"
+        );
+        // Bits outside the table are left out.
+        assert_eq!(BatteryStatus(0x8000).to_string(), "none");
+    }
+
+    #[test]
+    fn decodes_pack_config() {
+        let config = PackConfig(PackConfig::BALANCING_ENABLED | 3 << 5 | 16);
+        assert_eq!(config.series_cells(), 16);
+        assert_eq!(config.temperature_sensors(), 3);
+        assert!(config.contains(PackConfig::BALANCING_ENABLED));
+        assert_eq!(config.to_string(), "16S, 3 sensors, balancing_enabled");
+        assert_eq!(PackConfig(0x0D).to_string(), "13S, 0 sensors, none");
+    }
+}
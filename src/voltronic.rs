@@ -0,0 +1,232 @@
+//! Framing of the Voltronic-style ASCII protocol spoken by PowerMax
+//! inverters.
+//!
+//! A command is `<ASCII><crc16><CR>`, a response `(<ASCII><crc16><CR>`, with
+//! the CRC-16/XMODEM of everything before it sent big-endian. The inverter
+//! avoids `(`, CR and LF in CRC bytes by adding one to them, so senders must
+//! do the same.
+
+use std::fmt;
+use std::io;
+
+use crc::{Crc, CRC_16_XMODEM};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout_at, Instant};
+
+pub const CRC_16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+/// Longest response accepted, including framing.
+const MAX_RESPONSE: usize = 512;
+
+/// CRC of `data` with the reserved-byte adjustment applied.
+pub fn crc16(data: &[u8]) -> [u8; 2] {
+    CRC_16.checksum(data).to_be_bytes().map(|b| match b {
+        b'(' | b'\r' | b'\n' => b + 1,
+        b => b,
+    })
+}
+
+/// `command` framed for sending.
+pub fn frame(command: &str) -> Vec<u8> {
+    let mut frame = command.as_bytes().to_vec();
+    frame.extend_from_slice(&crc16(command.as_bytes()));
+    frame.push(b'\r');
+    frame
+}
+
+#[derive(Debug)]
+pub enum InverterError {
+    Io(io::Error),
+    /// The connection was closed by the inverter.
+    Closed,
+    /// No complete response before the deadline.
+    Timeout,
+    Crc(Vec<u8>),
+    /// The inverter refused the command.
+    Nak,
+    /// The response was framed correctly but its content could not be
+    /// understood.
+    Malformed(String),
+}
+
+impl fmt::Display for InverterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InverterError::Io(e) => write!(f, "{}", e),
+            InverterError::Closed => f.write_str("connection closed by inverter"),
+            InverterError::Timeout => f.write_str("inverter stopped responding"),
+            InverterError::Crc(received) => write!(
+                f,
+                "response failed CRC check: {}",
+                String::from_utf8_lossy(received).escape_debug()
+            ),
+            InverterError::Nak => f.write_str("command refused (NAK)"),
+            InverterError::Malformed(msg) => write!(f, "malformed response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for InverterError {}
+
+impl From<io::Error> for InverterError {
+    fn from(e: io::Error) -> Self {
+        InverterError::Io(e)
+    }
+}
+
+impl InverterError {
+    /// Whether the connection is unusable after this error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            InverterError::Io(_) | InverterError::Closed | InverterError::Timeout
+        )
+    }
+}
+
+/// Checks a complete response, without its trailing CR, and returns the
+/// payload between `(` and the CRC.
+pub fn parse_response(response: &[u8]) -> Result<String, InverterError> {
+    if response.len() < 3 || response[0] != b'(' {
+        return Err(InverterError::Malformed(format!(
+            "`{}` does not start with `(`",
+            String::from_utf8_lossy(response).escape_debug()
+        )));
+    }
+
+    let (body, crc) = response.split_at(response.len() - 2);
+    if crc16(body) != crc {
+        return Err(InverterError::Crc(response.to_vec()));
+    }
+
+    let payload = std::str::from_utf8(&body[1..])
+        .map_err(|_| InverterError::Malformed("payload is not ASCII".to_string()))?;
+    if payload.starts_with("NAK") {
+        return Err(InverterError::Nak);
+    }
+    Ok(payload.to_string())
+}
+
+/// Sends `command` and waits until `deadline` for its response payload.
+pub async fn query<S>(
+    stream: &mut S,
+    command: &str,
+    deadline: Instant,
+) -> Result<String, InverterError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&frame(command)).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        let n = timeout_at(deadline, stream.read(&mut buf))
+            .await
+            .map_err(|_| InverterError::Timeout)??;
+        if n == 0 {
+            return Err(InverterError::Closed);
+        }
+        response.extend_from_slice(&buf[..n]);
+
+        if let Some(end) = response.iter().position(|&b| b == b'\r') {
+            // Anything before the last `(` is left over from an earlier,
+            // abandoned response.
+            let start = response[..end]
+                .iter()
+                .rposition(|&b| b == b'(')
+                .unwrap_or(0);
+            return parse_response(&response[start..end]);
+        }
+        if response.len() > MAX_RESPONSE {
+            return Err(InverterError::Malformed(format!(
+                "no CR within {} bytes",
+                MAX_RESPONSE
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `payload` framed as the inverter sends it, without the CR.
+    fn response(payload: &str) -> Vec<u8> {
+        let mut response = format!("({}", payload).into_bytes();
+        let crc = crc16(&response);
+        response.extend_from_slice(&crc);
+        response
+    }
+
+    #[test]
+    fn frames_commands() {
+        assert_eq!(frame("QPIGS"), b"QPIGS\xB7\xA9\r");
+        assert_eq!(frame("QMOD"), b"QMOD\x49\xC1\r");
+    }
+
+    #[test]
+    fn avoids_reserved_crc_bytes() {
+        for data in (0..=255u8).map(|b| [b, b'Q']) {
+            let crc = crc16(&data);
+            assert!(!crc.contains(&b'(') && !crc.contains(&b'\r') && !crc.contains(&b'\n'));
+        }
+    }
+
+    #[test]
+    fn parses_responses() {
+        assert_eq!(parse_response(&response("B")).unwrap(), "B");
+        assert_eq!(
+            parse_response(b"(NAKss").unwrap_err().to_string(),
+            "command refused (NAK)"
+        );
+        assert!(matches!(
+            parse_response(&response("NAK")),
+            Err(InverterError::Nak)
+        ));
+        assert!(matches!(parse_response(&response("ACK")), Ok(ack) if ack == "ACK"));
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let mut corrupt = response("230.0 50.0");
+        corrupt[2] = b'4';
+        assert!(matches!(
+            parse_response(&corrupt),
+            Err(InverterError::Crc(_))
+        ));
+        assert!(matches!(
+            parse_response(b"B\x00\x00"),
+            Err(InverterError::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_response(b"("),
+            Err(InverterError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_stale_bytes_before_the_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let inverter = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0; 7];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"QMOD\x49\xC1\r");
+            let mut reply = b"(L\x00".to_vec();
+            reply.extend_from_slice(&response("B"));
+            // split so the response arrives in two reads
+            stream.write_all(&reply[..4]).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            stream.write_all(&reply[4..]).await.unwrap();
+            stream.write_all(b"\r").await.unwrap();
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(2);
+        assert_eq!(query(&mut stream, "QMOD", deadline).await.unwrap(), "B");
+        inverter.await.unwrap();
+    }
+}